@@ -1,9 +1,9 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use fake::Fake;
 use kvs::{KvStore, KvsEngine, SledKvsEngine};
 
 use rand::prelude::*;
-use std::collections::HashMap;
+use std::{collections::HashMap, thread};
 use tempfile::TempDir;
 
 pub type SampleData = HashMap<String, String>;
@@ -69,6 +69,29 @@ pub fn read(c: &mut Criterion) {
     group.finish();
 }
 
+// Every thread reads the whole list, so throughput should grow with the number of threads
+pub fn concurrent_read(c: &mut Criterion) {
+    let kvs = KvEngine::<KvStore>::new();
+    let (list, list_keys) = generate_write_list();
+    let mut group = c.benchmark_group("engines/concurrent_read");
+    load_data(&kvs, &list);
+    let read_list = generate_random_read_list(list_keys);
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements((threads * read_list.len()) as u64));
+        group.bench_with_input(BenchmarkId::new("kvs", threads), &threads, |b, &threads| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for _ in 0..threads {
+                        scope.spawn(|| get_data(&kvs, &read_list));
+                    }
+                });
+            })
+        });
+    }
+
+    group.finish();
+}
+
 fn generate_write_list() -> (SampleData, SampleDataVec) {
     let mut list = SampleData::new();
     let mut list_vec = SampleDataVec::new();
@@ -105,5 +128,5 @@ fn get_data<T: KvsEngine>(kvs: &KvEngine<T>, list: &SampleDataVec) {
     }
 }
 
-criterion_group!(engines, read, write);
+criterion_group!(engines, read, concurrent_read, write);
 criterion_main!(engines);
@@ -54,7 +54,7 @@ pub fn kvstore_bench<Engine: KvsEngine, Pool: ThreadPool>(c: &mut Criterion) {
     // Create a list of read commands from sample_data
     let mut read_commands = Vec::new();
     for (key, value) in sample_data {
-        let command = Command::from(Get::new(key));
+        let command = Command::from(Get::new(key.into()));
         read_commands.push((command, value));
     }
 
@@ -176,8 +176,8 @@ fn do_read_heavy_work(
                     let possible_error = client
                         .send_command(command)
                         .map(|actual_value| {
-                            let actual_value = actual_value.trim_end();
-                            (actual_value != expected_value).then_some(GeneralError(format!(
+                            let actual_value = actual_value.unwrap_or_default().to_string();
+                            (actual_value != *expected_value).then_some(GeneralError(format!(
                                 "Data doesn't match! Expected: {}, Actual: {}",
                                 expected_value, actual_value
                             )))
@@ -0,0 +1,145 @@
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{dump, server, shared::LOG_DIRECTORY_PREFIX, KvStore, KvsEngine, SledKvsEngine};
+use std::{
+    env::current_dir,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    process::exit,
+};
+use strum::Display;
+
+#[derive(Parser)]
+// Inherit cargo package defaults for author, version, etc
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: AdminCommand,
+    /// Directory of the store, `log_index` in the current directory by default, as used by
+    /// `kvs-server`
+    #[arg(long, global = true)]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum AdminCommand {
+    /// Convert the store to another engine, in place
+    ///
+    /// The store must not be in use by a server while it is migrated.
+    Migrate {
+        /// Engine the store uses now, read from the store if it isn't given
+        #[arg(long, value_enum)]
+        from: Option<Engine>,
+        /// Engine to convert the store to
+        #[arg(long, value_enum)]
+        to: Engine,
+    },
+    /// Write every entry of the store to a dump that any engine can import
+    Export {
+        /// File to write the dump to, or `-` to write it to stdout
+        file: PathBuf,
+        /// Engine the store uses, read from the store if it isn't given
+        #[arg(long, value_enum)]
+        engine: Option<Engine>,
+    },
+    /// Save every entry of a dump to the store
+    Import {
+        /// File to read the dump from, or `-` to read it from stdin
+        file: PathBuf,
+        /// Engine the store uses, read from the store if it isn't given
+        #[arg(long, value_enum)]
+        engine: Option<Engine>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, Display, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+enum Engine {
+    #[default]
+    Kvs,
+    Sled,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    // Logs go to stderr, so a dump can be exported to stdout
+    server::initialize_event_logging();
+    if let Err(error) = run(cli) {
+        eprintln!("{:#}", error);
+        exit(1)
+    }
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let dir = match cli.dir {
+        Some(dir) => dir,
+        None => current_dir()?.join(LOG_DIRECTORY_PREFIX),
+    };
+    match cli.command {
+        AdminCommand::Migrate { from, to } => {
+            let from = detect_engine(&dir, from)?;
+            let entries = match (from, to) {
+                (Engine::Kvs, Engine::Sled) => dump::migrate::<KvStore, SledKvsEngine>(&dir)?,
+                (Engine::Sled, Engine::Kvs) => dump::migrate::<SledKvsEngine, KvStore>(&dir)?,
+                (Engine::Kvs, Engine::Kvs) | (Engine::Sled, Engine::Sled) => {
+                    bail!("The store already uses the {} engine", to)
+                }
+            };
+            eprintln!("Migrated {entries} entries from {from} to {to}");
+        }
+        AdminCommand::Export { file, engine } => {
+            let entries = match detect_engine(&dir, engine)? {
+                Engine::Kvs => export(&KvStore::open(&dir)?, &file)?,
+                Engine::Sled => export(&SledKvsEngine::open(&dir)?, &file)?,
+            };
+            eprintln!("Exported {entries} entries");
+        }
+        AdminCommand::Import { file, engine } => {
+            let entries = match detect_engine(&dir, engine)? {
+                Engine::Kvs => import(&KvStore::open(&dir)?, &file)?,
+                Engine::Sled => import(&SledKvsEngine::open(&dir)?, &file)?,
+            };
+            eprintln!("Imported {entries} entries");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the given engine, or else the one recorded in the store, or else the default one,
+/// failing if the store uses another one.
+fn detect_engine(dir: &Path, engine: Option<Engine>) -> Result<Engine> {
+    let engine = match (engine, fs::read_to_string(dir.join("engine"))) {
+        (Some(engine), _) => engine,
+        (None, Ok(name)) => Engine::from_str(name.trim(), true)
+            .map_err(|_| anyhow::anyhow!("Unknown engine {} in {}", name, dir.display()))?,
+        (None, Err(_)) => Engine::default(),
+    };
+    match engine {
+        Engine::Kvs => dump::check_engine::<KvStore>(dir),
+        Engine::Sled => dump::check_engine::<SledKvsEngine>(dir),
+    }
+    .with_context(|| format!("{} isn't a {engine} store", dir.display()))?;
+    Ok(engine)
+}
+
+fn export(engine: &impl KvsEngine, file: &Path) -> Result<u64> {
+    let entries = if file.as_os_str() == "-" {
+        engine.export(io::stdout().lock())?
+    } else {
+        let writer = File::create(file).with_context(|| format!("Creating {}", file.display()))?;
+        engine.export(BufWriter::new(writer))?
+    };
+    Ok(entries)
+}
+
+fn import(engine: &impl KvsEngine, file: &Path) -> Result<u64> {
+    let entries = if file.as_os_str() == "-" {
+        engine.import(io::stdin().lock())?
+    } else {
+        let reader = File::open(file).with_context(|| format!("Opening {}", file.display()))?;
+        engine.import(BufReader::new(reader))?
+    };
+    Ok(entries)
+}
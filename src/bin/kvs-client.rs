@@ -1,10 +1,19 @@
-use anyhow::Result;
-use clap::{Args, Parser};
-use kvs::{client::KvsClient, shared::Command};
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use kvs::{
+    client::KvsClient,
+    shared::{format_ttl, prefix_range, WriteBatch},
+};
 use std::{
     fmt::Debug,
+    fs,
+    io::{self, Read, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Bound,
+    path::PathBuf,
     process::exit,
+    time::Duration,
 };
 
 const DEFAULT_SERVER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
@@ -16,11 +25,88 @@ const DEFAULT_SERVER_PORT: u16 = 4000;
 struct Cli {
     /// Lists all available SubCommands
     #[command(subcommand)]
-    command: Command,
+    command: ClientCommand,
     #[command(flatten)]
     options: CommandOptions,
 }
 
+#[derive(Subcommand, Clone, Debug)]
+enum ClientCommand {
+    /// Save the given value to the given key
+    #[command(group(ArgGroup::new("input").required(true).args(["value", "file"])))]
+    Set {
+        key: String,
+        value: Option<String>,
+        /// Read the value from a file instead, or from stdin if the path is `-`
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Remove the key after this long, e.g. `30s` or `1h 30m`
+        #[arg(long, value_parser = humantime::parse_duration)]
+        ttl: Option<Duration>,
+    },
+    /// Get the value of a given key
+    Get {
+        key: String,
+        /// How to print the value
+        #[arg(long, value_enum, default_value_t = Encoding::Raw)]
+        output: Encoding,
+    },
+    /// Remove the given key
+    Rm { key: String },
+    /// Show how long the given key has left before it expires, or `none` if it never does
+    Ttl { key: String },
+    /// Apply the writes listed in a file all at once
+    ///
+    /// Each line is either `set <key> <value>` or `rm <key>`. The value is the rest of the line.
+    /// Blank lines and lines starting with `#` are skipped.
+    Batch {
+        /// File to read the writes from, or `-` to read them from stdin
+        file: PathBuf,
+    },
+    /// List keys in order along with their values
+    Scan {
+        /// Only list keys that start with this prefix
+        #[arg(long, conflicts_with_all = ["start", "end"])]
+        prefix: Option<String>,
+        /// Start at this key
+        #[arg(long)]
+        start: Option<String>,
+        /// Stop before this key
+        #[arg(long)]
+        end: Option<String>,
+        /// List at most this many keys
+        #[arg(long)]
+        limit: Option<usize>,
+        /// How to print the values
+        #[arg(long, value_enum, default_value_t = Encoding::Raw)]
+        output: Encoding,
+    },
+    /// Show how big the store is and how much of it is garbage
+    Info,
+    /// Maintain the store the server runs on
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum AdminCommand {
+    /// Write a backup of the store to a directory on the server, which must not exist or be empty
+    ///
+    /// The path is taken from the backup directory of the server, which must have been started
+    /// with `--backup-dir`.
+    Backup { dir: PathBuf },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Encoding {
+    /// The bytes of the value as they are
+    Raw,
+    Hex,
+    Base64,
+}
+
 #[derive(Args, Clone, Debug)]
 struct CommandOptions {
     #[arg(
@@ -44,14 +130,108 @@ impl Default for CommandOptions {
 fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let cli = Cli::parse();
-    match KvsClient::new(cli.options.addr).send_command(&cli.command) {
-        Ok(response) => {
-            print!("{}", response);
+    if let Err(error) = run(&KvsClient::new(cli.options.addr), cli.command) {
+        eprintln!("{}", error);
+        exit(1)
+    }
+    Ok(())
+}
+
+fn run(client: &KvsClient, command: ClientCommand) -> Result<()> {
+    match command {
+        ClientCommand::Set {
+            key,
+            value,
+            file,
+            ttl,
+        } => {
+            let value = match (value, file) {
+                (Some(value), _) => value.into_bytes(),
+                (None, Some(file)) if file.as_os_str() == "-" => {
+                    let mut value = Vec::new();
+                    io::stdin().read_to_end(&mut value)?;
+                    value
+                }
+                (None, Some(file)) => fs::read(file)?,
+                (None, None) => unreachable!("clap requires a value or a file"),
+            };
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, ttl)?,
+                None => client.set(key, value)?,
+            }
         }
-        Err(error) => {
-            eprint!("{}", error);
-            exit(1)
+        ClientCommand::Get { key, output } => {
+            if let Some(value) = client.get(key)? {
+                let mut stdout = io::stdout().lock();
+                write_value(&mut stdout, &value, output)?;
+                writeln!(stdout)?;
+            }
         }
-    };
+        ClientCommand::Rm { key } => client.remove(key)?,
+        ClientCommand::Ttl { key } => match client.ttl(key)? {
+            Some(ttl) => println!("{}", format_ttl(ttl)),
+            None => println!("none"),
+        },
+        ClientCommand::Batch { file } => {
+            let contents = if file.as_os_str() == "-" {
+                io::read_to_string(io::stdin())?
+            } else {
+                fs::read_to_string(file)?
+            };
+            client.write_batch(parse_batch(&contents)?)?;
+        }
+        ClientCommand::Scan {
+            prefix,
+            start,
+            end,
+            limit,
+            output,
+        } => {
+            let range = match prefix {
+                Some(prefix) => prefix_range(prefix),
+                None => (
+                    start.map_or(Bound::Unbounded, |start| Bound::Included(start.into())),
+                    end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.into())),
+                ),
+            };
+            let mut stdout = io::stdout().lock();
+            for (key, value) in client.scan(range, limit)? {
+                write!(stdout, "{key}\t")?;
+                write_value(&mut stdout, &value, output)?;
+                writeln!(stdout)?;
+            }
+        }
+        ClientCommand::Info => print!("{}", client.info()?),
+        ClientCommand::Admin {
+            command: AdminCommand::Backup { dir },
+        } => client.backup(dir)?,
+    }
     Ok(())
 }
+
+fn parse_batch(contents: &str) -> Result<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (command, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let arguments = arguments.trim_start();
+        let key = arguments.trim_end();
+        batch = match (command, arguments.split_once(char::is_whitespace)) {
+            ("set", Some((key, value))) => batch.set(key, value),
+            ("rm", _) if !key.is_empty() && !key.contains(char::is_whitespace) => batch.remove(key),
+            _ => bail!("Invalid write on line {}: {}", number + 1, line),
+        };
+    }
+    Ok(batch)
+}
+
+fn write_value(out: &mut impl Write, value: &[u8], encoding: Encoding) -> io::Result<()> {
+    match encoding {
+        Encoding::Raw => out.write_all(value),
+        Encoding::Hex => out.write_all(hex::encode(value).as_bytes()),
+        Encoding::Base64 => out.write_all(STANDARD.encode(value).as_bytes()),
+    }
+}
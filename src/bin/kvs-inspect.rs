@@ -0,0 +1,132 @@
+use anyhow::Result;
+use clap::Parser;
+use kvs::{
+    inspect::{self, LogReport, StoreReport},
+    server,
+    shared::{BatchOperation, Command, LOG_DIRECTORY_PREFIX},
+};
+use std::{env::current_dir, path::PathBuf, process::exit};
+
+#[derive(Parser)]
+// Inherit cargo package defaults for author, version, etc
+#[command(author, version, about, long_about = None)]
+/// Inspect the logs of a `kvs` store without opening it
+struct Cli {
+    /// Directory of the store, `log_index` in the current directory by default, as used by
+    /// `kvs-server`
+    #[arg(long)]
+    dir: Option<PathBuf>,
+    /// List every record of each log
+    #[arg(long)]
+    records: bool,
+    /// Write a copy of the store that leaves out every damaged record and what follows it in its
+    /// log
+    #[arg(long, value_name = "DIR")]
+    repair: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    server::initialize_event_logging();
+    let dir = match cli.dir {
+        Some(dir) => dir,
+        None => current_dir()?.join(LOG_DIRECTORY_PREFIX),
+    };
+
+    let report = match &cli.repair {
+        Some(dest) => inspect::repair(&dir, dest),
+        None => inspect::inspect(&dir),
+    };
+    let report = match report {
+        Ok(report) => report,
+        Err(error) => {
+            eprintln!("{error}");
+            exit(1)
+        }
+    };
+    print_report(&report, cli.records);
+
+    if let Some(dest) = cli.repair {
+        println!("Wrote repaired copy to {}", dest.display());
+    } else if !report.is_healthy() {
+        eprintln!("The store can't be opened, use --repair to write a copy that can");
+        exit(1)
+    }
+    Ok(())
+}
+
+fn print_report(report: &StoreReport, records: bool) {
+    if !report.has_manifest {
+        println!("No manifest, logs were found in the directory");
+    }
+    if report.interrupted_compaction {
+        println!("A compaction was interrupted, it is recovered when the store is opened");
+    }
+    for log in &report.logs {
+        print_log(log);
+        if records {
+            for record in &log.records {
+                println!(
+                    "  {:>10} {:>8} {:<5} {}",
+                    record.offset,
+                    record.length,
+                    if record.live { "live" } else { "stale" },
+                    describe(&record.command)
+                );
+            }
+        }
+        if let Some(damage) = &log.damage {
+            let consequence = if damage.torn_tail {
+                "torn write, dropped when the store is opened"
+            } else {
+                "the store can't be opened"
+            };
+            println!(
+                "  damaged at offset {}: {} ({consequence}), {} bytes unreadable",
+                damage.offset,
+                damage.error,
+                log.size - damage.offset,
+            );
+        }
+    }
+}
+
+fn print_log(log: &LogReport) {
+    let active = if log.active { " (active)" } else { "" };
+    if log.missing {
+        println!("log {}{active}: missing", log.log_id);
+        return;
+    }
+    println!(
+        "log {}{active}: {} bytes, {} records ({} live, {} stale), {} live bytes, {} stale bytes",
+        log.log_id,
+        log.size,
+        log.records.len(),
+        log.live_records(),
+        log.stale_records(),
+        log.live_bytes(),
+        log.stale_bytes(),
+    );
+}
+
+fn describe(command: &Command) -> String {
+    match command {
+        Command::Set(cmd) => match cmd.expires_at() {
+            Some(expires_at) => format!("set {} (expires at {expires_at})", cmd.key),
+            None => format!("set {}", cmd.key),
+        },
+        Command::Rm(cmd) => format!("rm {}", cmd.key),
+        Command::Batch(batch) => {
+            let operations = batch
+                .operations()
+                .iter()
+                .map(|operation| match operation {
+                    BatchOperation::Set(cmd) => format!("set {}", cmd.key),
+                    BatchOperation::Rm(cmd) => format!("rm {}", cmd.key),
+                })
+                .collect::<Vec<_>>();
+            format!("batch [{}]", operations.join(", "))
+        }
+        command => format!("{command:?}"),
+    }
+}
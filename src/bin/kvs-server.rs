@@ -1,16 +1,18 @@
 use anyhow::Result;
 use clap::{Args, Parser, ValueEnum};
 use kvs::{
+    durability::SyncPolicy,
+    protocol::Limits,
     server,
-    server::KvsServer,
+    server::{KvsServer, Protocol as ServerProtocol, DEFAULT_MAX_CONNECTIONS},
     shared::initialize_log_directory,
     thread_pool::{SharedQueueThreadPool, ThreadPool},
-    KvStore, KvsEngine, SledKvsEngine,
+    KvStore, KvStoreOptions, KvsEngine, SledKvsEngine,
 };
 use std::{
     env::current_dir,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    path::Path,
+    path::{Path, PathBuf},
 };
 use strum::Display;
 
@@ -33,6 +35,25 @@ pub enum Engine {
     Sled,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, Default, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Protocol {
+    /// The protocol of `kvs-client`
+    #[default]
+    Kvs,
+    /// RESP2, as spoken by Redis clients
+    Resp,
+}
+
+impl From<Protocol> for ServerProtocol {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Kvs => Self::Kvs,
+            Protocol::Resp => Self::Resp,
+        }
+    }
+}
+
 #[derive(Args, Clone, Debug)]
 struct CommandOptions {
     #[arg(
@@ -51,13 +72,96 @@ struct CommandOptions {
         help = "Sets the Engine to be used."
     )]
     engine: Engine,
+
+    #[arg(
+        value_enum,
+        long,
+        default_value_t = CommandOptions::default().protocol,
+        help = "Sets the protocol spoken with clients."
+    )]
+    protocol: Protocol,
+
+    #[arg(
+        long,
+        value_name = "IP:PORT",
+        help = "Also serves an HTTP/JSON gateway to the store on the given IP and PORT."
+    )]
+    http: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        default_value_t = CommandOptions::default().sync,
+        help = "Sets when writes are synced to disk by the kvs engine: never, always, <N>ms or <N>b."
+    )]
+    sync: SyncPolicy,
+
+    #[arg(
+        long,
+        help = "Replaces the store with the backup in the given directory before starting."
+    )]
+    restore: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Lets clients write backups to directories inside DIR. Backups are disabled without it."
+    )]
+    backup_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = CommandOptions::default().max_connections,
+        help = "Sets how many connections are served at once. Connections past that are closed."
+    )]
+    max_connections: usize,
+
+    #[arg(
+        long,
+        default_value_t = CommandOptions::default().max_frame_size,
+        help = "Sets the largest request accepted, in bytes."
+    )]
+    max_frame_size: u32,
+
+    #[arg(
+        long,
+        default_value_t = CommandOptions::default().max_key_size,
+        help = "Sets the largest key accepted, in bytes."
+    )]
+    max_key_size: usize,
+
+    #[arg(
+        long,
+        default_value_t = CommandOptions::default().max_value_size,
+        help = "Sets the largest value accepted, in bytes."
+    )]
+    max_value_size: usize,
+}
+
+impl CommandOptions {
+    fn limits(&self) -> Limits {
+        Limits {
+            max_frame_size: self.max_frame_size,
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+        }
+    }
 }
 
 impl Default for CommandOptions {
     fn default() -> Self {
+        let limits = Limits::default();
         Self {
             address: SocketAddr::new(DEFAULT_SERVER_IP, DEFAULT_SERVER_PORT),
             engine: Engine::default(),
+            protocol: Protocol::default(),
+            http: None,
+            sync: SyncPolicy::default(),
+            restore: None,
+            backup_dir: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_frame_size: limits.max_frame_size,
+            max_key_size: limits.max_key_size,
+            max_value_size: limits.max_value_size,
         }
     }
 }
@@ -68,27 +172,43 @@ fn main() -> Result<()> {
     server::initialize_event_logging();
 
     let path = initialize_log_directory(&current_dir()?)?;
+    if let Some(backup) = &cli.options.restore {
+        match cli.options.engine {
+            Engine::Kvs => KvStore::restore(backup, &path)?,
+            Engine::Sled => SledKvsEngine::restore(backup, &path)?,
+        }
+    }
     match cli.options.engine {
         Engine::Kvs => {
-            let kv = KvStore::open(&path)?;
-            start_kvs_server(cli.options.address, kv, &path)
+            let options = KvStoreOptions::default().sync_policy(cli.options.sync);
+            let kv = KvStore::open_with_options(&path, options)?;
+            start_kvs_server(kv, &path, cli.options)
         }
         Engine::Sled => {
             let kv = SledKvsEngine::open(&path)?;
-            start_kvs_server(cli.options.address, kv, &path)
+            start_kvs_server(kv, &path, cli.options)
         }
     }?;
     Ok(())
 }
 
-pub fn start_kvs_server<Engine: KvsEngine>(
-    address: SocketAddr,
+fn start_kvs_server<Engine: KvsEngine>(
     engine: Engine,
     path: &Path,
+    options: CommandOptions,
 ) -> anyhow::Result<()> {
     let cpus = num_cpus::get();
     let pool = SharedQueueThreadPool::new(cpus as u32)?;
-    let server = KvsServer::new(address, engine, pool, path);
+    let mut server = KvsServer::new(options.address, engine, pool, path)
+        .with_protocol(options.protocol.into())
+        .with_limits(options.limits())
+        .with_max_connections(options.max_connections);
+    if let Some(http) = options.http {
+        server = server.with_http(http);
+    }
+    if let Some(backup_dir) = options.backup_dir {
+        server = server.with_backup_dir(backup_dir);
+    }
     server.start(1)?;
     Ok(())
 }
@@ -1,37 +1,373 @@
 use crate::{
-    serde::bincode::Serde,
-    shared::{Command, CommandResponse},
-    KvsError::GeneralError,
-    Result,
+    protocol::{self, Limits, Welcome, PIPELINING, TRANSACTIONS},
+    shared::{
+        AdminCommand, Command, CommandResponse, CompareAndSwap, Expiry, Get, Key, Remove,
+        RemoveIfEquals, Request, Response, Scan, Set, SetIfAbsent, TransactionControl, Ttl, Value,
+        WriteBatch,
+    },
+    EngineStats,
+    KvsError::{GeneralError, ProtocolError},
+    Result, Transaction,
 };
 use std::{
+    collections::VecDeque,
+    io::{BufReader, BufWriter, ErrorKind, Write},
     net::{SocketAddr, TcpStream},
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
+/// Most requests `KvsClient::pipeline` sends ahead of the responses it has read, so neither end
+/// blocks on a full socket buffer while the other isn't reading
+const PIPELINE_WINDOW: usize = 64;
+
+/// Client of a `KvsServer`.
+///
+/// Commands go over a single connection that is kept open between them, and is shared by every
+/// clone of the client. It is opened again if the server closed it in the meantime.
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone, Debug)]
 pub struct KvsClient {
     server_address: SocketAddr,
+    limits: Limits,
+    connection: Arc<Mutex<Option<Connection>>>,
 }
 
 impl KvsClient {
     #[must_use]
     pub fn new(server_address: SocketAddr) -> KvsClient {
-        Self { server_address }
+        Self {
+            server_address,
+            limits: Limits::default(),
+            connection: Arc::default(),
+        }
+    }
+
+    /// Sets the largest responses, keys and values the client accepts from the server.
+    #[must_use]
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sends a command to the server and returns the value it responded with, if any.
+    ///
+    /// # Errors
+    ///
+    /// If the server couldn't be reached or it reported an error.
+    pub fn send_command(&self, command: &Command) -> Result<Option<Value>> {
+        self.request(command)?.into_result()
+    }
+
+    /// Sends every command before waiting for its response, and returns the responses in the
+    /// same order.
+    ///
+    /// Up to `PIPELINE_WINDOW` requests are in flight at once, or one if the server doesn't
+    /// support pipelining. A failed command doesn't stop the ones after it, its error is in its
+    /// response.
+    ///
+    /// # Errors
+    ///
+    /// If the server couldn't be reached or the connection failed part of the way through.
+    pub fn pipeline<'a>(
+        &self,
+        commands: impl IntoIterator<Item = &'a Command>,
+    ) -> Result<Vec<CommandResponse>> {
+        self.with_connection(|connection| {
+            let window = if connection.welcome.supports(PIPELINING) {
+                PIPELINE_WINDOW
+            } else {
+                1
+            };
+            let mut commands = commands.into_iter().peekable();
+            let mut in_flight = VecDeque::new();
+            let mut responses = Vec::new();
+            while commands.peek().is_some() || !in_flight.is_empty() {
+                while in_flight.len() < window {
+                    let Some(command) = commands.next() else {
+                        break;
+                    };
+                    in_flight.push_back(connection.send(command)?);
+                }
+                connection.flush()?;
+                if let Some(id) = in_flight.pop_front() {
+                    responses.push(connection.receive(id)?);
+                }
+            }
+            Ok(responses)
+        })
+    }
+
+    fn request(&self, command: &Command) -> Result<CommandResponse> {
+        self.with_connection(|connection| connection.exchange(command))
+    }
+
+    /// Runs `f` on the shared connection, opening it if needed. The connection is dropped if `f`
+    /// fails, as it may be left part of the way through a request.
+    fn with_connection<T>(&self, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut guard = self.connection.lock()?;
+        let mut connection = match guard.take() {
+            Some(connection) if !connection.is_closed() => connection,
+            _ => Connection::open(self.server_address, self.limits)?,
+        };
+        let result = f(&mut connection);
+        if result.is_ok() {
+            *guard = Some(connection);
+        }
+        result
+    }
+
+    /// Writes a backup of the store to a directory on the server, which must not exist or be
+    /// empty. The path is relative to the backup directory of the server.
+    pub fn backup(&self, path: impl Into<PathBuf>) -> Result<()> {
+        self.send_command(&Command::Admin(AdminCommand::Backup(path.into())))?;
+        Ok(())
+    }
+
+    /// Returns the protocol version and the features the server agreed on, connecting to it if
+    /// needed.
+    pub fn protocol(&self) -> Result<Welcome> {
+        self.with_connection(|connection| Ok(connection.welcome.clone()))
+    }
+
+    /// Returns how big the store on the server is and how much of it is garbage.
+    pub fn info(&self) -> Result<EngineStats> {
+        self.request(&Command::Admin(AdminCommand::Info))?
+            .into_stats()
+    }
+
+    /// Starts a transaction on the server, which runs over its own connection until it is
+    /// committed or rolled back.
+    pub fn begin(&self) -> Result<RemoteTransaction> {
+        let mut connection = Connection::open(self.server_address, self.limits)?;
+        if !connection.welcome.supports(TRANSACTIONS) {
+            return Err(ProtocolError(
+                "the server doesn't support transactions".to_owned(),
+            ));
+        }
+        connection
+            .exchange(&Command::Transaction(TransactionControl::Begin))?
+            .into_result()?;
+        Ok(RemoteTransaction { connection })
+    }
+
+    pub fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        self.send_command(&Command::from(Get::new(key.into())))
+    }
+
+    pub fn set(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        self.send_command(&Command::from(Set::new(key.into(), value.into())))?;
+        Ok(())
+    }
+
+    /// Saves the value to the key until `ttl` has passed, counted from when the server gets it.
+    pub fn set_with_ttl(
+        &self,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let set = Set::new(key.into(), value.into()).with_expiry(Expiry::After(ttl));
+        self.send_command(&Command::from(set))?;
+        Ok(())
+    }
+
+    /// Returns how long the key has left before it expires, or `None` if it never expires.
+    pub fn ttl(&self, key: impl Into<Key>) -> Result<Option<Duration>> {
+        self.request(&Command::from(Ttl::new(key.into())))?
+            .into_ttl()
+    }
+
+    pub fn remove(&self, key: impl Into<Key>) -> Result<()> {
+        self.send_command(&Command::from(Remove::new(key.into())))?;
+        Ok(())
+    }
+
+    /// Saves `new` to the key if it currently holds `expected`.
+    ///
+    /// Fails with `ConditionFailed` and the current value otherwise.
+    pub fn compare_and_swap(
+        &self,
+        key: impl Into<Key>,
+        expected: impl Into<Value>,
+        new: impl Into<Value>,
+    ) -> Result<()> {
+        let command = CompareAndSwap::new(key.into(), expected.into(), new.into());
+        self.send_command(&Command::from(command))?;
+        Ok(())
+    }
+
+    /// Saves the value to the key if it doesn't exist.
+    ///
+    /// Fails with `ConditionFailed` and the current value otherwise.
+    pub fn set_if_absent(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        let command = SetIfAbsent::new(key.into(), value.into());
+        self.send_command(&Command::from(command))?;
+        Ok(())
     }
 
-    pub fn send_command(&self, command: &Command) -> Result<String> {
-        let timeout = Duration::from_millis(5_000);
-        let stream = TcpStream::connect_timeout(&self.server_address, timeout)?;
+    /// Removes the key if it currently holds `expected`.
+    ///
+    /// Fails with `ConditionFailed` and the current value otherwise.
+    pub fn remove_if_equals(&self, key: impl Into<Key>, expected: impl Into<Value>) -> Result<()> {
+        let command = RemoveIfEquals::new(key.into(), expected.into());
+        self.send_command(&Command::from(command))?;
+        Ok(())
+    }
+
+    /// Applies every write in the batch at once, or none of them if it fails.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.send_command(&Command::from(batch))?;
+        Ok(())
+    }
+
+    /// Returns the keys in `range` along with their values, in key order.
+    ///
+    /// The server returns no more entries than fit in a response, so the scan is sent again from
+    /// the last key returned until the range or `limit` runs out.
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<Key>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Key, Value)>> {
+        let mut start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let mut entries = Vec::new();
+        loop {
+            let remaining = limit.map(|limit| limit - entries.len());
+            if remaining == Some(0) {
+                return Ok(entries);
+            }
+            let page = self
+                .request(&Command::from(Scan::new(start, end.clone(), remaining)))?
+                .into_entries()?;
+            match page.last() {
+                Some((key, _)) => start = Bound::Excluded(key.clone()),
+                None => return Ok(entries),
+            }
+            entries.extend(page);
+        }
+    }
+}
+
+/// A connection to the server, which carries any number of requests.
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    /// Protocol version and features agreed on with the server
+    welcome: Welcome,
+    limits: Limits,
+    next_id: u64,
+}
+
+impl Connection {
+    fn open(address: SocketAddr, limits: Limits) -> Result<Self> {
+        let timeout = Duration::from_secs(5);
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
         stream.set_read_timeout(Some(timeout))?;
         stream.set_write_timeout(Some(timeout))?;
-        command.serialize_into_stream(&stream)?;
-        let response = CommandResponse::deserialize_from_stream(&stream)?;
-        if response.is_err() {
-            Err(GeneralError(response.to_string()))
-        } else {
-            Ok(response.to_string())
+        // Requests are small and flushed as soon as the client waits on them
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let welcome = protocol::say_hello(&mut reader, &mut writer, &limits)?;
+        Ok(Self {
+            reader,
+            writer,
+            welcome,
+            limits,
+            next_id: 0,
+        })
+    }
+
+    /// Sends a command and waits for its response.
+    fn exchange(&mut self, command: &Command) -> Result<CommandResponse> {
+        let id = self.send(command)?;
+        self.flush()?;
+        self.receive(id)
+    }
+
+    /// Queues a command to be sent with the next flush, and returns the id of its request.
+    fn send(&mut self, command: &Command) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        Request::new(id, command.clone()).write(&mut self.writer, self.welcome.version)?;
+        Ok(id)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads the next response, which must be the one to the request with the given id.
+    fn receive(&mut self, id: u64) -> Result<CommandResponse> {
+        let response = Response::read(&mut self.reader, self.welcome.version, &self.limits)?;
+        if response.id != id {
+            return Err(GeneralError(format!(
+                "Expected the response to request {id} but got {}",
+                response.id
+            )));
         }
+        Ok(response.response)
+    }
+
+    /// Checks whether the server has closed the connection, e.g. because it was idle for too
+    /// long, without waiting for anything to arrive.
+    fn is_closed(&self) -> bool {
+        let stream = self.reader.get_ref();
+        if !self.reader.buffer().is_empty() || stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed = match stream.peek(&mut [0]) {
+            Err(err) => err.kind() != ErrorKind::WouldBlock,
+            // Nothing is ever sent without a request, so data means the connection is out of step
+            Ok(_) => true,
+        };
+        closed || stream.set_nonblocking(false).is_err()
+    }
+}
+
+/// Transaction running on the server, started by `KvsClient::begin`.
+///
+/// Dropping it closes the connection, which rolls the transaction back.
+#[derive(Debug)]
+pub struct RemoteTransaction {
+    connection: Connection,
+}
+
+impl RemoteTransaction {
+    fn send_command(&mut self, command: &Command) -> Result<Option<Value>> {
+        self.connection.exchange(command)?.into_result()
+    }
+
+    /// Discards the writes of the transaction.
+    pub fn rollback(mut self) -> Result<()> {
+        self.send_command(&Command::Transaction(TransactionControl::Rollback))?;
+        Ok(())
+    }
+}
+
+impl Transaction for RemoteTransaction {
+    fn get(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
+        self.send_command(&Command::from(Get::new(key.into())))
+    }
+
+    fn set(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        self.send_command(&Command::from(Set::new(key.into(), value.into())))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: impl Into<Key>) -> Result<()> {
+        self.send_command(&Command::from(Remove::new(key.into())))?;
+        Ok(())
+    }
+
+    fn commit(mut self) -> Result<()> {
+        self.send_command(&Command::Transaction(TransactionControl::Commit))?;
+        Ok(())
     }
 }
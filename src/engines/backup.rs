@@ -0,0 +1,203 @@
+use super::{engine_name, DirectoryLock, KvsEngine};
+use crate::{shared::Key, KvsError::InvalidBackup, Result};
+use fs2::FileExt;
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{Read, Write},
+    ops::Bound,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+/// Name of the file that records which engine a store uses, which also marks a complete backup
+pub(super) const ENGINE_FILE: &str = "engine";
+/// How many entries are read at once while checking a backup
+const VALIDATION_PAGE_SIZE: usize = 1024;
+
+/// Creates the directory a backup is written to, which must not exist or be empty.
+pub(crate) fn create_backup_dir(path: &Path) -> Result<()> {
+    fs::create_dir_all(path)?;
+    if fs::read_dir(path)?.next().is_some() {
+        Err(InvalidBackup(format!("{} is not empty", path.display())))?;
+    }
+    Ok(())
+}
+
+/// Marks the backup at `path` as complete, once everything else in it has been synced.
+pub(crate) fn finish_backup<Engine: KvsEngine>(path: &Path) -> Result<()> {
+    let file = path.join(ENGINE_FILE);
+    fs::write(&file, engine_name::<Engine>())?;
+    File::open(file)?.sync_all()?;
+    sync_dir(path)
+}
+
+/// Replaces the store at `path` with the backup at `backup`.
+///
+/// The backup is copied next to the store and opened, and every value in it is read, before it
+/// replaces the store. The store is left untouched if any of that fails.
+pub(crate) fn restore<Engine: KvsEngine>(backup: &Path, path: &Path) -> Result<()> {
+    recover_swap(path)?;
+    let engine = fs::read_to_string(backup.join(ENGINE_FILE))
+        .map_err(|_| InvalidBackup(format!("{} is not a complete backup", backup.display())))?;
+    if engine != engine_name::<Engine>() {
+        Err(InvalidBackup(format!(
+            "{} was written by the {} engine",
+            backup.display(),
+            engine
+        )))?;
+    }
+    let staging = sibling(path, "restore");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    copy_dir(backup, &staging)?;
+    if let Err(err) = validate::<Engine>(&staging) {
+        fs::remove_dir_all(&staging)?;
+        Err(InvalidBackup(err.to_string()))?;
+    }
+
+    swap_in(&staging, path)?;
+    info!("Restored {} from {}", path.display(), backup.display());
+    Ok(())
+}
+
+/// Replaces the store at `path`, if there is one, with the store at `staging`.
+///
+/// The swap is recorded in a journal next to the store before anything is renamed, so a swap
+/// that was cut short by a crash is finished by `recover_swap`.
+pub(super) fn swap_in(staging: &Path, path: &Path) -> Result<()> {
+    // Left behind by a swap that finished before the previous store could be deleted
+    let previous = sibling(path, "old");
+    if previous.exists() {
+        fs::remove_dir_all(&previous)?;
+    }
+    sync_tree(staging)?;
+    // Keeps the store from being opened while it is swapped out
+    let _lock = lock_if_exists(path)?;
+    let mut journal = File::create(sibling(path, "swap"))?;
+    // Held until the swap is finished, so `recover_swap` waits for it rather than racing it
+    journal.lock_exclusive()?;
+    journal.write_all(staging.as_os_str().as_bytes())?;
+    journal.sync_all()?;
+    sync_parent(path)?;
+    finish_swap(staging, path)
+}
+
+/// Finishes swapping in a store if a crash interrupted it. The journal is only written once the
+/// store being swapped in is complete and synced, so it is always safe to finish. Must run
+/// before the store is opened or its directory is created.
+pub(crate) fn recover_swap(path: &Path) -> Result<()> {
+    let journal = sibling(path, "swap");
+    let Ok(mut file) = File::open(&journal) else {
+        return Ok(());
+    };
+    file.lock_exclusive()?;
+    // The swap may have been finished while waiting for the lock
+    if !journal.exists() {
+        return Ok(());
+    }
+    let mut staging = Vec::new();
+    file.read_to_end(&mut staging)?;
+    let staging = PathBuf::from(OsString::from_vec(staging));
+    let _lock = lock_if_exists(path)?;
+    warn!(
+        "Finishing swapping {} in for {} after it was interrupted",
+        staging.display(),
+        path.display()
+    );
+    finish_swap(&staging, path)
+}
+
+/// Renames the store at `path` out of the way and `staging` into its place, then drops the
+/// journal and the previous store. Every step is skipped if it already happened.
+fn finish_swap(staging: &Path, path: &Path) -> Result<()> {
+    let previous = sibling(path, "old");
+    if staging.exists() {
+        if path.exists() {
+            fs::rename(path, &previous)?;
+        }
+        fs::rename(staging, path)?;
+        sync_parent(path)?;
+    }
+    fs::remove_file(sibling(path, "swap"))?;
+    sync_parent(path)?;
+    if previous.exists() {
+        fs::remove_dir_all(previous)?;
+    }
+    Ok(())
+}
+
+pub(super) fn lock_if_exists(path: &Path) -> Result<Option<DirectoryLock>> {
+    if path.exists() {
+        Ok(Some(DirectoryLock::acquire(path)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Opens the store at `path` and reads every value in it.
+fn validate<Engine: KvsEngine>(path: &Path) -> Result<()> {
+    let engine = Engine::open(path)?;
+    let mut start = Bound::Unbounded;
+    loop {
+        let entries = engine.scan((start, Bound::<Key>::Unbounded), VALIDATION_PAGE_SIZE)?;
+        match entries.last() {
+            Some((key, _)) if entries.len() == VALIDATION_PAGE_SIZE => {
+                start = Bound::Excluded(key.clone());
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Returns the path next to `path` with the given extension added to its name.
+pub(super) fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(extension);
+    path.with_file_name(name)
+}
+
+/// Copies the directory at `from` and everything in it to `to`.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Syncs the directory that holds `path`, so a rename of it is durable.
+fn sync_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => sync_dir(Path::new(".")),
+        Some(parent) => sync_dir(parent),
+        None => Ok(()),
+    }
+}
+
+/// Syncs every file and directory in the directory at `path`, and the directory itself.
+fn sync_tree(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            sync_tree(&entry.path())?;
+        } else {
+            File::open(entry.path())?.sync_all()?;
+        }
+    }
+    sync_dir(path)
+}
@@ -0,0 +1,230 @@
+//! A portable format for the contents of a store, which any engine can write and read.
+//!
+//! A dump is a sequence of frames, each written the same way as a log record: the little-endian
+//! `u32` length of the payload, its little-endian `u32` CRC32, then the bincode-encoded
+//! `DumpRecord`. It starts with a `Header` and ends with an `End` that counts the entries in
+//! between, so a dump that was cut short is detected when it is read.
+
+use super::{
+    backup::{sibling, swap_in, ENGINE_FILE},
+    engine_name, KvsEngine,
+};
+use crate::{
+    serde::bincode::Serde,
+    shared::{Expiry, Set, WriteBatch},
+    KvsError::{InvalidDump, TruncatedRecord, WrongEngine},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+};
+use tracing::info;
+
+/// Identifies a dump
+const DUMP_MAGIC: [u8; 4] = *b"KVSD";
+/// Version of the dump format written by this build
+pub const DUMP_FORMAT_VERSION: u32 = 1;
+/// How many entries are imported in each batch
+const IMPORT_BATCH_SIZE: usize = 1024;
+/// A file that only a store of the named engine keeps in its directory
+const ENGINE_MARKERS: [(&str, &str); 2] = [("kvs", "MANIFEST"), ("sled", "conf")];
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum DumpRecord {
+    /// First record of every dump
+    Header { magic: [u8; 4], version: u32 },
+    /// A key along with its value and, if it expires, the absolute time it expires at
+    Entry(Set),
+    /// Last record of every dump, with the number of entries in it
+    End { entries: u64 },
+}
+
+impl Serde for DumpRecord {}
+
+/// Writes a dump one entry at a time.
+pub struct DumpWriter<W: Write> {
+    writer: W,
+    entries: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Starts a dump by writing its header.
+    pub fn new(mut writer: W) -> Result<Self> {
+        DumpRecord::Header {
+            magic: DUMP_MAGIC,
+            version: DUMP_FORMAT_VERSION,
+        }
+        .write_frame(&mut writer)?;
+        Ok(Self { writer, entries: 0 })
+    }
+
+    /// Adds an entry to the dump. Its expiry, if any, must be absolute.
+    pub fn write_entry(&mut self, entry: Set) -> Result<()> {
+        DumpRecord::Entry(entry).write_frame(&mut self.writer)?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Ends the dump and returns how many entries were written.
+    pub fn finish(mut self) -> Result<u64> {
+        DumpRecord::End {
+            entries: self.entries,
+        }
+        .write_frame(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(self.entries)
+    }
+}
+
+/// Reads the entries of a dump, failing if it is damaged or cut short.
+pub struct DumpReader<R: BufRead> {
+    reader: R,
+    entries: u64,
+    finished: bool,
+}
+
+impl<R: BufRead> DumpReader<R> {
+    /// Reads the header of the dump.
+    pub fn new(mut reader: R) -> Result<Self> {
+        match Self::read_record(&mut reader)? {
+            DumpRecord::Header { magic, version } if magic == DUMP_MAGIC => {
+                if version != DUMP_FORMAT_VERSION {
+                    Err(InvalidDump(format!("unsupported version {version}")))?;
+                }
+            }
+            _ => Err(InvalidDump("missing header".to_owned()))?,
+        }
+        Ok(Self {
+            reader,
+            entries: 0,
+            finished: false,
+        })
+    }
+
+    fn read_record(reader: &mut R) -> Result<DumpRecord> {
+        DumpRecord::deserialize_from_reader(reader).map_err(|err| match err {
+            TruncatedRecord => InvalidDump("cut short".to_owned()),
+            err => InvalidDump(err.to_string()),
+        })
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Set>> {
+        match Self::read_record(&mut self.reader)? {
+            DumpRecord::Entry(entry) => {
+                self.entries += 1;
+                Ok(Some(entry))
+            }
+            DumpRecord::End { entries } if entries == self.entries => Ok(None),
+            DumpRecord::End { entries } => Err(InvalidDump(format!(
+                "expected {entries} entries but found {}",
+                self.entries
+            ))),
+            DumpRecord::Header { .. } => Err(InvalidDump("unexpected header".to_owned())),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for DumpReader<R> {
+    type Item = Result<Set>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let entry = self.next_entry();
+        // Stop at the end of the dump or at the first error
+        self.finished = !matches!(entry, Ok(Some(_)));
+        entry.transpose()
+    }
+}
+
+/// Writes the entries of a dump to the engine, a batch at a time. Entries that have expired since
+/// the dump was written are skipped.
+///
+/// Returns how many entries were read from the dump.
+pub(crate) fn import<Engine: KvsEngine>(engine: &Engine, reader: impl BufRead) -> Result<u64> {
+    let mut batch = WriteBatch::new();
+    let mut entries = 0;
+    for entry in DumpReader::new(reader)? {
+        let entry = entry?;
+        entries += 1;
+        if !entry.expiry.is_some_and(Expiry::has_passed) {
+            batch = batch.push(entry);
+        }
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            engine.write_batch(batch)?;
+            batch = WriteBatch::new();
+        }
+    }
+    engine.write_batch(batch)?;
+    Ok(entries)
+}
+
+/// Checks that the store at `path`, if there is one, uses `Engine`, going by the engine recorded
+/// in it as well as the files in it.
+///
+/// A store opened with the wrong engine looks empty, so exporting or migrating it would lose every
+/// entry, and importing into it would leave the files of both engines in the directory.
+///
+/// # Errors
+///
+/// `WrongEngine` if the store uses another engine.
+pub fn check_engine<Engine: KvsEngine>(path: &Path) -> Result<()> {
+    let engine = engine_name::<Engine>();
+    match fs::read_to_string(path.join(ENGINE_FILE)) {
+        Ok(recorded) if recorded.trim() != engine => Err(WrongEngine)?,
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err)?,
+        _ => {}
+    }
+    let foreign = ENGINE_MARKERS
+        .iter()
+        .any(|(name, marker)| *name != engine && path.join(marker).exists());
+    if foreign {
+        Err(WrongEngine)?;
+    }
+    Ok(())
+}
+
+/// Converts the store at `path` from one engine to another, in place.
+///
+/// The contents are exported to a dump next to the store and imported into a new store, which
+/// replaces the old one once every entry has been imported. The dump is removed afterwards. The
+/// store must not be open while it is migrated, and must use `Source`.
+///
+/// Returns how many entries were migrated.
+pub fn migrate<Source: KvsEngine, Target: KvsEngine>(path: &Path) -> Result<u64> {
+    check_engine::<Source>(path)?;
+    let dump = sibling(path, "dump");
+    let staging = sibling(path, "migrate");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    let source = Source::open(path)?;
+    let exported = source.export(BufWriter::new(File::create(&dump)?))?;
+    drop(source);
+    let target = Target::open(&staging)?;
+    let imported = target.import(BufReader::new(File::open(&dump)?))?;
+    drop(target);
+    if imported != exported {
+        Err(InvalidDump(format!(
+            "exported {exported} entries but imported {imported}"
+        )))?;
+    }
+
+    fs::write(staging.join(ENGINE_FILE), engine_name::<Target>())?;
+    swap_in(&staging, path)?;
+    fs::remove_file(dump)?;
+    info!(
+        "Migrated {} entries in {} from {} to {}",
+        imported,
+        path.display(),
+        engine_name::<Source>(),
+        engine_name::<Target>()
+    );
+    Ok(imported)
+}
@@ -0,0 +1,59 @@
+use super::{LogIndex, LogMetadata};
+use crate::{engines::backup::sync_dir, Result};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek},
+    path::Path,
+    sync::Mutex,
+};
+use tracing::debug;
+
+impl LogIndex {
+    /// Writes the logs of the store as they are now to `path`, along with a manifest listing
+    /// them.
+    ///
+    /// Sealed logs never change, so they are hard linked, or copied if they can't be. The active
+    /// log is copied up to the last write logged before the backup started.
+    pub(super) fn backup_to(&self, path: &Path, write_lock: &Mutex<()>) -> Result<()> {
+        // Compaction rewrites and removes sealed logs, so it has to wait
+        let _compacting = self.compacting.lock()?;
+        let write_lock = write_lock.lock()?;
+        let metadata = self.metadata.read()?.clone();
+        let active_log_size = self.writer.write()?.stream_position()?;
+        drop(write_lock);
+        debug!("Backing up logs {:?} to {}", metadata.ids, path.display());
+
+        for log_id in &metadata.ids {
+            let log_file = Self::get_log_file(&metadata.path, *log_id);
+            let backup_file = Self::get_log_file(path, *log_id);
+            if *log_id == metadata.active_log_id {
+                let mut backup = File::create(&backup_file)?;
+                io::copy(
+                    &mut File::open(log_file)?.take(active_log_size),
+                    &mut backup,
+                )?;
+                backup.sync_all()?;
+                continue;
+            }
+            link_or_copy(&log_file, &backup_file)?;
+            let hint_file = Self::get_hint_file(&metadata.path, *log_id);
+            if hint_file.exists() {
+                link_or_copy(&hint_file, &Self::get_hint_file(path, *log_id))?;
+            }
+        }
+        Self::save_manifest(&LogMetadata {
+            path: path.to_owned(),
+            ..metadata
+        })?;
+        sync_dir(path)
+    }
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        // The backup may be on another file system
+        fs::copy(from, to)?;
+        File::open(to)?.sync_all()?;
+    }
+    Ok(())
+}
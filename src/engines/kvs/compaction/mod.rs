@@ -0,0 +1,614 @@
+mod policy;
+
+pub use self::policy::*;
+use super::{
+    LogEntries, LogId, LogIndex, LogIndexState, LogMetadata, LogOffset, LogPointer, LogSize,
+};
+use crate::{
+    serde::bincode::Serde,
+    shared::{new_reader, save_atomically, BatchOperation, Command, Key, Remove, Set},
+    KvsError::ThreadError,
+    Result,
+};
+use derive_more::{Constructor, From};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufWriter, Seek},
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, warn};
+
+/// Name of the file in the log directory that an in-flight compaction is journaled to
+const COMPACTION_JOURNAL_FILE: &str = "compaction";
+
+#[derive(Constructor, Clone, Debug, Default, From, Deserialize, Serialize)]
+pub struct CompactionList {
+    ids: HashMap<LogId, CompactionAction>,
+}
+
+#[derive(Clone, Debug, From, Eq, PartialEq, Deserialize, Serialize)]
+pub enum CompactionAction {
+    /// The log still has live entries that must be copied before it can be removed
+    Migrate,
+    /// The log has no live entries left
+    Remove,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum CompactionPhase {
+    /// Live entries are being copied into `output_log_id`.
+    Migrating { output_log_id: LogId },
+    /// Every live entry has been copied, so the logs in the plan can be deleted.
+    Removing,
+}
+
+/// The compaction plan, persisted while a compaction is in flight so that it can be finished or
+/// rolled back if the process dies part way through.
+#[derive(Constructor, Clone, Debug, Deserialize, Serialize)]
+pub struct CompactionJournal {
+    phase: CompactionPhase,
+    plan: CompactionList,
+}
+
+impl Serde for CompactionJournal {}
+
+impl CompactionJournal {
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let file = path.join(COMPACTION_JOURNAL_FILE);
+        if !file.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::deserialize_from_reader(new_reader(&file)?)?))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        save_atomically(self, &path.join(COMPACTION_JOURNAL_FILE))
+    }
+
+    pub fn clear(path: &Path) -> Result<()> {
+        let file = path.join(COMPACTION_JOURNAL_FILE);
+        if file.exists() {
+            fs::remove_file(file)?;
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default)]
+struct CompactionStatus {
+    requested: bool,
+    running: bool,
+    paused: bool,
+    shutdown: bool,
+    /// Compactions that compacted any logs since the store was opened
+    completed: u64,
+    /// How long the last of them took
+    last_duration: Option<Duration>,
+}
+
+/// Controls the background thread that compacts the logs of a `KvStore`.
+///
+/// A compaction is requested every time the active log is rotated, but it can also be triggered,
+/// paused and waited on through this handle.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionHandle {
+    status: Arc<(Mutex<CompactionStatus>, Condvar)>,
+}
+
+impl CompactionHandle {
+    /// Requests a compaction without waiting for it to run.
+    pub fn trigger(&self) -> Result<()> {
+        self.update(|status| status.requested = true)
+    }
+
+    /// Stops compactions from starting until `resume` is called.
+    ///
+    /// Waits for a compaction that is already running to finish.
+    pub fn pause(&self) -> Result<()> {
+        let (lock, condvar) = &*self.status;
+        let mut status = lock.lock()?;
+        status.paused = true;
+        while status.running {
+            status = condvar.wait(status)?;
+        }
+        Ok(())
+    }
+
+    /// Allows compactions to start again, including any requested while paused.
+    pub fn resume(&self) -> Result<()> {
+        self.update(|status| status.paused = false)
+    }
+
+    /// Blocks until every requested compaction has finished.
+    ///
+    /// Returns once the running compaction (if any) has finished while compaction is paused.
+    pub fn wait(&self) -> Result<()> {
+        let (lock, condvar) = &*self.status;
+        let mut status = lock.lock()?;
+        while status.running || (status.requested && !status.paused && !status.shutdown) {
+            status = condvar.wait(status)?;
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        self.update(|status| status.shutdown = true)
+    }
+
+    /// Blocks the compaction thread until it has work to do.
+    ///
+    /// Returns `false` once the store has been shut down.
+    fn next_request(&self) -> Result<bool> {
+        let (lock, condvar) = &*self.status;
+        let mut status = lock.lock()?;
+        while !status.shutdown && (!status.requested || status.paused) {
+            status = condvar.wait(status)?;
+        }
+        if status.shutdown {
+            return Ok(false);
+        }
+        status.requested = false;
+        status.running = true;
+        Ok(true)
+    }
+
+    /// Returns how many compactions compacted any logs since the store was opened, and how long
+    /// the last of them took.
+    pub(super) fn history(&self) -> Result<(u64, Option<Duration>)> {
+        let status = self.status.0.lock()?;
+        Ok((status.completed, status.last_duration))
+    }
+
+    fn finish(&self, duration: Option<Duration>) -> Result<()> {
+        self.update(|status| {
+            status.running = false;
+            if duration.is_some() {
+                status.completed += 1;
+                status.last_duration = duration;
+            }
+        })
+    }
+
+    fn update(&self, change: impl FnOnce(&mut CompactionStatus)) -> Result<()> {
+        let (lock, condvar) = &*self.status;
+        change(&mut *lock.lock()?);
+        condvar.notify_all();
+        Ok(())
+    }
+}
+
+/// Owns the background compaction thread and stops it when dropped.
+pub struct Compactor {
+    handle: CompactionHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub(super) fn spawn(
+        index: LogIndex,
+        write_lock: Arc<Mutex<()>>,
+        policy: Arc<dyn CompactionPolicy>,
+    ) -> Result<Self> {
+        let handle = index.compaction.clone();
+        let thread = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || loop {
+                match index.compaction.next_request() {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => {
+                        error!("Compaction thread error: {}", err);
+                        break;
+                    }
+                }
+                let started = Instant::now();
+                let duration = match index.try_compacting_logs(&write_lock, &*policy) {
+                    Ok(compacted) => compacted.then(|| started.elapsed()),
+                    Err(err) => {
+                        error!("Compaction failed: {}", err);
+                        None
+                    }
+                };
+                if let Err(err) = index.compaction.finish(duration) {
+                    error!("Compaction thread error: {}", err);
+                    break;
+                }
+            })
+            .map_err(|e| ThreadError(e.to_string()))?;
+        Ok(Self {
+            handle,
+            thread: Some(thread),
+        })
+    }
+
+    #[must_use]
+    pub fn handle(&self) -> &CompactionHandle {
+        &self.handle
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        if let Err(err) = self.handle.shutdown() {
+            error!("Unable to stop compaction thread: {}", err);
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Compaction thread panicked");
+            }
+        }
+    }
+}
+
+impl LogIndex {
+    /// Finishes or rolls back a compaction that was interrupted before it completed.
+    ///
+    /// - If the process died while entries were being migrated, the source logs are still intact,
+    ///   so the partially written output log is deleted.
+    /// - If it died while stale logs were being removed, the remaining ones are removed.
+    pub(super) fn recover_interrupted_compaction(path: &Path) -> Result<()> {
+        let Some(journal) = CompactionJournal::load(path)? else {
+            return Ok(());
+        };
+        match journal.phase {
+            CompactionPhase::Migrating { output_log_id } => {
+                warn!(
+                    "Rolling back interrupted compaction into log {}",
+                    output_log_id
+                );
+                Self::remove_from_manifest(path, &[output_log_id])?;
+                let file = Self::get_log_file(path, output_log_id);
+                if file.exists() {
+                    fs::remove_file(file)?;
+                }
+            }
+            CompactionPhase::Removing => {
+                warn!("Finishing interrupted compaction");
+                let log_ids = journal.plan.ids.keys().copied().collect::<Vec<_>>();
+                Self::remove_from_manifest(path, &log_ids)?;
+                Self::remove_logs(path, &journal.plan)?;
+            }
+        }
+        CompactionJournal::clear(path)
+    }
+
+    pub(super) fn try_compacting_logs(
+        &self,
+        write_lock: &Mutex<()>,
+        policy: &dyn CompactionPolicy,
+    ) -> Result<bool> {
+        let _compacting = self.compacting.lock()?;
+        self.metadata.write()?.state = LogIndexState::Compacting;
+        let result = self.compact_logs(write_lock, policy);
+        self.metadata.write()?.state = LogIndexState::Ready;
+        result
+    }
+
+    /// Returns whether any logs were compacted.
+    fn compact_logs(&self, write_lock: &Mutex<()>, policy: &dyn CompactionPolicy) -> Result<bool> {
+        // Logs sealed since the last compaction (including its output) get their hint files here
+        self.write_missing_hints()?;
+        let plan = self.identify_logs_that_can_be_compacted(policy)?;
+        if plan.ids.is_empty() {
+            return Ok(false);
+        }
+        debug!("Compacting logs: {:?}", plan.ids);
+        let output_log_id = {
+            let _write_lock = write_lock.lock()?;
+            let mut metadata = self.metadata.write()?;
+            self.reserve_output_log(&mut metadata)?
+        };
+        let path = self.metadata.read()?.path.clone();
+        let phase = CompactionPhase::Migrating { output_log_id };
+        CompactionJournal::new(phase, plan.clone()).save(&path)?;
+
+        let mut plan = plan;
+        if !self.try_migrating_infrequently_accessed_keys(&plan, output_log_id)? {
+            // Nothing needed to be kept, so the output log can go as well
+            plan.ids.insert(output_log_id, CompactionAction::Remove);
+        }
+        CompactionJournal::new(CompactionPhase::Removing, plan.clone()).save(&path)?;
+
+        self.try_removing_stale_logs(&plan)?;
+        CompactionJournal::clear(&path)?;
+        self.write_missing_hints()?;
+        Ok(true)
+    }
+
+    /// Rotates the active log, leaving a gap in the ids for the compaction output.
+    ///
+    /// Entries copied into the output log must replay after the logs they came from but before
+    /// anything written to the new active log while the compaction is running.
+    fn reserve_output_log(&self, metadata: &mut LogMetadata) -> Result<LogId> {
+        let output_log_id = metadata.active_log_id + 1;
+        self.reader.insert(
+            output_log_id,
+            Self::log_reader(&metadata.path, output_log_id)?,
+        );
+        metadata.ids.push(output_log_id);
+        self.rotate_log(metadata, output_log_id + 1)?;
+        Ok(output_log_id)
+    }
+
+    fn identify_logs_that_can_be_compacted(
+        &self,
+        policy: &dyn CompactionPolicy,
+    ) -> Result<CompactionList> {
+        let metadata = self.metadata.read()?;
+        let sealed_logs = metadata
+            .ids
+            .iter()
+            .filter(|log_id| **log_id != metadata.active_log_id)
+            .map(|log_id| {
+                self.usage
+                    .get(log_id)
+                    .map_or_else(|| LogUsage::new(*log_id), |usage| usage.clone())
+            })
+            .collect::<Vec<_>>();
+        drop(metadata);
+
+        let mut eligible_ids = HashMap::new();
+        for log in &sealed_logs {
+            if log.live_bytes == 0 {
+                // Mark this log as one that can be deleted
+                eligible_ids.insert(log.id, CompactionAction::Remove);
+            }
+        }
+        // Expired entries are only dropped when their log is migrated, so they count as stale
+        // towards the policy but never make a log removable on their own
+        let expired_bytes = self.expired_bytes()?;
+        let sealed_logs = sealed_logs
+            .into_iter()
+            .map(|mut log| {
+                let expired = expired_bytes.get(&log.id).copied().unwrap_or_default();
+                log.live_bytes = log.live_bytes.saturating_sub(expired);
+                log.stale_bytes += expired;
+                log
+            })
+            .collect::<Vec<_>>();
+        for log_id in policy.select(&sealed_logs) {
+            // Mark this log as one that has entries that need migrating
+            eligible_ids
+                .entry(log_id)
+                .or_insert(CompactionAction::Migrate);
+        }
+        Ok(CompactionList::new(eligible_ids))
+    }
+
+    /// Returns how many bytes of each log are taken up by values that have expired.
+    fn expired_bytes(&self) -> Result<HashMap<LogId, LogSize>> {
+        // Work on a snapshot so writers aren't blocked while the index is walked
+        let database = self.database.read()?.clone();
+        // Every key in a batch points at the same record, which only counts once and only once
+        // all of them have expired
+        let (expired, live): (Vec<_>, Vec<_>) =
+            database.values().partition(|pointer| pointer.has_expired());
+        let live_records = live
+            .into_iter()
+            .map(|pointer| (pointer.id, pointer.offset))
+            .collect::<HashSet<_>>();
+        let mut expired_records = HashSet::new();
+        let mut expired_bytes = HashMap::new();
+        for pointer in expired {
+            let record = (pointer.id, pointer.offset);
+            if !live_records.contains(&record) && expired_records.insert(record) {
+                *expired_bytes.entry(pointer.id).or_default() += pointer.length;
+            }
+        }
+        Ok(expired_bytes)
+    }
+
+    /// Copies the entries that are still needed out of the logs in the plan into the output log.
+    ///
+    /// A `set` is copied if the index still points at it and it hasn't expired. A `rm` is copied
+    /// if its key hasn't been set again and an older log that may contain the removed value is
+    /// being kept. An expired `set` is dropped from the index, and replaced by a `rm` under the
+    /// same condition. Batches are split into the `set` and `rm` records they are made of.
+    ///
+    /// Returns whether anything was copied.
+    fn try_migrating_infrequently_accessed_keys(
+        &self,
+        plan: &CompactionList,
+        output_log_id: LogId,
+    ) -> Result<bool> {
+        let (path, oldest_kept_log_id) = {
+            let metadata = self.metadata.read()?;
+            let oldest_kept_log_id = metadata
+                .ids
+                .iter()
+                .filter(|log_id| !plan.ids.contains_key(log_id))
+                .min()
+                .copied()
+                .unwrap_or(output_log_id);
+            (metadata.path.clone(), oldest_kept_log_id)
+        };
+        let mut writer = Self::log_writer(&path, output_log_id)?;
+        let mut log_ids = plan.ids.keys().copied().collect::<Vec<_>>();
+        log_ids.sort_unstable();
+
+        for log_id in log_ids {
+            let keep_removals = oldest_kept_log_id < log_id;
+            for entry in LogEntries::open(&path, log_id)? {
+                let (command, log_pointer) = entry?;
+                match command {
+                    Command::Set(cmd) => self.migrate_value(
+                        &mut writer,
+                        output_log_id,
+                        cmd,
+                        &log_pointer,
+                        keep_removals,
+                    )?,
+                    Command::Rm(cmd) => {
+                        self.migrate_removal(&mut writer, output_log_id, cmd.key, keep_removals)?;
+                    }
+                    // The whole batch was applied when it was written, so the writes in it can be
+                    // copied one at a time
+                    Command::Batch(batch) => {
+                        for operation in batch.into_operations() {
+                            match operation {
+                                BatchOperation::Set(cmd) => {
+                                    let log_pointer = log_pointer.to_batched(&cmd);
+                                    self.migrate_value(
+                                        &mut writer,
+                                        output_log_id,
+                                        cmd,
+                                        &log_pointer,
+                                        keep_removals,
+                                    )?;
+                                }
+                                BatchOperation::Rm(cmd) => self.migrate_removal(
+                                    &mut writer,
+                                    output_log_id,
+                                    cmd.key,
+                                    keep_removals,
+                                )?,
+                            }
+                        }
+                    }
+                    Command::Get(_)
+                    | Command::Scan(_)
+                    | Command::Ttl(_)
+                    | Command::Cas(_)
+                    | Command::SetIfAbsent(_)
+                    | Command::RmIfEquals(_)
+                    | Command::Transaction(_)
+                    | Command::Admin(_) => {}
+                }
+            }
+        }
+        // The copied entries must be durable before the logs they came from are deleted
+        writer.get_ref().sync_data()?;
+        Ok(writer.stream_position()? > 0)
+    }
+
+    /// Copies a `set` into the output log if the index still points at it, and moves the index
+    /// over to the copy.
+    fn migrate_value(
+        &self,
+        writer: &mut BufWriter<File>,
+        output_log_id: LogId,
+        cmd: Set,
+        log_pointer: &LogPointer,
+        keep_removals: bool,
+    ) -> Result<()> {
+        if !self.points_to(&cmd.key, log_pointer)? {
+            return Ok(());
+        }
+        if log_pointer.has_expired() {
+            self.drop_expired(&cmd.key, log_pointer)?;
+            return self.migrate_removal(writer, output_log_id, cmd.key, keep_removals);
+        }
+        let key = cmd.key.clone();
+        let (offset, length) = Self::append(writer, &cmd.into())?;
+        // Only move the pointer if the key wasn't written while it was copied. It keeps its
+        // version, as the value hasn't changed.
+        let moved = self
+            .database
+            .write()?
+            .get_mut(&key)
+            .filter(|pointer| pointer.is_at(log_pointer))
+            .map(|pointer| {
+                *pointer = LogPointer {
+                    id: output_log_id,
+                    offset,
+                    length,
+                    ..pointer.clone()
+                };
+            })
+            .is_some();
+        if moved {
+            self.add_live_bytes(output_log_id, length);
+        } else {
+            self.add_stale_bytes(output_log_id, length);
+        }
+        Ok(())
+    }
+
+    /// Writes a `rm` into the output log if the key hasn't been set again and older logs that
+    /// may hold its value are kept.
+    fn migrate_removal(
+        &self,
+        writer: &mut BufWriter<File>,
+        output_log_id: LogId,
+        key: Key,
+        keep_removals: bool,
+    ) -> Result<()> {
+        if keep_removals && !self.database.read()?.contains_key(&key) {
+            let (_, length) = Self::append(writer, &Remove::new(key).into())?;
+            self.add_stale_bytes(output_log_id, length);
+        }
+        Ok(())
+    }
+
+    fn append(writer: &mut BufWriter<File>, command: &Command) -> Result<(LogOffset, LogSize)> {
+        let offset = command.serialize_into_writer(&mut *writer)?;
+        Ok((offset, writer.stream_position()? - offset))
+    }
+
+    /// Removes an expired key from the index, unless it was written again in the meantime.
+    fn drop_expired(&self, key: &[u8], log_pointer: &LogPointer) -> Result<()> {
+        let mut database = self.database.write()?;
+        if database
+            .get(key)
+            .is_some_and(|pointer| pointer.is_at(log_pointer))
+        {
+            database.remove(key);
+        }
+        Ok(())
+    }
+
+    fn points_to(&self, key: &[u8], log_pointer: &LogPointer) -> Result<bool> {
+        Ok(self
+            .database
+            .read()?
+            .get(key)
+            .is_some_and(|pointer| pointer.is_at(log_pointer)))
+    }
+
+    /// Drops the logs in the plan from the store and deletes them, except for those pinned by a
+    /// snapshot, which are deleted once the last snapshot pinning them is dropped.
+    fn try_removing_stale_logs(&self, plan: &CompactionList) -> Result<()> {
+        let mut pins = self.pins.lock()?;
+        let mut metadata = self.metadata.write()?;
+        // Drop the logs from the manifest first, so it never lists a log that was deleted
+        metadata.ids.retain(|log_id| !plan.ids.contains_key(log_id));
+        Self::save_manifest(&metadata)?;
+        self.batch_refs
+            .retain(|(log_id, _), _| !plan.ids.contains_key(log_id));
+        for log_id in plan.ids.keys() {
+            self.usage.remove(log_id);
+            if !pins.retire(*log_id) {
+                Self::remove_log_files(&metadata.path, *log_id)?;
+                self.reader.remove(log_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_logs(path: &Path, plan: &CompactionList) -> Result<()> {
+        for log_id in plan.ids.keys() {
+            Self::remove_log_files(path, *log_id)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a log and its hint file.
+    pub(super) fn remove_log_files(path: &Path, log_id: LogId) -> Result<()> {
+        // Remove the hint first so it can never describe a log that no longer exists
+        for file in [
+            Self::get_hint_file(path, log_id),
+            Self::get_log_file(path, log_id),
+        ] {
+            if file.exists() && file.is_file() {
+                fs::remove_file(file)?;
+            }
+        }
+        Ok(())
+    }
+}
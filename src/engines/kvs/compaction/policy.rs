@@ -0,0 +1,129 @@
+use super::super::{LogId, LogSize};
+use derive_more::Constructor;
+use std::fmt::Debug;
+
+/// How many bytes of a log are still referenced by the index and how many have been overwritten
+/// or removed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LogUsage {
+    pub id: LogId,
+    pub live_bytes: LogSize,
+    pub stale_bytes: LogSize,
+}
+
+impl LogUsage {
+    #[must_use]
+    pub fn new(id: LogId) -> Self {
+        Self {
+            id,
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn total_bytes(&self) -> LogSize {
+        self.live_bytes + self.stale_bytes
+    }
+}
+
+/// Decides which sealed logs are compacted when a compaction runs.
+///
+/// Logs without any live bytes are always compacted, whatever the policy selects.
+pub trait CompactionPolicy: Debug + Send + Sync {
+    /// Returns the ids of the logs to compact, given the usage of every sealed log in id order.
+    fn select(&self, logs: &[LogUsage]) -> Vec<LogId>;
+}
+
+/// Compacts every log where at least `min_stale_percent` of the bytes are stale.
+#[derive(Constructor, Clone, Debug)]
+pub struct StaleRatioPolicy {
+    min_stale_percent: u64,
+}
+
+impl Default for StaleRatioPolicy {
+    fn default() -> Self {
+        Self::new(50)
+    }
+}
+
+impl CompactionPolicy for StaleRatioPolicy {
+    fn select(&self, logs: &[LogUsage]) -> Vec<LogId> {
+        logs.iter()
+            .filter(|log| {
+                log.stale_bytes > 0
+                    && log.stale_bytes * 100 >= log.total_bytes() * self.min_stale_percent
+            })
+            .map(|log| log.id)
+            .collect()
+    }
+}
+
+/// Compacts every log holding stale bytes once the stale bytes across all logs reach
+/// `max_stale_bytes`.
+#[derive(Constructor, Clone, Debug)]
+pub struct GarbageThresholdPolicy {
+    max_stale_bytes: LogSize,
+}
+
+impl Default for GarbageThresholdPolicy {
+    fn default() -> Self {
+        Self::new(256 * 1024 * 1024)
+    }
+}
+
+impl CompactionPolicy for GarbageThresholdPolicy {
+    fn select(&self, logs: &[LogUsage]) -> Vec<LogId> {
+        let stale_bytes = logs.iter().map(|log| log.stale_bytes).sum::<LogSize>();
+        if stale_bytes < self.max_stale_bytes {
+            return Vec::new();
+        }
+        logs.iter()
+            .filter(|log| log.stale_bytes > 0)
+            .map(|log| log.id)
+            .collect()
+    }
+}
+
+/// Groups logs of a similar size into tiers and merges the largest tier once it holds at least
+/// `min_logs` logs.
+///
+/// A log joins a tier if its size is between `low_percent` and `high_percent` of the average size
+/// of the logs already in that tier.
+#[derive(Constructor, Clone, Debug)]
+pub struct SizeTieredPolicy {
+    min_logs: usize,
+    low_percent: u64,
+    high_percent: u64,
+}
+
+impl Default for SizeTieredPolicy {
+    fn default() -> Self {
+        Self::new(4, 50, 150)
+    }
+}
+
+impl CompactionPolicy for SizeTieredPolicy {
+    fn select(&self, logs: &[LogUsage]) -> Vec<LogId> {
+        let mut logs = logs.to_vec();
+        logs.sort_by_key(LogUsage::total_bytes);
+        let mut tiers: Vec<Vec<LogUsage>> = Vec::new();
+        for log in logs {
+            if let Some(tier) = tiers.last_mut() {
+                let average =
+                    tier.iter().map(LogUsage::total_bytes).sum::<LogSize>() / tier.len() as LogSize;
+                let size = log.total_bytes() * 100;
+                if size >= average * self.low_percent && size <= average * self.high_percent {
+                    tier.push(log);
+                    continue;
+                }
+            }
+            tiers.push(vec![log]);
+        }
+        tiers
+            .into_iter()
+            .filter(|tier| tier.len() >= self.min_logs.max(2))
+            .max_by_key(Vec::len)
+            .map(|tier| tier.iter().map(|log| log.id).collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{
+    KvsError::{GeneralError, ThreadError},
+    Result,
+};
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use std::{
+    fmt::{Debug, Display, Formatter},
+    fs::File,
+    io,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tracing::error;
+
+/// When the data appended to the active log is flushed to durable storage with `fsync`.
+///
+/// Every write is flushed to the OS regardless of the policy, so it survives the process crashing,
+/// but only data that has been synced survives a power failure.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// Leave it to the OS to write the data back
+    #[default]
+    Never,
+    /// Sync before a write is acknowledged. Concurrent writers share a single sync.
+    Always,
+    /// Sync from a background thread on the given interval
+    Interval(Duration),
+    /// Sync before acknowledging a write once this many bytes are unsynced
+    Bytes(u64),
+}
+
+impl Display for SyncPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncPolicy::Never => write!(f, "never"),
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+            SyncPolicy::Bytes(bytes) => write!(f, "{bytes}b"),
+        }
+    }
+}
+
+/// Parses `never`, `always`, an interval such as `100ms` or a byte count such as `4096b`.
+///
+/// Zero is refused for both: a `0ms` interval would sync in a busy loop, and `0b` is `always`.
+impl FromStr for SyncPolicy {
+    type Err = crate::KvsError;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        let invalid = || GeneralError(format!("Invalid sync policy '{policy}'"));
+        match policy {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => {
+                if let Some(millis) = policy.strip_suffix("ms") {
+                    let millis = millis.parse::<NonZeroU64>().map_err(|_| invalid())?;
+                    Ok(SyncPolicy::Interval(Duration::from_millis(millis.get())))
+                } else if let Some(bytes) = policy.strip_suffix('b') {
+                    let bytes = bytes.parse::<NonZeroU64>().map_err(|_| invalid())?;
+                    Ok(SyncPolicy::Bytes(bytes.get()))
+                } else {
+                    Err(invalid())
+                }
+            }
+        }
+    }
+}
+
+/// The file operations the write path relies on for durability.
+///
+/// The default implementation calls through to the OS. Tests can replace it to count syncs or
+/// inject failures.
+pub trait FileLayer: Debug + Send + Sync {
+    /// Flushes the data written to `file`, the log at `path`, to durable storage.
+    fn sync_data(&self, path: &Path, file: &File) -> io::Result<()>;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OsFileLayer;
+
+impl FileLayer for OsFileLayer {
+    fn sync_data(&self, _path: &Path, file: &File) -> io::Result<()> {
+        file.sync_data()
+    }
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    /// Total bytes appended to the logs since the store was opened
+    written: u64,
+    /// How many of the written bytes are known to be durable
+    synced: u64,
+    /// Whether a writer is currently syncing on behalf of the others
+    syncing: bool,
+}
+
+/// Applies the `SyncPolicy` to the active log, grouping concurrent syncs into one.
+#[derive(Debug)]
+pub struct LogSync {
+    policy: SyncPolicy,
+    layer: Arc<dyn FileLayer>,
+    state: Mutex<SyncState>,
+    condvar: Condvar,
+    active_log: Mutex<(PathBuf, Arc<File>)>,
+}
+
+impl LogSync {
+    pub(super) fn new(
+        policy: SyncPolicy,
+        layer: Arc<dyn FileLayer>,
+        path: PathBuf,
+        active_log: File,
+    ) -> Self {
+        Self {
+            policy,
+            layer,
+            state: Mutex::default(),
+            condvar: Condvar::new(),
+            active_log: Mutex::new((path, Arc::new(active_log))),
+        }
+    }
+
+    pub(super) fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    /// Switches to a new active log, syncing the one it replaces unless syncing is disabled.
+    pub(super) fn set_active_log(&self, path: PathBuf, file: File) -> Result<()> {
+        let mut active_log = self.active_log.lock()?;
+        if self.policy != SyncPolicy::Never {
+            let mut state = self.state.lock()?;
+            self.layer.sync_data(&active_log.0, &active_log.1)?;
+            state.synced = state.written;
+        }
+        *active_log = (path, Arc::new(file));
+        Ok(())
+    }
+
+    /// Records that `length` bytes were appended to the active log and returns the position the
+    /// write must be synced up to before it is durable.
+    pub(super) fn appended(&self, length: u64) -> Result<u64> {
+        let mut state = self.state.lock()?;
+        state.written += length;
+        Ok(state.written)
+    }
+
+    /// Syncs a write that ended at `position` if the policy requires it before acknowledging it.
+    pub(super) fn commit(&self, position: u64) -> Result<()> {
+        match self.policy {
+            SyncPolicy::Never | SyncPolicy::Interval(_) => Ok(()),
+            SyncPolicy::Always => self.sync_until(position),
+            SyncPolicy::Bytes(bytes) => {
+                let state = self.state.lock()?;
+                if state.written.saturating_sub(state.synced) < bytes {
+                    return Ok(());
+                }
+                drop(state);
+                self.sync_until(position)
+            }
+        }
+    }
+
+    /// Syncs everything written so far.
+    pub(super) fn sync_all(&self) -> Result<()> {
+        let written = self.state.lock()?.written;
+        self.sync_until(written)
+    }
+
+    /// Waits until `position` is durable, syncing the active log if no one else is.
+    ///
+    /// The writer that performs the sync covers every write that finished before it started, so
+    /// concurrent writers share a single sync (group commit).
+    fn sync_until(&self, position: u64) -> Result<()> {
+        let mut state = self.state.lock()?;
+        loop {
+            if state.synced >= position {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.condvar.wait(state)?;
+        }
+        state.syncing = true;
+        // Writes up to here are in the active log, or in a sealed log synced when it was rotated
+        let target = state.written;
+        drop(state);
+
+        let (path, file) = self.active_log.lock()?.clone();
+        let result = self.layer.sync_data(&path, &file);
+
+        let mut state = self.state.lock()?;
+        state.syncing = false;
+        if result.is_ok() {
+            state.synced = state.synced.max(target);
+        }
+        self.condvar.notify_all();
+        Ok(result?)
+    }
+}
+
+/// Syncs the active log on an interval, stopping when dropped.
+pub struct Flusher {
+    shutdown: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub(super) fn spawn(sync: Arc<LogSync>) -> Result<Self> {
+        let SyncPolicy::Interval(interval) = sync.policy() else {
+            return Ok(Self {
+                shutdown: None,
+                thread: None,
+            });
+        };
+        let (shutdown, receiver) = channel::bounded::<()>(0);
+        let thread = thread::Builder::new()
+            .name("kvs-flusher".to_owned())
+            .spawn(move || {
+                // Stops once the channel is disconnected
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                    if let Err(err) = sync.sync_all() {
+                        error!("Unable to sync log: {}", err);
+                    }
+                }
+            })
+            .map_err(|e| ThreadError(e.to_string()))?;
+        Ok(Self {
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // Disconnecting the channel stops the thread
+        self.shutdown.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Flusher thread panicked");
+            }
+        }
+    }
+}
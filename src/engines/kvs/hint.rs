@@ -0,0 +1,196 @@
+use super::{LogEntries, LogId, LogIndex, LogOffset, LogPointer, LogSize};
+use crate::{
+    serde::bincode::Serde,
+    shared::{new_reader, save_atomically, BatchOperation, Command, Key},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, warn};
+
+/// The location of an entry in a sealed log, without its value.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct HintEntry {
+    key: Key,
+    offset: LogOffset,
+    length: LogSize,
+    tombstone: bool,
+    /// Unix time in milliseconds the value expires at
+    expires_at: Option<u64>,
+}
+
+/// Bitcask-style hint file, written next to a sealed log so the log can be indexed on open
+/// without reading every value in it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HintFile {
+    log_id: LogId,
+    /// Size of the log when the hint was written, used to check the hint still describes it
+    log_size: LogSize,
+    entries: Vec<HintEntry>,
+}
+
+impl Serde for HintFile {}
+
+impl LogIndex {
+    pub(super) fn get_hint_file(path: &Path, log_id: LogId) -> PathBuf {
+        path.join(format!("{log_id}.hint"))
+    }
+
+    /// Writes a hint file for every sealed log that doesn't have one yet.
+    pub(super) fn write_missing_hints(&self) -> Result<()> {
+        let metadata = self.metadata.read()?;
+        let path = metadata.path.clone();
+        let sealed_log_ids = metadata
+            .ids
+            .iter()
+            .filter(|log_id| **log_id != metadata.active_log_id)
+            .copied()
+            .collect::<Vec<_>>();
+        drop(metadata);
+        for log_id in sealed_log_ids {
+            if !Self::get_hint_file(&path, log_id).exists() {
+                Self::write_hint(&path, log_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_hint(path: &Path, log_id: LogId) -> Result<()> {
+        let log_size = fs::metadata(Self::get_log_file(path, log_id))?.len();
+        let mut entries = Vec::new();
+        for entry in LogEntries::open(path, log_id)? {
+            let (command, pointer) = entry?;
+            match command {
+                Command::Set(cmd) => entries.push(HintEntry::new(cmd.key, &pointer)),
+                Command::Rm(cmd) => entries.push(HintEntry::removal(cmd.key, &pointer)),
+                Command::Batch(batch) => {
+                    for operation in batch.into_operations() {
+                        entries.push(match operation {
+                            BatchOperation::Set(cmd) => {
+                                let pointer = pointer.to_batched(&cmd);
+                                HintEntry::new(cmd.key, &pointer)
+                            }
+                            BatchOperation::Rm(cmd) => HintEntry::removal(cmd.key, &pointer),
+                        });
+                    }
+                }
+                Command::Get(_)
+                | Command::Scan(_)
+                | Command::Ttl(_)
+                | Command::Cas(_)
+                | Command::SetIfAbsent(_)
+                | Command::RmIfEquals(_)
+                | Command::Transaction(_)
+                | Command::Admin(_) => {}
+            }
+        }
+        debug!("Writing hint file for log {}", log_id);
+        let hint = HintFile {
+            log_id,
+            log_size,
+            entries,
+        };
+        save_atomically(&hint, &Self::get_hint_file(path, log_id))
+    }
+
+    /// Loads the hint file of a log, if it exists and still matches the log.
+    pub(super) fn load_hint(path: &Path, log_id: LogId) -> Option<HintFile> {
+        let file = Self::get_hint_file(path, log_id);
+        if !file.exists() {
+            return None;
+        }
+        let hint = new_reader(&file).and_then(HintFile::deserialize_from_reader);
+        let log_size = fs::metadata(Self::get_log_file(path, log_id)).map(|m| m.len());
+        match (hint, log_size) {
+            (Ok(hint), Ok(log_size))
+                if hint.log_id == log_id
+                    && hint.log_size == log_size
+                    && hint.covers_whole_log() =>
+            {
+                Some(hint)
+            }
+            (Ok(_), _) => {
+                warn!("Hint file for log {} is out of date, replaying log", log_id);
+                None
+            }
+            (Err(err), _) => {
+                warn!("Unable to read hint file for log {}: {}", log_id, err);
+                None
+            }
+        }
+    }
+
+    pub(super) fn replay_hint(&self, hint: HintFile) -> Result<()> {
+        let mut entries = hint.entries.into_iter().peekable();
+        while let Some(entry) = entries.next() {
+            let log_pointer = LogPointer::new(hint.log_id, entry.offset, entry.length);
+            let mut writes = vec![entry.into_write(hint.log_id)];
+            // Every write in a batch has an entry for the same record
+            while let Some(entry) = entries.next_if(|entry| entry.offset == log_pointer.offset) {
+                writes.push(entry.into_write(hint.log_id));
+            }
+            if writes.len() > 1 {
+                self.index_batch_writes(&log_pointer, writes)?;
+            } else if let Some((key, pointer)) = writes.pop() {
+                match pointer {
+                    Some(pointer) => self.index_value(key, pointer)?,
+                    None => self.index_removal(&key, &log_pointer)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HintFile {
+    /// Checks the entries follow each other from the start to the end of the log, as every
+    /// record in a log is a `set`, a `rm` or a batch of them. Hint files written in an older
+    /// layout don't.
+    fn covers_whole_log(&self) -> bool {
+        let mut offset = 0;
+        let mut last_record = None;
+        for entry in &self.entries {
+            // Every write in a batch has an entry for the same record
+            if last_record == Some((entry.offset, entry.length)) {
+                continue;
+            }
+            if entry.offset != offset {
+                return false;
+            }
+            offset += entry.length;
+            last_record = Some((entry.offset, entry.length));
+        }
+        offset == self.log_size
+    }
+}
+
+impl HintEntry {
+    fn new(key: Key, pointer: &LogPointer) -> Self {
+        Self {
+            key,
+            offset: pointer.offset,
+            length: pointer.length,
+            tombstone: false,
+            expires_at: pointer.expires_at,
+        }
+    }
+
+    fn removal(key: Key, pointer: &LogPointer) -> Self {
+        Self {
+            tombstone: true,
+            ..Self::new(key, pointer)
+        }
+    }
+
+    /// Returns the key with the pointer to its value, or no pointer if the entry removes it.
+    fn into_write(self, log_id: LogId) -> (Key, Option<LogPointer>) {
+        let pointer = (!self.tombstone).then(|| LogPointer {
+            expires_at: self.expires_at,
+            ..LogPointer::new(log_id, self.offset, self.length)
+        });
+        (self.key, pointer)
+    }
+}
@@ -0,0 +1,312 @@
+//! Offline inspection and repair of the logs of a `KvStore`.
+//!
+//! Nothing here opens the store, so it works on stores that fail to open. The store is locked
+//! while it is inspected or repaired though, so neither runs while a server has it open.
+
+use super::{
+    compaction::CompactionJournal, LogEntries, LogId, LogIndex, LogIndexState, LogMetadata,
+    LogOffset, LogSize,
+};
+use crate::{
+    engines::backup::{finish_backup, lock_if_exists},
+    shared::{unix_millis_now, BatchOperation, Command, Key},
+    KvStore,
+    KvsError::GeneralError,
+    Result,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+};
+use tracing::info;
+
+/// What was found in the logs of a store.
+#[derive(Debug)]
+pub struct StoreReport {
+    /// Whether the logs are listed in a manifest, rather than found in the directory
+    pub has_manifest: bool,
+    /// Whether a compaction was interrupted, which is finished or rolled back the next time the
+    /// store is opened
+    pub interrupted_compaction: bool,
+    /// Every log of the store, oldest first
+    pub logs: Vec<LogReport>,
+}
+
+/// What was found in a single log.
+#[derive(Debug)]
+pub struct LogReport {
+    pub log_id: LogId,
+    /// Whether this is the log new writes are appended to
+    pub active: bool,
+    /// Whether the log is listed in the manifest but its file doesn't exist
+    pub missing: bool,
+    pub size: LogSize,
+    /// Every record that could be read, in the order they were written
+    pub records: Vec<RecordReport>,
+    /// The first record that couldn't be read, if any. Nothing after it is read.
+    pub damage: Option<LogDamage>,
+}
+
+/// A record read from a log.
+#[derive(Debug)]
+pub struct RecordReport {
+    pub offset: LogOffset,
+    pub length: LogSize,
+    pub command: Command,
+    /// Whether the index rebuilt from every log still points at the record. A batch is live as
+    /// long as any of its writes is.
+    pub live: bool,
+}
+
+/// A record that couldn't be read.
+#[derive(Debug)]
+pub struct LogDamage {
+    pub offset: LogOffset,
+    pub error: String,
+    /// Whether the record was left half-written at the end of the active log by a crash, which
+    /// the store drops by itself when it is opened. Any other damage stops it from opening.
+    pub torn_tail: bool,
+}
+
+impl StoreReport {
+    /// Checks whether the store would open without losing anything but a torn tail.
+    #[must_use]
+    pub fn is_healthy(&self) -> bool {
+        self.logs
+            .iter()
+            .all(|log| !log.missing && log.damage.as_ref().is_none_or(|damage| damage.torn_tail))
+    }
+}
+
+impl LogReport {
+    #[must_use]
+    pub fn live_records(&self) -> usize {
+        self.records.iter().filter(|record| record.live).count()
+    }
+
+    #[must_use]
+    pub fn stale_records(&self) -> usize {
+        self.records.len() - self.live_records()
+    }
+
+    #[must_use]
+    pub fn live_bytes(&self) -> LogSize {
+        self.records
+            .iter()
+            .filter(|record| record.live)
+            .map(|record| record.length)
+            .sum()
+    }
+
+    #[must_use]
+    pub fn stale_bytes(&self) -> LogSize {
+        self.records
+            .iter()
+            .filter(|record| !record.live)
+            .map(|record| record.length)
+            .sum()
+    }
+
+    /// Size of the log up to the first damaged record.
+    #[must_use]
+    pub fn readable_size(&self) -> LogSize {
+        self.damage
+            .as_ref()
+            .map_or(self.size, |damage| damage.offset)
+    }
+}
+
+/// Reads every log of the store at `path`, decoding each record and checking which of them the
+/// store still uses.
+///
+/// # Errors
+///
+/// `StoreLocked` if the store is open.
+pub fn inspect(path: impl AsRef<Path>) -> Result<StoreReport> {
+    let path = path.as_ref();
+    let _lock = lock_if_exists(path)?;
+    inspect_locked(path)
+}
+
+fn inspect_locked(path: &Path) -> Result<StoreReport> {
+    let manifest = LogIndex::read_manifest(path)?;
+    let has_manifest = manifest.is_some();
+    let log_ids = match manifest {
+        Some(log_ids) => log_ids,
+        None => LogIndex::get_file_log_ids(path)?
+            .into_iter()
+            .filter(|log_id| LogIndex::get_log_file(path, *log_id).exists())
+            .collect(),
+    };
+    let active_log_id = log_ids.last().copied();
+
+    let mut logs = Vec::with_capacity(log_ids.len());
+    // Rebuilt the same way as when the store is opened, pointing at the record of each value
+    let mut index = HashMap::<Key, (LogId, LogOffset, Option<u64>)>::new();
+    for log_id in log_ids {
+        let log = inspect_log(path, log_id, Some(log_id) == active_log_id)?;
+        for record in &log.records {
+            index_record(&mut index, log_id, record);
+        }
+        logs.push(log);
+    }
+
+    let now = unix_millis_now();
+    let live = index
+        .into_values()
+        .filter(|(_, _, expires_at)| expires_at.is_none_or(|expires_at| expires_at > now))
+        .map(|(log_id, offset, _)| (log_id, offset))
+        .collect::<HashSet<_>>();
+    for log in &mut logs {
+        for record in &mut log.records {
+            record.live = live.contains(&(log.log_id, record.offset));
+        }
+    }
+
+    Ok(StoreReport {
+        has_manifest,
+        interrupted_compaction: CompactionJournal::load(path)?.is_some(),
+        logs,
+    })
+}
+
+/// Writes a copy of the store at `path` to `dest`, leaving out each log's records from the first
+/// damaged one on, and logs that are missing. The store itself is left untouched.
+///
+/// Hint files aren't copied, they are written again as the copy is compacted.
+///
+/// Returns the report of the store the copy was made from.
+///
+/// # Errors
+///
+/// `StoreLocked` if the store is open.
+pub fn repair(path: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<StoreReport> {
+    let (path, dest) = (path.as_ref(), dest.as_ref());
+    let _lock = lock_if_exists(path)?;
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        Err(GeneralError(format!("{} is not empty", dest.display())))?;
+    }
+    let report = inspect_locked(path)?;
+
+    let mut ids = Vec::new();
+    for log in report.logs.iter().filter(|log| !log.missing) {
+        let mut copy = File::create(LogIndex::get_log_file(dest, log.log_id))?;
+        io::copy(
+            &mut File::open(LogIndex::get_log_file(path, log.log_id))?.take(log.readable_size()),
+            &mut copy,
+        )?;
+        copy.sync_all()?;
+        if log.damage.is_some() {
+            info!(
+                "Dropped {} bytes from log {}",
+                log.size - log.readable_size(),
+                log.log_id
+            );
+        }
+        ids.push(log.log_id);
+    }
+    if ids.is_empty() {
+        // Every store has an active log
+        File::create(LogIndex::get_log_file(dest, 0))?.sync_all()?;
+        ids.push(0);
+    }
+    LogIndex::save_manifest(&LogMetadata {
+        active_log_id: ids.last().copied().unwrap_or_default(),
+        size: 0,
+        path: dest.to_owned(),
+        ids,
+        state: LogIndexState::default(),
+    })?;
+    finish_backup::<KvStore>(dest)?;
+    Ok(report)
+}
+
+fn inspect_log(path: &Path, log_id: LogId, active: bool) -> Result<LogReport> {
+    if !LogIndex::get_log_file(path, log_id).exists() {
+        return Ok(LogReport {
+            log_id,
+            active,
+            missing: true,
+            size: 0,
+            records: Vec::new(),
+            damage: None,
+        });
+    }
+    let mut entries = LogEntries::open(path, log_id)?;
+    let mut records = Vec::new();
+    let mut damage = None;
+    while entries.offset < entries.size {
+        let offset = entries.offset;
+        match entries.next_entry() {
+            Ok((command, pointer)) => records.push(RecordReport {
+                offset,
+                length: pointer.length,
+                command,
+                live: false,
+            }),
+            Err(err) => {
+                // Same as when the store is opened
+                let torn_tail = active
+                    && LogIndex::is_torn_record(
+                        entries.reader.get_ref(),
+                        offset,
+                        entries.size,
+                        &err,
+                    )?;
+                damage = Some(LogDamage {
+                    offset,
+                    error: err.to_string(),
+                    torn_tail,
+                });
+                break;
+            }
+        }
+    }
+    Ok(LogReport {
+        log_id,
+        active,
+        missing: false,
+        size: entries.size,
+        records,
+        damage,
+    })
+}
+
+fn index_record(
+    index: &mut HashMap<Key, (LogId, LogOffset, Option<u64>)>,
+    log_id: LogId,
+    record: &RecordReport,
+) {
+    match &record.command {
+        Command::Set(cmd) => {
+            index.insert(cmd.key.clone(), (log_id, record.offset, cmd.expires_at()));
+        }
+        Command::Rm(cmd) => {
+            index.remove(&cmd.key);
+        }
+        Command::Batch(batch) => {
+            for operation in batch.operations() {
+                match operation {
+                    BatchOperation::Set(cmd) => {
+                        index.insert(cmd.key.clone(), (log_id, record.offset, cmd.expires_at()));
+                    }
+                    BatchOperation::Rm(cmd) => {
+                        index.remove(&cmd.key);
+                    }
+                }
+            }
+        }
+        // Never written to a log
+        Command::Get(_)
+        | Command::Scan(_)
+        | Command::Ttl(_)
+        | Command::Cas(_)
+        | Command::SetIfAbsent(_)
+        | Command::RmIfEquals(_)
+        | Command::Transaction(_)
+        | Command::Admin(_) => {}
+    }
+}
@@ -0,0 +1,133 @@
+use super::{LogId, LogIndex, LogMetadata};
+use crate::{
+    serde::bincode::Serde,
+    shared::{new_reader, save_atomically},
+    KvsError::{MissingLog, UnsupportedFormatVersion},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, path::Path};
+use tracing::{info, warn};
+
+/// Name of the file in the log directory that records which logs make up the store
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Version of the on-disk format written by this build
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Files that may live in the log directory alongside the logs and their hint files
+const KNOWN_FILES: [&str; 4] = [MANIFEST_FILE, "compaction", "engine", "LOCK"];
+
+/// The set of logs that make up the store.
+///
+/// Replaced atomically whenever a log is added or removed, so opening the store never has to
+/// guess which files in the directory are logs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    version: u32,
+    active_log_id: LogId,
+    log_ids: Vec<LogId>,
+}
+
+impl Serde for Manifest {}
+
+impl Manifest {
+    fn load(path: &Path) -> Result<Option<Self>> {
+        let file = path.join(MANIFEST_FILE);
+        if !file.exists() {
+            return Ok(None);
+        }
+        let manifest = Self::deserialize_from_reader(new_reader(&file)?)?;
+        if manifest.version != FORMAT_VERSION {
+            Err(UnsupportedFormatVersion(manifest.version))?;
+        }
+        Ok(Some(manifest))
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        save_atomically(self, &path.join(MANIFEST_FILE))
+    }
+}
+
+impl LogIndex {
+    /// Returns the ids of the logs listed in the manifest, oldest first.
+    ///
+    /// Stores written before the manifest existed have their logs discovered from the directory
+    /// once, and upgraded if they predate framed records, after which the manifest is created.
+    pub(super) fn load_log_ids(path: &Path) -> Result<Vec<LogId>> {
+        let ids = if let Some(manifest) = Manifest::load(path)? {
+            let mut ids = manifest.log_ids;
+            ids.sort_unstable();
+            for log_id in &ids {
+                if !Self::get_log_file(path, *log_id).exists() {
+                    Err(MissingLog(*log_id))?;
+                }
+            }
+            ids
+        } else {
+            let ids = Self::get_file_log_ids(path)?;
+            Self::upgrade_unframed_logs(path, &ids)?;
+            info!("Creating manifest for logs {:?}", ids);
+            let active_log_id = ids.last().copied().unwrap_or_default();
+            Manifest {
+                version: FORMAT_VERSION,
+                active_log_id,
+                log_ids: ids.clone(),
+            }
+            .save(path)?;
+            ids
+        };
+        Self::report_unknown_files(path, &ids)?;
+        Ok(ids)
+    }
+
+    /// Returns the ids of the logs listed in the manifest, oldest first, or `None` if there is no
+    /// manifest. Unlike `load_log_ids`, the directory is left untouched.
+    pub(super) fn read_manifest(path: &Path) -> Result<Option<Vec<LogId>>> {
+        Ok(Manifest::load(path)?.map(|manifest| {
+            let mut ids = manifest.log_ids;
+            ids.sort_unstable();
+            ids
+        }))
+    }
+
+    /// Records the current set of logs, replacing the previous manifest.
+    pub(super) fn save_manifest(metadata: &LogMetadata) -> Result<()> {
+        Manifest {
+            version: FORMAT_VERSION,
+            active_log_id: metadata.active_log_id,
+            log_ids: metadata.ids.clone(),
+        }
+        .save(&metadata.path)
+    }
+
+    /// Drops logs from the manifest, if there is one, before the store has been opened.
+    pub(super) fn remove_from_manifest(path: &Path, log_ids: &[LogId]) -> Result<()> {
+        if let Some(mut manifest) = Manifest::load(path)? {
+            manifest.log_ids.retain(|log_id| !log_ids.contains(log_id));
+            manifest.save(path)?;
+        }
+        Ok(())
+    }
+
+    /// Warns about files in the log directory that don't belong to the store. They are ignored.
+    fn report_unknown_files(path: &Path, ids: &[LogId]) -> Result<()> {
+        let known_files = ids
+            .iter()
+            .flat_map(|log_id| {
+                [
+                    Self::get_log_file(path, *log_id),
+                    Self::get_hint_file(path, *log_id),
+                ]
+            })
+            .chain(KNOWN_FILES.iter().map(|file| path.join(file)))
+            .collect::<HashSet<_>>();
+        for entry in fs::read_dir(path)? {
+            let file = entry?.path();
+            if !known_files.contains(&file) {
+                warn!("Ignoring unknown file {}", file.display());
+            }
+        }
+        Ok(())
+    }
+}
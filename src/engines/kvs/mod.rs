@@ -0,0 +1,937 @@
+mod backup;
+pub mod compaction;
+pub mod durability;
+mod hint;
+pub mod inspect;
+mod manifest;
+mod snapshot;
+mod stats;
+mod transaction;
+mod upgrade;
+
+use self::{
+    compaction::{CompactionHandle, CompactionPolicy, Compactor, LogUsage, StaleRatioPolicy},
+    durability::{FileLayer, Flusher, LogSync, OsFileLayer, SyncPolicy},
+    snapshot::LogPins,
+};
+pub use self::{snapshot::KvStoreSnapshot, transaction::KvStoreTransaction};
+use crate::{
+    engines::{
+        backup::{create_backup_dir, finish_backup, recover_swap},
+        DirectoryLock,
+    },
+    serde::bincode::{Serde, FRAME_HEADER_SIZE},
+    shared::{
+        new_reader, new_writer, BatchOperation, Command, Expiry, Key, Remove, Set, Value,
+        WriteBatch, LOG_ROTATION_MIN_SIZE_BYTES, LOG_ROTATION_MIN_SIZE_BYTES_DEFAULT,
+    },
+    EngineStats, KvsEngine,
+    KvsError::{
+        self, ChecksumMismatch, ConditionFailed, CorruptLog, KeyNotFound, LogIndexIDError,
+        LogRemoved, TruncatedRecord,
+    },
+    Result,
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use derive_more::{Constructor, From};
+use im::OrdMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+use tracing::{error, warn};
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct LogPointer {
+    id: LogId,
+    offset: LogOffset,
+    length: LogSize,
+    /// Unix time in milliseconds the entry expires at, kept here so expired keys can be skipped
+    /// without reading them
+    expires_at: Option<u64>,
+    /// Bumped every time the key is written, so transactions can tell whether it changed. Only
+    /// kept in memory, as transactions don't outlive the store.
+    version: u64,
+}
+
+impl LogPointer {
+    fn new(id: LogId, offset: LogOffset, length: LogSize) -> Self {
+        Self {
+            id,
+            offset,
+            length,
+            expires_at: None,
+            version: 0,
+        }
+    }
+
+    /// Checks whether both point at the same entry in a log, whatever their version.
+    fn is_at(&self, other: &LogPointer) -> bool {
+        self.id == other.id && self.offset == other.offset
+    }
+
+    /// Points at the given command, taking the expiry of a `set` from it.
+    fn to_command(command: &Command, id: LogId, offset: LogOffset, length: LogSize) -> Self {
+        let expires_at = match command {
+            Command::Set(cmd) => cmd.expires_at(),
+            _ => None,
+        };
+        Self {
+            expires_at,
+            ..Self::new(id, offset, length)
+        }
+    }
+
+    /// Points at a `set` inside the batch record this points at.
+    fn to_batched(&self, cmd: &Set) -> Self {
+        Self {
+            expires_at: cmd.expires_at(),
+            ..self.clone()
+        }
+    }
+
+    fn expiry(&self) -> Option<Expiry> {
+        self.expires_at.map(Expiry::At)
+    }
+
+    fn has_expired(&self) -> bool {
+        self.expiry().is_some_and(Expiry::has_passed)
+    }
+
+    /// Checks whether the entry had expired by the given Unix time in milliseconds.
+    fn has_expired_at(&self, unix_millis: u64) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= unix_millis)
+    }
+}
+
+/// Bytes read at a time while looking for records after a damaged one
+const SCAN_WINDOW_SIZE: usize = 64 * 1024;
+
+/// Keys in order, so they can be scanned. Cloning it is cheap, as the clone shares its structure.
+type LogPointerIndex = OrdMap<Key, LogPointer>;
+type LogId = u64;
+type LogOffset = u64;
+type LogSize = u64;
+
+#[derive(Clone, Debug, Default, From, Eq, PartialEq, Deserialize, Serialize)]
+pub enum LogIndexState {
+    Compacting,
+    #[default]
+    Ready,
+}
+
+#[derive(Clone)]
+pub struct LogMetadata {
+    active_log_id: LogId,
+    size: LogSize,
+    path: PathBuf,
+    ids: Vec<LogId>,
+    state: LogIndexState,
+}
+
+#[derive(Clone)]
+pub struct LogIndex {
+    database: Arc<RwLock<LogPointerIndex>>,
+    /// Read-only handle to each log. Entries are read with positional reads, so concurrent
+    /// readers never wait on each other.
+    reader: Arc<DashMap<LogId, Arc<File>>>,
+    writer: Arc<RwLock<BufWriter<File>>>,
+    metadata: Arc<RwLock<LogMetadata>>,
+    /// Live and stale bytes in each log, used to decide which logs are worth compacting
+    usage: Arc<DashMap<LogId, LogUsage>>,
+    /// Number of keys that point at each batch record, so its bytes only count once towards
+    /// `usage`
+    batch_refs: Arc<DashMap<(LogId, LogOffset), usize>>,
+    compaction: CompactionHandle,
+    /// Flushes written logs to durable storage according to the `SyncPolicy`
+    sync: Arc<LogSync>,
+    /// Last version given to a written key
+    version: Arc<AtomicU64>,
+    /// Logs pinned by live snapshots
+    pins: Arc<Mutex<LogPins>>,
+    /// Held while logs are compacted, so backups never see a log that is being rewritten
+    compacting: Arc<Mutex<()>>,
+}
+
+impl LogIndex {
+    fn new(path: PathBuf, options: &KvStoreOptions) -> Result<LogIndex> {
+        Self::recover_interrupted_compaction(&path)?;
+        let ids = Self::load_log_ids(&path)?;
+        let mut id = 0;
+        let reader = Arc::new(DashMap::new());
+        for log_id in &ids {
+            let buf_reader = Self::log_reader(&path, *log_id)?;
+            reader.insert(*log_id, buf_reader);
+            id = *log_id;
+        }
+        let writer = Self::log_writer(&path, id)?;
+        let sync = LogSync::new(
+            options.sync_policy,
+            options.file_layer.clone(),
+            Self::get_log_file(&path, id),
+            writer.get_ref().try_clone()?,
+        );
+        let writer = Arc::new(RwLock::new(writer));
+        let size = 0;
+
+        Ok(LogIndex {
+            database: Arc::default(),
+            reader,
+            writer,
+            metadata: Arc::new(RwLock::new(LogMetadata {
+                active_log_id: id,
+                size,
+                path,
+                ids,
+                state: LogIndexState::default(),
+            })),
+            usage: Arc::default(),
+            batch_refs: Arc::default(),
+            compaction: CompactionHandle::default(),
+            sync: Arc::new(sync),
+            version: Arc::default(),
+            pins: Arc::default(),
+            compacting: Arc::default(),
+        })
+    }
+
+    fn log_reader(path: &Path, id: LogId) -> Result<Arc<File>> {
+        let file = Self::get_log_file(path, id);
+        if !file.exists() {
+            File::create(&file)?;
+        }
+        Ok(Arc::new(new_reader(&file)?.into_inner()))
+    }
+
+    fn log_writer(path: &Path, id: LogId) -> Result<BufWriter<File>> {
+        let file = Self::get_log_file(path, id);
+        new_writer(file)
+    }
+
+    fn get_log_file(path: &Path, log_id: LogId) -> PathBuf {
+        path.join(format!("{log_id}"))
+    }
+
+    fn replay_log(self) -> Result<Self> {
+        let mut sorted_log_ids = self.reader.iter().map(|f| *f.key()).collect::<Vec<_>>();
+        sorted_log_ids.sort_unstable();
+        let active_log_id = sorted_log_ids.last().copied().unwrap_or_default();
+        let path = self.metadata.read()?.path.clone();
+        for log_id in sorted_log_ids {
+            if log_id != active_log_id {
+                if let Some(hint) = Self::load_hint(&path, log_id) {
+                    self.replay_hint(hint)?;
+                    continue;
+                }
+            }
+            let mut reader = new_reader(&Self::get_log_file(&path, log_id))?;
+            let mut offset = 0;
+            let file_size = reader.get_ref().metadata()?.size();
+            while offset < file_size {
+                let command = match Command::deserialize_from_reader(&mut reader) {
+                    Ok(command) => command,
+                    Err(err) => {
+                        // Only the tail of the active log can be half-written by a crash.
+                        // Anything else means the log has been damaged.
+                        if log_id != active_log_id
+                            || !Self::is_torn_record(reader.get_ref(), offset, file_size, &err)?
+                        {
+                            error!("Log {} is corrupt at offset {}: {}", log_id, offset, err);
+                            return Err(CorruptLog(log_id, offset));
+                        }
+                        warn!(
+                            "Truncating torn record in log {} at offset {}",
+                            log_id, offset
+                        );
+                        Self::truncate_log(&path, log_id, offset)?;
+                        break;
+                    }
+                };
+                let next_offset = reader.stream_position()?;
+                let log_pointer =
+                    LogPointer::to_command(&command, log_id, offset, next_offset - offset);
+                self.update_log_index(command, log_pointer)?;
+                offset = next_offset;
+            }
+        }
+        // The writer appends to the active log, whichever log the last record replayed was in
+        let size = self.writer.write()?.seek(SeekFrom::End(0))?;
+        let mut metadata = self.metadata.write()?;
+        metadata.active_log_id = active_log_id;
+        metadata.size = size;
+        drop(metadata);
+        Ok(self)
+    }
+
+    /// Checks whether the record that couldn't be read at `offset` was cut short by a crash
+    /// while it was being appended, rather than damaged in place.
+    ///
+    /// A torn record runs to the end of the log, and nothing after its header reads as a record.
+    /// A damaged length in the middle of a log can run past the end too, but the records after
+    /// it are still there.
+    fn is_torn_record(
+        file: &File,
+        offset: LogOffset,
+        size: LogSize,
+        err: &KvsError,
+    ) -> Result<bool> {
+        let header_end = offset + FRAME_HEADER_SIZE as u64;
+        if header_end > size {
+            return Ok(matches!(err, TruncatedRecord));
+        }
+        let mut length = [0; 4];
+        file.read_exact_at(&mut length, offset)?;
+        let record_end = header_end + u64::from(u32::from_le_bytes(length));
+        let runs_to_end = match err {
+            TruncatedRecord => record_end > size,
+            ChecksumMismatch => record_end == size,
+            _ => false,
+        };
+        Ok(runs_to_end && !Self::contains_record(file, header_end, size)?)
+    }
+
+    /// Checks whether any whole record starts between `start` and `end` in the log.
+    ///
+    /// The log is read a window at a time, rather than with a read for every position. The window
+    /// grows to hold any record that doesn't fit in it, so every byte is only read once, and a
+    /// record is only decoded once its checksum matches.
+    fn contains_record(file: &File, start: LogOffset, end: LogOffset) -> Result<bool> {
+        let header_size = FRAME_HEADER_SIZE as u64;
+        let mut window = Vec::new();
+        let mut window_start = start;
+        for position in start..end.saturating_sub(header_size - 1) {
+            if position + header_size > window_start + window.len() as u64 {
+                window.clear();
+                window_start = position;
+                let window_end = (position + SCAN_WINDOW_SIZE as u64).min(end);
+                Self::extend_window(file, &mut window, window_start, window_end)?;
+            }
+            let at = usize::try_from(position - window_start).expect("inside the window");
+            let header = &window[at..at + FRAME_HEADER_SIZE];
+            let length = u32::from_le_bytes(header[..4].try_into().expect("4 bytes"));
+            let checksum = u32::from_le_bytes(header[4..].try_into().expect("4 bytes"));
+            let record_end = position + header_size + u64::from(length);
+            if record_end > end {
+                continue;
+            }
+            let window_end = window_start + window.len() as u64;
+            if record_end > window_end {
+                let window_end = record_end
+                    .max(window_end + SCAN_WINDOW_SIZE as u64)
+                    .min(end);
+                Self::extend_window(file, &mut window, window_start, window_end)?;
+            }
+            let record = &window[at..at + FRAME_HEADER_SIZE + length as usize];
+            if crc32fast::hash(&record[FRAME_HEADER_SIZE..]) == checksum
+                && Command::deserialize_from_reader(record).is_ok()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Reads the part of the log between the end of the window, which starts at `window_start`,
+    /// and `window_end` onto it.
+    fn extend_window(
+        file: &File,
+        window: &mut Vec<u8>,
+        window_start: LogOffset,
+        window_end: LogOffset,
+    ) -> Result<()> {
+        let kept = window.len();
+        let length = usize::try_from(window_end - window_start).expect("the log fits in memory");
+        window.resize(length, 0);
+        file.read_exact_at(&mut window[kept..], window_start + kept as u64)?;
+        Ok(())
+    }
+
+    fn truncate_log(path: &Path, id: LogId, size: LogSize) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(Self::get_log_file(path, id))?;
+        file.set_len(size)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn update_log_index(&self, command: Command, log_pointer: LogPointer) -> Result<()> {
+        match command {
+            Command::Set(cmd) => self.index_value(cmd.key, log_pointer),
+            Command::Rm(cmd) => self.index_removal(&cmd.key, &log_pointer),
+            Command::Batch(batch) => self.index_batch(batch, &log_pointer),
+            // Only `set`, `rm` and batches of them are written to the log, conditional writes are
+            // logged as those
+            Command::Get(_)
+            | Command::Scan(_)
+            | Command::Ttl(_)
+            | Command::Cas(_)
+            | Command::SetIfAbsent(_)
+            | Command::RmIfEquals(_)
+            | Command::Transaction(_)
+            | Command::Admin(_) => Ok(()),
+        }
+    }
+
+    fn next_version(&self) -> u64 {
+        self.version.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn index_value(&self, key: Key, mut log_pointer: LogPointer) -> Result<()> {
+        log_pointer.version = self.next_version();
+        self.add_live_bytes(log_pointer.id, log_pointer.length);
+        let previous = self.database.write()?.insert(key, log_pointer);
+        if let Some(previous) = previous {
+            self.mark_stale(&previous);
+        }
+        Ok(())
+    }
+
+    fn index_removal(&self, key: &[u8], log_pointer: &LogPointer) -> Result<()> {
+        // A removal never holds a live value, so it is stale as soon as it is written
+        self.add_stale_bytes(log_pointer.id, log_pointer.length);
+        let previous = self.database.write()?.remove(key);
+        if let Some(previous) = previous {
+            self.mark_stale(&previous);
+        }
+        Ok(())
+    }
+
+    /// Applies every write in a batch to the index at once, so readers never see part of it.
+    ///
+    /// Every key in the batch points at the whole batch record.
+    fn index_batch(&self, batch: WriteBatch, log_pointer: &LogPointer) -> Result<()> {
+        let writes = batch
+            .into_operations()
+            .into_iter()
+            .map(|operation| match operation {
+                BatchOperation::Set(cmd) => {
+                    let pointer = log_pointer.to_batched(&cmd);
+                    (cmd.key, Some(pointer))
+                }
+                BatchOperation::Rm(cmd) => (cmd.key, None),
+            });
+        self.index_batch_writes(log_pointer, writes)
+    }
+
+    /// Points each key at the batch record, or removes it if it has no pointer.
+    ///
+    /// The record counts as live once for as long as any key points at it, and as stale once no
+    /// key does, however many writes it holds.
+    fn index_batch_writes(
+        &self,
+        log_pointer: &LogPointer,
+        writes: impl IntoIterator<Item = (Key, Option<LogPointer>)>,
+    ) -> Result<()> {
+        let record = (log_pointer.id, log_pointer.offset);
+        let mut database = self.database.write()?;
+        let mut previous = Vec::new();
+        let mut has_values = false;
+        for (key, pointer) in writes {
+            if let Some(mut pointer) = pointer {
+                pointer.version = self.next_version();
+                *self.batch_refs.entry(record).or_default() += 1;
+                has_values = true;
+                previous.extend(database.insert(key, pointer));
+            } else {
+                previous.extend(database.remove(&key));
+            }
+        }
+        if has_values {
+            self.add_live_bytes(log_pointer.id, log_pointer.length);
+        } else {
+            self.add_stale_bytes(log_pointer.id, log_pointer.length);
+        }
+        drop(database);
+        for pointer in &previous {
+            self.mark_stale(pointer);
+        }
+        Ok(())
+    }
+
+    fn add_live_bytes(&self, log_id: LogId, length: LogSize) {
+        self.usage
+            .entry(log_id)
+            .or_insert_with(|| LogUsage::new(log_id))
+            .live_bytes += length;
+    }
+
+    fn add_stale_bytes(&self, log_id: LogId, length: LogSize) {
+        self.usage
+            .entry(log_id)
+            .or_insert_with(|| LogUsage::new(log_id))
+            .stale_bytes += length;
+    }
+
+    /// Moves the bytes of an entry that has been overwritten or removed from live to stale. A batch
+    /// record is only moved once the last key pointing at it is.
+    fn mark_stale(&self, log_pointer: &LogPointer) {
+        if let Entry::Occupied(mut refs) =
+            self.batch_refs.entry((log_pointer.id, log_pointer.offset))
+        {
+            *refs.get_mut() -= 1;
+            if *refs.get() > 0 {
+                return;
+            }
+            refs.remove();
+        }
+        if let Some(mut usage) = self.usage.get_mut(&log_pointer.id) {
+            usage.live_bytes = usage.live_bytes.saturating_sub(log_pointer.length);
+            usage.stale_bytes += log_pointer.length;
+        }
+    }
+
+    /// Appends the command to the active log and returns the position it has to be synced up to.
+    fn log_command(&self, command: Command) -> Result<u64> {
+        self.try_log_rotate()?;
+        let mut writer = self.writer.write()?;
+        let log_offset = command.serialize_into_writer(&mut *writer)?;
+        let size = writer.stream_position()?;
+        let sync_position = self.sync.appended(size - log_offset)?;
+        self.metadata.write()?.size = size;
+        drop(writer);
+        let log_pointer = LogPointer::to_command(
+            &command,
+            self.metadata.read()?.active_log_id,
+            log_offset,
+            size - log_offset,
+        );
+        self.update_log_index(command, log_pointer)?;
+        Ok(sync_position)
+    }
+
+    fn try_log_rotate(&self) -> Result<bool> {
+        let log_rotation_min_size =
+            LOG_ROTATION_MIN_SIZE_BYTES.get_or_init(|| LOG_ROTATION_MIN_SIZE_BYTES_DEFAULT);
+        let mut metadata = self.metadata.write()?;
+        metadata.size = self.writer.write()?.stream_position()?;
+        if metadata.size <= *log_rotation_min_size {
+            return Ok(false);
+        }
+        let log_id = metadata.active_log_id + 1;
+        self.rotate_log(&mut metadata, log_id)?;
+        drop(metadata);
+        self.compaction.trigger()?;
+        Ok(true)
+    }
+
+    /// Seals the active log and starts writing to the given log id.
+    fn rotate_log(&self, metadata: &mut LogMetadata, log_id: LogId) -> Result<()> {
+        metadata.size = 0;
+        metadata.active_log_id = log_id;
+        metadata.ids.push(log_id);
+        let writer = Self::log_writer(&metadata.path, log_id)?;
+        self.sync.set_active_log(
+            Self::get_log_file(&metadata.path, log_id),
+            writer.get_ref().try_clone()?,
+        )?;
+        *self.writer.write()? = writer;
+        self.reader
+            .insert(log_id, Self::log_reader(&metadata.path, log_id)?);
+        Self::save_manifest(metadata)
+    }
+
+    /// Returns the pointer to the value of a key, unless it has expired.
+    fn get_pointer(&self, key: &[u8]) -> Result<Option<LogPointer>> {
+        let database = self.database.read()?;
+        Ok(database.get(key).filter(|p| !p.has_expired()).cloned())
+    }
+
+    fn get_value(&self, key: &[u8]) -> Result<Option<Value>> {
+        match self.get_pointer(key)? {
+            Some(pointer) => self.read_value(key, &pointer),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the value of a key from where the index pointed when it was looked up.
+    ///
+    /// Compaction may have moved the entry to a new log and removed the old one since, in which
+    /// case the key is looked up again and read from where it is now.
+    fn read_value(&self, key: &[u8], pointer: &LogPointer) -> Result<Option<Value>> {
+        if let Some(command) = self.get_command(pointer)? {
+            return Ok(command.value_of(key).cloned());
+        }
+        match self.get_pointer(key)? {
+            Some(moved) if !moved.is_at(pointer) => match self.get_command(&moved)? {
+                Some(command) => Ok(command.value_of(key).cloned()),
+                None => Err(LogRemoved(moved.id)),
+            },
+            Some(_) => Err(LogRemoved(pointer.id)),
+            // Removed or expired in the meantime
+            None => Ok(None),
+        }
+    }
+
+    /// Returns up to `limit` keys in `range` along with their values, in key order.
+    fn scan_values(&self, range: impl RangeBounds<Key>, limit: usize) -> Result<Vec<(Key, Value)>> {
+        let pointers = self
+            .database
+            .read()?
+            .range(range)
+            .filter(|(_, pointer)| !pointer.has_expired())
+            .take(limit)
+            .map(|(key, pointer)| (key.clone(), pointer.clone()))
+            .collect::<Vec<_>>();
+        let mut entries = Vec::with_capacity(pointers.len());
+        for (key, pointer) in pointers {
+            if let Some(value) = self.read_value(&key, &pointer)? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Reads the entry a pointer points at, or returns `None` if its log has been removed.
+    fn get_command(&self, pointer: &LogPointer) -> Result<Option<Command>> {
+        // Clone the handle so the map isn't locked while reading
+        let Some(file) = self.reader.get(&pointer.id).map(|file| file.clone()) else {
+            return Ok(None);
+        };
+        let length =
+            usize::try_from(pointer.length).map_err(|_| CorruptLog(pointer.id, pointer.offset))?;
+        let mut buffer = vec![0; length];
+        file.read_exact_at(&mut buffer, pointer.offset)?;
+        Ok(Some(Command::deserialize_from_reader(buffer.as_slice())?))
+    }
+
+    /// Finds the logs in a directory written before the manifest existed.
+    fn get_file_log_ids(path: &Path) -> Result<Vec<LogId>> {
+        let path = path.join("[0-9]*");
+        let path = path.to_str().ok_or_else(|| LogIndexIDError)?;
+        let mut log_ids = glob::glob(path)?
+            .filter_map(|path| {
+                let filename = path.ok()?.file_name()?.to_str()?.to_owned();
+                // Other files, such as hint files, share the log id prefix
+                str::parse::<u64>(&filename).ok()
+            })
+            .collect::<Vec<_>>();
+        if log_ids.is_empty() {
+            log_ids = vec![0];
+        } else {
+            log_ids.sort_unstable();
+        }
+        Ok(log_ids)
+    }
+}
+
+/// Reads the entries of a log from start to end, along with the pointer to each of them.
+pub struct LogEntries {
+    reader: BufReader<File>,
+    log_id: LogId,
+    offset: LogOffset,
+    size: LogSize,
+}
+
+impl LogEntries {
+    fn open(path: &Path, log_id: LogId) -> Result<Self> {
+        let reader = new_reader(&LogIndex::get_log_file(path, log_id))?;
+        let size = reader.get_ref().metadata()?.size();
+        Ok(Self {
+            reader,
+            log_id,
+            offset: 0,
+            size,
+        })
+    }
+
+    fn next_entry(&mut self) -> Result<(Command, LogPointer)> {
+        let command = Command::deserialize_from_reader(&mut self.reader)?;
+        let next_offset = self.reader.stream_position()?;
+        let log_pointer = LogPointer::to_command(
+            &command,
+            self.log_id,
+            self.offset,
+            next_offset - self.offset,
+        );
+        self.offset = next_offset;
+        Ok((command, log_pointer))
+    }
+}
+
+impl Iterator for LogEntries {
+    type Item = Result<(Command, LogPointer)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.size {
+            return None;
+        }
+        let entry = self.next_entry();
+        if entry.is_err() {
+            // Don't keep reading past a damaged entry
+            self.offset = self.size;
+        }
+        Some(entry)
+    }
+}
+
+/// Options used to open a `KvStore`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    compaction_policy: Arc<dyn CompactionPolicy>,
+    sync_policy: SyncPolicy,
+    file_layer: Arc<dyn FileLayer>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            compaction_policy: Arc::new(StaleRatioPolicy::default()),
+            sync_policy: SyncPolicy::default(),
+            file_layer: Arc::new(OsFileLayer),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Sets the policy used to pick the logs that are compacted.
+    #[must_use]
+    pub fn compaction_policy(mut self, policy: impl CompactionPolicy + 'static) -> Self {
+        self.compaction_policy = Arc::new(policy);
+        self
+    }
+
+    /// Sets when writes are synced to durable storage.
+    #[must_use]
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Sets the layer used to sync the logs, which tests use to inject faults.
+    #[must_use]
+    pub fn file_layer(mut self, layer: impl FileLayer + 'static) -> Self {
+        self.file_layer = Arc::new(layer);
+        self
+    }
+}
+
+/// Contains the in-memory index and
+#[derive(Constructor, Clone)]
+pub struct KvStore {
+    index: Arc<LogIndex>,
+    /// Use to selectively lock write operations, without locking the index
+    write_lock: Arc<Mutex<()>>,
+    /// Stops the background compaction thread when the last handle to the store is dropped
+    compactor: Arc<Compactor>,
+    /// Syncs the active log in the background when using `SyncPolicy::Interval`, until dropped
+    #[allow(dead_code)]
+    flusher: Arc<Flusher>,
+    /// Keeps other processes out of the store until the last handle is dropped, after the
+    /// background threads have stopped
+    #[allow(dead_code)]
+    lock: Arc<DirectoryLock>,
+}
+
+impl KvStore {
+    /// Open the `KvStore` at a given path with the given options.
+    ///
+    /// # Errors
+    ///
+    /// If there was a problem opening the `KvStore`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        let path = path.into();
+        recover_swap(&path)?;
+        let lock = DirectoryLock::acquire(&path)?;
+        let index = LogIndex::new(path, &options)?.replay_log()?;
+        let write_lock = Arc::new(Mutex::new(()));
+        let flusher = Flusher::spawn(index.sync.clone())?;
+        let compactor =
+            Compactor::spawn(index.clone(), write_lock.clone(), options.compaction_policy)?;
+        Ok(KvStore::new(
+            Arc::new(index),
+            write_lock,
+            Arc::new(compactor),
+            Arc::new(flusher),
+            Arc::new(lock),
+        ))
+    }
+
+    fn log_write(&self, command: Command) -> Result<()> {
+        let write_lock = self.write_lock.lock()?;
+        let sync_position = self.index.log_command(command)?;
+        drop(write_lock);
+        self.index.sync.commit(sync_position)
+    }
+
+    /// Logs the command if `condition` holds for the current value of the key.
+    ///
+    /// The value is checked while holding the write lock, so no other write can land between
+    /// the check and the command.
+    fn log_if(
+        &self,
+        key: &Key,
+        condition: impl FnOnce(Option<&Value>) -> bool,
+        command: Command,
+    ) -> Result<()> {
+        let write_lock = self.write_lock.lock()?;
+        let current = self.index.get_value(key)?;
+        if !condition(current.as_ref()) {
+            return Err(ConditionFailed(current));
+        }
+        let sync_position = self.index.log_command(command)?;
+        drop(write_lock);
+        self.index.sync.commit(sync_position)
+    }
+
+    /// Returns a read-only view of the store as it is now, which later writes don't change.
+    ///
+    /// # Errors
+    ///
+    /// If the index is not copied successfully.
+    pub fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        KvStoreSnapshot::new(self.clone())
+    }
+
+    /// Returns the handle used to control the background compaction of the store's logs.
+    #[must_use]
+    pub fn compaction(&self) -> &CompactionHandle {
+        self.compactor.handle()
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Transaction = KvStoreTransaction;
+
+    /// Open the `KvStore` at a given path and return the `KvStore`.
+    ///
+    /// # Errors
+    ///
+    /// If there was a problem opening the `KvStore`.
+    fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        self.index.get_value(&key.into())
+    }
+
+    fn remove(&self, key: impl Into<Key>) -> Result<()> {
+        let key = key.into();
+        if self.index.get_pointer(&key)?.is_some() {
+            let command = Command::from(Remove::new(key));
+            let write_lock = self.write_lock.lock()?;
+            let sync_position = self.index.log_command(command)?;
+            drop(write_lock);
+            self.index.sync.commit(sync_position)
+        } else {
+            Err(KeyNotFound)?
+        }
+    }
+
+    fn set(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        self.log_write(Set::new(key.into(), value.into()).into())
+    }
+
+    fn set_with_expiry(
+        &self,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        expiry: Expiry,
+    ) -> Result<()> {
+        // The log holds the absolute time, so replaying it later doesn't extend the lifetime
+        let command = Set::new(key.into(), value.into())
+            .with_expiry(expiry)
+            .resolve_expiry();
+        self.log_write(command.into())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let batch =
+            batch
+                .into_operations()
+                .into_iter()
+                .fold(WriteBatch::new(), |batch, operation| match operation {
+                    BatchOperation::Set(cmd) => batch.push(cmd.resolve_expiry()),
+                    operation @ BatchOperation::Rm(_) => batch.push(operation),
+                });
+        self.log_write(batch.into())
+    }
+
+    fn expire(&self, key: impl Into<Key>, expiry: Expiry) -> Result<()> {
+        let key = key.into();
+        // Held while the value is read, so no other write can land before it is written back
+        let write_lock = self.write_lock.lock()?;
+        let value = self.index.get_value(&key)?.ok_or(KeyNotFound)?;
+        let command = Set::new(key, value).with_expiry(expiry).resolve_expiry();
+        let sync_position = self.index.log_command(command.into())?;
+        drop(write_lock);
+        self.index.sync.commit(sync_position)
+    }
+
+    fn ttl(&self, key: impl Into<Key>) -> Result<Option<Duration>> {
+        let pointer = self.index.get_pointer(&key.into())?.ok_or(KeyNotFound)?;
+        Ok(pointer.expiry().and_then(Expiry::remaining))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Key>,
+        expected: impl Into<Value>,
+        new: impl Into<Value>,
+    ) -> Result<()> {
+        let key = key.into();
+        let expected = expected.into();
+        let command = Command::from(Set::new(key.clone(), new.into()));
+        self.log_if(&key, |current| current == Some(&expected), command)
+    }
+
+    fn set_if_absent(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        let key = key.into();
+        let command = Command::from(Set::new(key.clone(), value.into()));
+        self.log_if(&key, |current| current.is_none(), command)
+    }
+
+    fn remove_if_equals(&self, key: impl Into<Key>, expected: impl Into<Value>) -> Result<()> {
+        let key = key.into();
+        let expected = expected.into();
+        let command = Command::from(Remove::new(key.clone()));
+        self.log_if(&key, |current| current == Some(&expected), command)
+    }
+
+    fn scan(&self, range: impl RangeBounds<Key>, limit: usize) -> Result<Vec<(Key, Value)>> {
+        self.index.scan_values(range, limit)
+    }
+
+    fn keys(&self, start_after: Option<Key>, limit: usize) -> Result<Vec<Key>> {
+        let start = start_after.map_or(Bound::Unbounded, Bound::Excluded);
+        let database = self.index.database.read()?;
+        let keys = database
+            .range((start, Bound::Unbounded))
+            .filter(|(_, pointer)| !pointer.has_expired())
+            .take(limit);
+        Ok(keys.map(|(key, _)| key.clone()).collect())
+    }
+
+    fn begin(&self) -> Result<KvStoreTransaction> {
+        KvStoreTransaction::new(self.clone())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.index.stats()
+    }
+
+    fn export(&self, writer: impl Write) -> Result<u64> {
+        self.snapshot()?.export(writer)
+    }
+
+    fn backup_to(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        create_backup_dir(&path)?;
+        self.index.backup_to(&path, &self.write_lock)?;
+        finish_backup::<Self>(&path)
+    }
+}
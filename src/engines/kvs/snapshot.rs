@@ -0,0 +1,185 @@
+use super::{KvStore, LogId, LogIndex, LogPointer, LogPointerIndex};
+use crate::{
+    engines::dump::DumpWriter,
+    shared::{unix_millis_now, Key, Set, Value},
+    KvsError::LogRemoved,
+    Result,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    ops::RangeBounds,
+};
+use tracing::{debug, error};
+
+/// Logs that live snapshots may read from, which compaction must not delete until every snapshot
+/// pinning them has been dropped.
+#[derive(Debug, Default)]
+pub(super) struct LogPins {
+    /// Number of live snapshots pinning each log
+    counts: HashMap<LogId, usize>,
+    /// Logs compaction has dropped from the store while they were pinned
+    retired: HashSet<LogId>,
+}
+
+impl LogPins {
+    /// Keeps the log around for the snapshots pinning it, if there are any.
+    ///
+    /// Returns whether the log was retired rather than left for the caller to delete.
+    pub(super) fn retire(&mut self, log_id: LogId) -> bool {
+        if self.counts.contains_key(&log_id) {
+            self.retired.insert(log_id);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl LogIndex {
+    /// Copies the index and pins every log of the store, so the logs its pointers point into
+    /// outlive any compaction until they are unpinned.
+    fn pin_logs(&self) -> Result<(LogPointerIndex, Vec<LogId>)> {
+        // Held throughout, so compaction can't remove a log between the copy and the pinning
+        let mut pins = self.pins.lock()?;
+        let database = self.database.read()?.clone();
+        let log_ids = self.metadata.read()?.ids.clone();
+        for log_id in &log_ids {
+            *pins.counts.entry(*log_id).or_default() += 1;
+        }
+        Ok((database, log_ids))
+    }
+
+    /// Releases logs pinned by `pin_logs`, deleting those compaction retired in the meantime
+    /// once nothing pins them anymore.
+    ///
+    /// A retired log is no longer in the manifest, so if the store stops before it is deleted,
+    /// it is left behind and reported as an unknown file the next time the store is opened.
+    fn unpin_logs(&self, log_ids: &[LogId]) -> Result<()> {
+        let mut pins = self.pins.lock()?;
+        let mut released = Vec::new();
+        for log_id in log_ids {
+            if let Some(count) = pins.counts.get_mut(log_id) {
+                *count -= 1;
+                if *count == 0 {
+                    pins.counts.remove(log_id);
+                    if pins.retired.remove(log_id) {
+                        released.push(*log_id);
+                    }
+                }
+            }
+        }
+        if released.is_empty() {
+            return Ok(());
+        }
+        debug!("Removing logs released by snapshots: {:?}", released);
+        let path = self.metadata.read()?.path.clone();
+        for log_id in released {
+            Self::remove_log_files(&path, log_id)?;
+            self.reader.remove(&log_id);
+        }
+        Ok(())
+    }
+}
+
+/// Read-only view of a `KvStore` as it was when `KvStore::snapshot` was called.
+///
+/// Writes made after the snapshot was taken aren't visible through it, and keys are expired as of
+/// when it was taken. Compaction keeps every log the snapshot may read from until it is dropped,
+/// so holding on to one for a long time holds on to disk space as well.
+#[allow(clippy::module_name_repetitions)]
+pub struct KvStoreSnapshot {
+    store: KvStore,
+    database: LogPointerIndex,
+    pinned: Vec<LogId>,
+    /// Unix time in milliseconds the snapshot was taken at
+    taken_at: u64,
+}
+
+impl KvStoreSnapshot {
+    pub(super) fn new(store: KvStore) -> Result<Self> {
+        let (database, pinned) = store.index.pin_logs()?;
+        Ok(Self {
+            store,
+            database,
+            pinned,
+            taken_at: unix_millis_now(),
+        })
+    }
+
+    /// Returns the value of the given key, or `None` if it didn't exist.
+    pub fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        let key = key.into();
+        match self.pointer(&key) {
+            Some(pointer) => self.read(&key, pointer),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterates over every key along with its value, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(Key, Value)>> + '_ {
+        self.range(..)
+    }
+
+    /// Iterates over the keys in the given range along with their values, in key order.
+    ///
+    /// Values are read from the logs as the iterator advances.
+    pub fn range(
+        &self,
+        range: impl RangeBounds<Key>,
+    ) -> impl Iterator<Item = Result<(Key, Value)>> + '_ {
+        self.database
+            .range(range)
+            .filter(|(_, pointer)| !pointer.has_expired_at(self.taken_at))
+            .filter_map(|(key, pointer)| match self.read(key, pointer) {
+                Ok(value) => value.map(|value| Ok((key.clone(), value))),
+                Err(err) => Some(Err(err)),
+            })
+    }
+
+    /// Writes every entry of the snapshot to a dump, along with its expiry.
+    pub(super) fn export(&self, writer: impl Write) -> Result<u64> {
+        let mut dump = DumpWriter::new(writer)?;
+        let entries = self
+            .database
+            .iter()
+            .filter(|(_, pointer)| !pointer.has_expired_at(self.taken_at));
+        for (key, pointer) in entries {
+            if let Some(value) = self.read(key, pointer)? {
+                let mut entry = Set::new(key.clone(), value);
+                entry.expiry = pointer.expiry();
+                dump.write_entry(entry)?;
+            }
+        }
+        dump.finish()
+    }
+
+    /// Returns the pointer to the value of the key, unless it had expired.
+    pub(super) fn pointer(&self, key: &[u8]) -> Option<&LogPointer> {
+        self.database
+            .get(key)
+            .filter(|pointer| !pointer.has_expired_at(self.taken_at))
+    }
+
+    pub(super) fn read(&self, key: &[u8], pointer: &LogPointer) -> Result<Option<Value>> {
+        // The log is pinned, so it can only be missing if something has gone wrong
+        let command = self
+            .store
+            .index
+            .get_command(pointer)?
+            .ok_or(LogRemoved(pointer.id))?;
+        Ok(command.value_of(key).cloned())
+    }
+
+    pub(super) fn store(&self) -> &KvStore {
+        &self.store
+    }
+}
+
+impl Drop for KvStoreSnapshot {
+    fn drop(&mut self) {
+        if let Err(err) = self.store.index.unpin_logs(&self.pinned) {
+            error!("Unable to release logs pinned by snapshot: {}", err);
+        }
+    }
+}
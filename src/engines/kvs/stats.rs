@@ -0,0 +1,62 @@
+use super::{KvStore, LogId, LogIndex, LogOffset, LogSize};
+use crate::{engines::engine_name, EngineStats, LogStats, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+impl LogIndex {
+    /// Works out how many bytes of each log the index still points at, along with the rest of the
+    /// stats of the store.
+    pub(super) fn stats(&self) -> Result<EngineStats> {
+        // Work on a snapshot so writers aren't blocked while the index is walked
+        let database = self.database.read()?.clone();
+        let metadata = self.metadata.read()?.clone();
+        let mut keys = 0;
+        // Every key in a batch points at the same record, which only counts once
+        let mut records = HashSet::<(LogId, LogOffset)>::new();
+        let mut live_bytes = HashMap::<LogId, LogSize>::new();
+        for pointer in database.values().filter(|pointer| !pointer.has_expired()) {
+            keys += 1;
+            if records.insert((pointer.id, pointer.offset)) {
+                *live_bytes.entry(pointer.id).or_default() += pointer.length;
+            }
+        }
+
+        let mut logs = Vec::with_capacity(metadata.ids.len());
+        for log_id in &metadata.ids {
+            // Compaction may have removed the log since the metadata was read
+            let Ok(file) = fs::metadata(Self::get_log_file(&metadata.path, *log_id)) else {
+                continue;
+            };
+            let size = file.len();
+            let live_bytes = live_bytes.get(log_id).copied().unwrap_or_default();
+            logs.push(LogStats {
+                log_id: *log_id,
+                size,
+                live_bytes,
+                stale_bytes: size.saturating_sub(live_bytes),
+            });
+        }
+        // Logs, hint files and everything else in the directory
+        let mut disk_bytes = 0;
+        for entry in fs::read_dir(&metadata.path)? {
+            if let Ok(file) = entry?.metadata() {
+                if file.is_file() {
+                    disk_bytes += file.len();
+                }
+            }
+        }
+        let (compactions, last_compaction) = self.compaction.history()?;
+
+        Ok(EngineStats {
+            engine: engine_name::<KvStore>().to_owned(),
+            keys,
+            live_bytes: Some(logs.iter().map(|log| log.live_bytes).sum()),
+            disk_bytes,
+            logs,
+            compactions: Some(compactions),
+            last_compaction,
+        })
+    }
+}
@@ -0,0 +1,114 @@
+use super::{KvStore, KvStoreSnapshot, LogPointer};
+use crate::{
+    engines::Transaction,
+    shared::{Key, Remove, Set, Value, WriteBatch},
+    KvsError::{KeyNotFound, TransactionConflict},
+    Result,
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// Optimistic transaction over a `KvStore`, started by `KvStore::begin`.
+///
+/// Reads come from a snapshot taken when the transaction started. Every key the transaction uses
+/// is checked against the live index when it commits, by comparing the version of the pointer it
+/// saw with the current one.
+#[allow(clippy::module_name_repetitions)]
+pub struct KvStoreTransaction {
+    snapshot: KvStoreSnapshot,
+    /// Version of each key the transaction has used, or `None` if it didn't exist
+    versions: HashMap<Key, Option<u64>>,
+    /// Values to save when committing, or `None` to remove the key
+    writes: BTreeMap<Key, Option<Value>>,
+}
+
+impl KvStoreTransaction {
+    pub(super) fn new(store: KvStore) -> Result<Self> {
+        Ok(Self {
+            snapshot: KvStoreSnapshot::new(store)?,
+            versions: HashMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the pointer to the value of the key in the snapshot, recording its version the
+    /// first time the key is used.
+    fn pointer(&mut self, key: &Key) -> Option<LogPointer> {
+        let pointer = self.snapshot.pointer(key).cloned();
+        self.versions
+            .entry(key.clone())
+            .or_insert_with(|| pointer.as_ref().map(|pointer| pointer.version));
+        pointer
+    }
+}
+
+impl Transaction for KvStoreTransaction {
+    fn get(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
+        let key = key.into();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        match self.pointer(&key) {
+            Some(pointer) => self.snapshot.read(&key, &pointer),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        let key = key.into();
+        self.pointer(&key);
+        self.writes.insert(key, Some(value.into()));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: impl Into<Key>) -> Result<()> {
+        let key = key.into();
+        let exists = match self.writes.get(&key) {
+            Some(value) => value.is_some(),
+            None => self.pointer(&key).is_some(),
+        };
+        if !exists {
+            return Err(KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        let Self {
+            snapshot,
+            versions,
+            writes,
+        } = self;
+        let store = snapshot.store();
+        if writes.is_empty() {
+            // Every read came from the same snapshot, so there is nothing to check
+            return Ok(());
+        }
+        let write_lock = store.write_lock.lock()?;
+        let database = store.index.database.read()?;
+        let conflict = versions.iter().any(|(key, version)| {
+            let current = database.get(key).filter(|pointer| !pointer.has_expired());
+            current.map(|pointer| pointer.version) != *version
+        });
+        drop(database);
+        if conflict {
+            return Err(TransactionConflict);
+        }
+        let batch = writes
+            .into_iter()
+            // Removing a key that didn't exist before the transaction is a no-op
+            .filter(|(key, value)| {
+                value.is_some() || versions.get(key).is_some_and(Option::is_some)
+            })
+            .fold(WriteBatch::new(), |batch, (key, value)| match value {
+                Some(value) => batch.push(Set::new(key, value)),
+                None => batch.push(Remove::new(key)),
+            });
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let sync_position = store.index.log_command(batch.into())?;
+        drop(write_lock);
+        store.index.sync.commit(sync_position)
+    }
+}
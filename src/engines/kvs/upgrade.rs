@@ -0,0 +1,120 @@
+use super::{LogId, LogIndex};
+use crate::{
+    engines::backup::sync_dir,
+    serde::bincode::Serde,
+    shared::{new_reader, Command, Remove, Set},
+    KvsError::CorruptLog,
+    Result,
+};
+use bincode::{ErrorKind, Options};
+use serde::Deserialize;
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Seek, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+};
+use tracing::{error, info, warn};
+
+/// A record written before records were framed, as a bare bincode `Command` with string keys and
+/// values. Variants are in the order they were declared in.
+#[derive(Deserialize)]
+enum UnframedCommand {
+    Set {
+        key: String,
+        value: String,
+    },
+    // Never written, but it takes up a variant index
+    #[allow(dead_code)]
+    Get {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+}
+
+impl LogIndex {
+    /// Rewrites logs written before records were framed in the current format, so they can be
+    /// replayed. Only stores without a manifest can hold such logs.
+    pub(super) fn upgrade_unframed_logs(path: &Path, ids: &[LogId]) -> Result<()> {
+        for log_id in ids {
+            if Self::is_unframed(path, *log_id)? {
+                Self::upgrade_unframed_log(path, *log_id, ids.last() == Some(log_id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A framed log starts with the length of its first record, which is never shorter than the
+    /// variant index of a `Command`. An unframed one starts with the variant index itself.
+    fn is_unframed(path: &Path, log_id: LogId) -> Result<bool> {
+        let file = Self::get_log_file(path, log_id);
+        if !file.exists() {
+            return Ok(false);
+        }
+        let mut first_word = [0; 4];
+        match File::open(file)?.read_exact_at(&mut first_word, 0) {
+            Ok(()) => Ok(u32::from_le_bytes(first_word) < 4),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Records were written with bincode's defaults, as fixed-size integers. None can be longer
+    /// than what is left of the log, which keeps a damaged length from allocating more than that.
+    ///
+    /// The last log was the one being appended to, so a record there that runs past the end was
+    /// cut short by a crash. It is dropped, as it would be when replaying a framed log.
+    fn upgrade_unframed_log(path: &Path, log_id: LogId, last: bool) -> Result<()> {
+        info!("Upgrading log {} to framed records", log_id);
+        let file = Self::get_log_file(path, log_id);
+        let mut reader = new_reader(&file)?;
+        let size = reader.get_ref().metadata()?.len();
+        let upgraded = path.join(format!("{log_id}.upgrade"));
+        let mut writer = BufWriter::new(File::create(&upgraded)?);
+        let mut offset = 0;
+        while offset < size {
+            let options = bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .with_limit(size - offset);
+            let command: Command = match options.deserialize_from(&mut reader) {
+                Ok(UnframedCommand::Set { key, value }) => {
+                    Set::new(key.into(), value.into()).into()
+                }
+                Ok(UnframedCommand::Rm { key }) => Remove::new(key.into()).into(),
+                Ok(UnframedCommand::Get { .. }) => {
+                    offset = reader.stream_position()?;
+                    continue;
+                }
+                Err(err) if last && Self::runs_past_end(&err) => {
+                    warn!(
+                        "Truncating torn record in log {} at offset {}",
+                        log_id, offset
+                    );
+                    break;
+                }
+                Err(err) => {
+                    error!("Log {} is corrupt at offset {}: {}", log_id, offset, err);
+                    fs::remove_file(&upgraded)?;
+                    return Err(CorruptLog(log_id, offset));
+                }
+            };
+            command.write_frame(&mut writer)?;
+            offset = reader.stream_position()?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&upgraded, &file)?;
+        sync_dir(path)
+    }
+
+    fn runs_past_end(err: &bincode::Error) -> bool {
+        match &**err {
+            ErrorKind::Io(err) => err.kind() == std::io::ErrorKind::UnexpectedEof,
+            ErrorKind::SizeLimit => true,
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{KvsError::StoreLocked, Result};
+use fs2::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    process,
+};
+use tracing::warn;
+
+/// Name of the lock file taken in the directory of an open store
+const LOCK_FILE: &str = "LOCK";
+
+/// An exclusive advisory lock on a store's directory, held until it is dropped.
+///
+/// The lock file holds the PID of the process that owns it, so a process that fails to take the
+/// lock can report who has it.
+#[derive(Debug)]
+pub struct DirectoryLock {
+    file: File,
+}
+
+impl DirectoryLock {
+    /// Locks the store in the directory at `path`.
+    ///
+    /// # Errors
+    ///
+    /// `StoreLocked` if another process, or another handle in this process, already has it open.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join(LOCK_FILE))?;
+        if file.try_lock_exclusive().is_err() {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            // The owner may not have written its PID yet, in which case it is reported as 0
+            Err(StoreLocked(pid.trim().parse().unwrap_or_default()))?;
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        file.sync_data()?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for DirectoryLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock as well, this just makes it explicit
+        if let Err(err) = self.file.unlock() {
+            warn!("Unable to unlock store: {}", err);
+        }
+    }
+}
@@ -1,11 +1,32 @@
+mod backup;
+pub mod dump;
 pub mod kvs;
+mod lock;
 pub mod sled;
+mod stats;
 
-pub use self::{kvs::KvStore, sled::SledKvsEngine};
-use crate::Result;
-use std::path::PathBuf;
+pub(crate) use self::{backup::recover_swap, lock::DirectoryLock};
+pub use self::{
+    kvs::{KvStore, KvStoreOptions, KvStoreSnapshot, KvStoreTransaction},
+    sled::{SledKvsEngine, SledTransaction},
+    stats::{EngineStats, LogStats},
+};
+use crate::{
+    shared::{prefix_range, Expiry, Key, Value, WriteBatch},
+    Result,
+};
+use std::{
+    any::type_name,
+    io::{BufRead, Write},
+    ops::RangeBounds,
+    path::PathBuf,
+    time::Duration,
+};
 
 pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Transaction started by `begin`.
+    type Transaction: Transaction + Send + 'static;
+
     /// Open the `KvsEngine` at a given path and return it.
     ///
     /// # Errors
@@ -13,7 +34,7 @@ pub trait KvsEngine: Clone + Send + Sync + 'static {
     /// If there was a problem opening the `KvsEngine`.
     fn open(path: impl Into<PathBuf>) -> Result<Self>;
 
-    /// Returns the string value of the given string key.
+    /// Returns the value of the given key.
     ///
     /// If the key does not exist, return `None`.
     ///
@@ -24,9 +45,9 @@ pub trait KvsEngine: Clone + Send + Sync + 'static {
     /// # Errors
     ///
     /// If the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: impl Into<Key>) -> Result<Option<Value>>;
 
-    /// Removes the given string key.
+    /// Removes the given key.
     ///
     /// `kvs` first writes the `rm` command to the sequential log on-disk and then removes the key
     /// from the in-memory index.
@@ -35,9 +56,9 @@ pub trait KvsEngine: Clone + Send + Sync + 'static {
     ///
     /// - If the key does not exist.
     /// - If the key is not removed successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: impl Into<Key>) -> Result<()>;
 
-    /// Saves the given string value to the given string key.
+    /// Saves the given value to the given key.
     ///
     /// `kvs` first writes the `set` command to disk in a sequential log, then stores the log
     /// pointer (file offset) of that command to the in-memory index (i.e. the value stored
@@ -46,5 +67,220 @@ pub trait KvsEngine: Clone + Send + Sync + 'static {
     /// # Errors
     ///
     /// If the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()>;
+
+    /// Saves the given value to the given key until `expiry`, after which the key behaves as if
+    /// it had been removed.
+    ///
+    /// `kvs` writes the absolute expiry into the log along with the value, and drops the entry
+    /// when the log it is in gets compacted.
+    ///
+    /// # Errors
+    ///
+    /// If the value is not written successfully.
+    fn set_with_expiry(
+        &self,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        expiry: Expiry,
+    ) -> Result<()>;
+
+    /// Makes the given key expire at `expiry`, keeping its value, as a single atomic step.
+    ///
+    /// # Errors
+    ///
+    /// - `KeyNotFound` if the key does not exist or has expired.
+    /// - If the expiry is not written successfully.
+    fn expire(&self, key: impl Into<Key>, expiry: Expiry) -> Result<()>;
+
+    /// Returns how long the given key has left before it expires, or `None` if it never expires.
+    ///
+    /// # Errors
+    ///
+    /// - If the key does not exist.
+    /// - If the expiry is not read successfully.
+    fn ttl(&self, key: impl Into<Key>) -> Result<Option<Duration>>;
+
+    /// Saves `new` to the given key if it currently holds `expected`, as a single atomic step.
+    ///
+    /// The key no longer expires afterwards, as with `set`.
+    ///
+    /// # Errors
+    ///
+    /// - `ConditionFailed` with the current value if the key doesn't hold `expected`.
+    /// - If the value is not written successfully.
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Key>,
+        expected: impl Into<Value>,
+        new: impl Into<Value>,
+    ) -> Result<()>;
+
+    /// Saves the given value to the given key if the key doesn't exist or has expired.
+    ///
+    /// # Errors
+    ///
+    /// - `ConditionFailed` with the current value if the key exists.
+    /// - If the value is not written successfully.
+    fn set_if_absent(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()>;
+
+    /// Removes the given key if it currently holds `expected`, as a single atomic step.
+    ///
+    /// # Errors
+    ///
+    /// - `ConditionFailed` with the current value if the key doesn't hold `expected`.
+    /// - If the key is not removed successfully.
+    fn remove_if_equals(&self, key: impl Into<Key>, expected: impl Into<Value>) -> Result<()>;
+
+    /// Applies every write in the batch as a single atomic step.
+    ///
+    /// `kvs` writes the whole batch to the log as one record, so it is replayed all together or,
+    /// if the store crashed while writing it, not at all. Other readers never see part of it.
+    ///
+    /// # Errors
+    ///
+    /// If the batch is not written successfully, in which case none of it is applied.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Returns up to `limit` keys in the given range along with their values, in key order.
+    ///
+    /// # Errors
+    ///
+    /// If the values are not read successfully.
+    fn scan(&self, range: impl RangeBounds<Key>, limit: usize) -> Result<Vec<(Key, Value)>>;
+
+    /// Returns up to `limit` keys that start with `prefix` along with their values, in key order.
+    ///
+    /// # Errors
+    ///
+    /// If the values are not read successfully.
+    fn scan_prefix(&self, prefix: impl Into<Key>, limit: usize) -> Result<Vec<(Key, Value)>> {
+        self.scan(prefix_range(prefix), limit)
+    }
+
+    /// Returns a page of up to `limit` keys in order, starting after `start_after`, or from the
+    /// first key if it is `None`.
+    ///
+    /// Passing the last key of a page as `start_after` returns the next page.
+    ///
+    /// # Errors
+    ///
+    /// If the keys are not read successfully.
+    fn keys(&self, start_after: Option<Key>, limit: usize) -> Result<Vec<Key>>;
+
+    /// Starts a transaction, which reads the store as it was when it started and applies its
+    /// writes when committed.
+    ///
+    /// # Errors
+    ///
+    /// If the transaction is not started successfully.
+    fn begin(&self) -> Result<Self::Transaction>;
+
+    /// Writes a copy of the store as it is now to the directory at `path`, which must not exist
+    /// or be empty, while the store stays open.
+    ///
+    /// `kvs` hard links the sealed logs, which never change, and copies the active log up to its
+    /// last write. Compaction waits until the backup is done.
+    ///
+    /// # Errors
+    ///
+    /// If the directory isn't empty or the backup is not written successfully.
+    fn backup_to(&self, path: impl Into<PathBuf>) -> Result<()>;
+
+    /// Replaces the store at `path` with a backup written by `backup_to`.
+    ///
+    /// The backup is checked by opening a copy of it and reading every value, before it
+    /// replaces the store. The store must not be open while it is restored.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidBackup` if the backup is incomplete, was written by another engine or can't be
+    ///   read, in which case the store is left as it was.
+    /// - `StoreLocked` if the store is open.
+    /// - If the backup is not restored successfully.
+    fn restore(backup: impl Into<PathBuf>, path: impl Into<PathBuf>) -> Result<()> {
+        backup::restore::<Self>(&backup.into(), &path.into())
+    }
+
+    /// Writes every key that hasn't expired, along with its value and expiry, to `writer` in the
+    /// portable format of the `dump` module, and returns how many were written.
+    ///
+    /// The dump holds the store as it was at a single moment, while writes carry on.
+    ///
+    /// # Errors
+    ///
+    /// If the dump is not written successfully.
+    fn export(&self, writer: impl Write) -> Result<u64>;
+
+    /// Saves every entry of a dump written by `export` to the store, replacing the values of keys
+    /// it already has, and returns how many entries the dump held.
+    ///
+    /// The entries are written in batches, so if the import fails part of the way through, only
+    /// some of them are saved.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidDump` if the dump is damaged or cut short.
+    /// - If the entries are not written successfully.
+    fn import(&self, reader: impl BufRead) -> Result<u64> {
+        dump::import(self, reader)
+    }
+
+    /// Returns how big the store is and how much of it is garbage.
+    ///
+    /// `kvs` works out which bytes of each log are live from its index, so this walks every key.
+    ///
+    /// # Errors
+    ///
+    /// If the size of the store on disk is not read successfully.
+    fn stats(&self) -> Result<EngineStats>;
+}
+
+/// Returns the name of the engine, as recorded in the directory of the store it is used for.
+pub(crate) fn engine_name<Engine: KvsEngine>() -> &'static str {
+    type_name::<Engine>()
+        .split("::")
+        .nth(2)
+        .expect("Unable to parse engine.")
+}
+
+/// A group of reads and writes that either all take effect or none do.
+///
+/// Transactions are optimistic: nothing is locked while one runs. Instead, `commit` checks that
+/// none of the keys it read or wrote have been written by anyone else since, and fails with
+/// `TransactionConflict` otherwise, in which case the transaction can be retried from the start.
+///
+/// Reads see the transaction's own writes. Dropping a transaction without committing it rolls it
+/// back.
+pub trait Transaction {
+    /// Returns the value of the given key, or `None` if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// If the value is not read successfully.
+    fn get(&mut self, key: impl Into<Key>) -> Result<Option<Value>>;
+
+    /// Saves the given value to the given key when the transaction commits.
+    ///
+    /// # Errors
+    ///
+    /// If the current value of the key is not read successfully.
+    fn set(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()>;
+
+    /// Removes the given key when the transaction commits.
+    ///
+    /// # Errors
+    ///
+    /// - If the key does not exist.
+    /// - If the current value of the key is not read successfully.
+    fn remove(&mut self, key: impl Into<Key>) -> Result<()>;
+
+    /// Applies every write of the transaction as a single atomic step.
+    ///
+    /// # Errors
+    ///
+    /// - `TransactionConflict` if a key the transaction used has been written since, in which
+    ///   case none of the writes are applied.
+    /// - If the writes are not applied successfully.
+    fn commit(self) -> Result<()>;
 }
@@ -1,36 +1,578 @@
-use crate::{KvsEngine, KvsError::KeyNotFound, Result};
-use sled::Db;
-use std::path::PathBuf;
+use super::{
+    backup::{create_backup_dir, finish_backup, recover_swap},
+    dump::DumpWriter,
+    engine_name, DirectoryLock, EngineStats, Transaction,
+};
+use crate::{
+    shared::{BatchOperation, Expiry, Key, Set, Value, WriteBatch},
+    KvsEngine,
+    KvsError::{self, ConditionFailed, KeyNotFound, TransactionConflict},
+    Result,
+};
+use sled::{
+    transaction::{abort, TransactionError, TransactionalTree, UnabortableTransactionError},
+    Db, IVec, Transactional, Tree,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Write,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, TryLockError},
+    thread,
+    time::Duration,
+};
+
+/// How many times to retry opening the database while sled still holds its own lock on it
+const OPEN_RETRIES: u32 = 50;
+/// Tree holding the Unix time in milliseconds each expiring key expires at, as a big-endian `u64`
+const EXPIRY_TREE: &str = "expiry";
+/// Tree holding the version each key was last written at, as a big-endian `u64`
+const VERSION_TREE: &str = "versions";
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone)]
 pub struct SledKvsEngine {
     index: Db,
+    /// When keys expire. Keys that never expire aren't in it.
+    expiry: Tree,
+    /// Version of each key, which changes every time the key is written, so transactions can tell
+    /// a key that was changed and changed back from one that wasn't touched. Keys that don't
+    /// exist aren't in it.
+    versions: Tree,
+    /// Taken for reading by every write and for writing by backups, so a backup sees every tree
+    /// as of the same moment
+    writes: Arc<RwLock<()>>,
+    /// Keeps other processes out of the database until the last handle is dropped
+    #[allow(dead_code)]
+    lock: Arc<DirectoryLock>,
 }
 
 impl KvsEngine for SledKvsEngine {
+    type Transaction = SledTransaction;
+
     fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let index = sled::open(path.into())?;
-        Ok(Self { index })
+        let path = path.into();
+        recover_swap(&path)?;
+        fs::create_dir_all(&path)?;
+        let lock = Arc::new(DirectoryLock::acquire(&path)?);
+        // Sled releases its lock from a background thread after the last handle is dropped, so a
+        // store that was just closed may still be locked for a moment
+        let mut retries = 0;
+        let index = loop {
+            match sled::open(&path) {
+                Err(sled::Error::Io(_)) if retries < OPEN_RETRIES => {
+                    retries += 1;
+                    thread::sleep(Duration::from_millis(20));
+                }
+                result => break result?,
+            }
+        };
+        let expiry = index.open_tree(EXPIRY_TREE)?;
+        let versions = index.open_tree(VERSION_TREE)?;
+        Ok(Self {
+            index,
+            expiry,
+            versions,
+            writes: Arc::default(),
+            lock,
+        })
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        match self.index.get(key)? {
-            None => Ok(None),
-            Some(val) => Ok(Some(String::from_utf8(val.to_vec())?)),
-        }
+    fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        let value = self.live_value(&key.into())?;
+        Ok(value.map(|value| Value::from(&*value)))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        if self.index.remove(key)?.is_none() {
-            Err(KeyNotFound)
-        } else {
+    fn remove(&self, key: impl Into<Key>) -> Result<()> {
+        let key = key.into();
+        let _writing = self.writes.read()?;
+        let removed = (&*self.index, &self.expiry, &self.versions)
+            .transaction(|(values, expiry, versions)| {
+                // An expired key is removed all the same, but it was already gone
+                let expired = has_expired_in(expiry, &key)?;
+                expiry.remove(&*key)?;
+                versions.remove(&*key)?;
+                Ok(values.remove(&*key)?.is_some() && !expired)
+            })
+            .map_err(into_kvs_error)?;
+        if removed {
             Ok(())
+        } else {
+            Err(KeyNotFound)
+        }
+    }
+
+    fn set(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        self.insert(&key.into(), &value.into(), None)
+    }
+
+    fn set_with_expiry(
+        &self,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        expiry: Expiry,
+    ) -> Result<()> {
+        self.insert(&key.into(), &value.into(), Some(expiry.unix_millis()))
+    }
+
+    fn expire(&self, key: impl Into<Key>, expiry: Expiry) -> Result<()> {
+        let key = key.into();
+        let expires_at = expiry.unix_millis();
+        let _writing = self.writes.read()?;
+        (&*self.index, &self.expiry, &self.versions)
+            .transaction(|(values, expiry, versions)| {
+                if has_expired_in(expiry, &key)? || values.get(&*key)?.is_none() {
+                    return abort(());
+                }
+                expiry.insert(&*key, &expires_at.to_be_bytes())?;
+                bump_version(versions, &key)?;
+                Ok(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(()) => KeyNotFound,
+                TransactionError::Storage(err) => err.into(),
+            })
+    }
+
+    fn ttl(&self, key: impl Into<Key>) -> Result<Option<Duration>> {
+        let key = key.into();
+        let expiry = self.get_expiry(&key)?;
+        if !self.index.contains_key(&key)? || expiry.is_some_and(Expiry::has_passed) {
+            return Err(KeyNotFound);
+        }
+        Ok(expiry.and_then(Expiry::remaining))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Key>,
+        expected: impl Into<Value>,
+        new: impl Into<Value>,
+    ) -> Result<()> {
+        let expected = expected.into();
+        let new = new.into();
+        self.write_if(
+            &key.into(),
+            |current| current == Some(&*expected),
+            Some(&new),
+        )
+    }
+
+    fn set_if_absent(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        let value = value.into();
+        self.write_if(&key.into(), |current| current.is_none(), Some(&value))
+    }
+
+    fn remove_if_equals(&self, key: impl Into<Key>, expected: impl Into<Value>) -> Result<()> {
+        let expected = expected.into();
+        self.write_if(&key.into(), |current| current == Some(&*expected), None)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        // Resolved up front, as the transaction may run more than once
+        let operations = batch
+            .into_operations()
+            .into_iter()
+            .map(|operation| match operation {
+                BatchOperation::Set(cmd) => BatchOperation::Set(cmd.resolve_expiry()),
+                operation @ BatchOperation::Rm(_) => operation,
+            })
+            .collect::<Vec<_>>();
+        let _writing = self.writes.read()?;
+        (&*self.index, &self.expiry, &self.versions)
+            .transaction(|(values, expiry, versions)| {
+                for operation in &operations {
+                    let key = &**operation.key();
+                    match operation {
+                        BatchOperation::Set(cmd) => {
+                            values.insert(key, &*cmd.value)?;
+                            bump_version(versions, key)?;
+                            match cmd.expires_at() {
+                                Some(expires_at) => {
+                                    expiry.insert(key, &expires_at.to_be_bytes())?;
+                                }
+                                None => {
+                                    expiry.remove(key)?;
+                                }
+                            }
+                        }
+                        BatchOperation::Rm(_) => {
+                            values.remove(key)?;
+                            expiry.remove(key)?;
+                            versions.remove(key)?;
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(into_kvs_error)
+    }
+
+    fn scan(&self, range: impl RangeBounds<Key>, limit: usize) -> Result<Vec<(Key, Value)>> {
+        self.collect_entries(self.index.range(range), limit)
+    }
+
+    fn scan_prefix(&self, prefix: impl Into<Key>, limit: usize) -> Result<Vec<(Key, Value)>> {
+        self.collect_entries(self.index.scan_prefix(prefix.into()), limit)
+    }
+
+    fn keys(&self, start_after: Option<Key>, limit: usize) -> Result<Vec<Key>> {
+        let start = start_after.map_or(Bound::Unbounded, Bound::Excluded);
+        let entries = self.index.range::<Key, _>((start, Bound::Unbounded));
+        Ok(self
+            .collect_entries(entries, limit)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    /// Copies every tree into a new database at `path`, which is what sled's own export does,
+    /// but without panicking on errors. Writes wait until the copy is done, while reads carry on.
+    fn backup_to(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        create_backup_dir(&path)?;
+        let writes = self.writes.write()?;
+        self.copy_trees(&path)?;
+        drop(writes);
+        finish_backup::<Self>(&path)
+    }
+
+    /// Writes wait until the dump is done, so it holds every key as of the same moment.
+    fn export(&self, writer: impl Write) -> Result<u64> {
+        let _writes = self.writes.write()?;
+        let mut dump = DumpWriter::new(writer)?;
+        for entry in &*self.index {
+            let (key, value) = entry?;
+            let expiry = self.get_expiry(&key)?;
+            if !expiry.is_some_and(Expiry::has_passed) {
+                let mut entry = Set::new(Key::from(&*key), Value::from(&*value));
+                entry.expiry = expiry;
+                dump.write_entry(entry)?;
+            }
+        }
+        dump.finish()
+    }
+
+    /// Sled doesn't say how much of its files is garbage, nor when it cleans them up. Keys that
+    /// have expired are purged first, so they aren't counted.
+    fn stats(&self) -> Result<EngineStats> {
+        self.purge_expired()?;
+        Ok(EngineStats {
+            engine: engine_name::<Self>().to_owned(),
+            keys: self.index.len() as u64,
+            disk_bytes: self.index.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+
+    fn begin(&self) -> Result<SledTransaction> {
+        Ok(SledTransaction {
+            engine: self.clone(),
+            observed: HashMap::new(),
+            versions: HashMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+}
+
+impl SledKvsEngine {
+    /// Writes the value and its expiry together, so neither is seen without the other.
+    fn insert(&self, key: &Key, value: &Value, expires_at: Option<u64>) -> Result<()> {
+        let _writing = self.writes.read()?;
+        (&*self.index, &self.expiry, &self.versions)
+            .transaction(|(values, expiry, versions)| {
+                values.insert(&**key, &**value)?;
+                bump_version(versions, key)?;
+                match expires_at {
+                    Some(expires_at) => expiry.insert(&**key, &expires_at.to_be_bytes())?,
+                    None => expiry.remove(&**key)?,
+                };
+                Ok(())
+            })
+            .map_err(into_kvs_error)
+    }
+
+    /// Saves `new` to the key, or removes it if `None`, if `condition` holds for its current
+    /// value.
+    ///
+    /// Sled's own compare-and-swap only covers a single tree, so this uses a transaction to check
+    /// the value and update its expiry together.
+    fn write_if(
+        &self,
+        key: &Key,
+        condition: impl Fn(Option<&[u8]>) -> bool,
+        new: Option<&Value>,
+    ) -> Result<()> {
+        let _writing = self.writes.read()?;
+        (&*self.index, &self.expiry, &self.versions)
+            .transaction(|(values, expiry, versions)| {
+                let expired = has_expired_in(expiry, key)?;
+                let current = if expired { None } else { values.get(&**key)? };
+                if !condition(current.as_deref()) {
+                    return abort(current.map(|value| Value::from(&*value)));
+                }
+                if let Some(value) = new {
+                    values.insert(&**key, &**value)?;
+                    bump_version(versions, key)?;
+                } else {
+                    values.remove(&**key)?;
+                    versions.remove(&**key)?;
+                }
+                expiry.remove(&**key)?;
+                Ok(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(current) => ConditionFailed(current),
+                TransactionError::Storage(err) => err.into(),
+            })
+    }
+
+    fn copy_trees(&self, path: &Path) -> Result<()> {
+        let backup = sled::open(path)?;
+        for name in self.index.tree_names() {
+            let tree = self.index.open_tree(&name)?;
+            let backup_tree = backup.open_tree(&name)?;
+            for entry in &tree {
+                let (key, value) = entry?;
+                backup_tree.insert(key, value)?;
+            }
+        }
+        backup.flush()?;
+        Ok(())
+    }
+
+    fn get_expiry(&self, key: &[u8]) -> Result<Option<Expiry>> {
+        Ok(self
+            .expiry
+            .get(key)?
+            .map(|expires_at| decode_expiry(&expires_at)))
+    }
+
+    fn has_expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get_expiry(key)?.is_some_and(Expiry::has_passed))
+    }
+
+    /// Reads the value of the key together with its expiry, purging the key if it has expired.
+    fn live_value(&self, key: &[u8]) -> Result<Option<IVec>> {
+        let (value, expired) = (&*self.index, &self.expiry)
+            .transaction(|(values, expiry)| {
+                if has_expired_in(expiry, key)? {
+                    Ok((None, true))
+                } else {
+                    Ok((values.get(key)?, false))
+                }
+            })
+            .map_err(into_kvs_error)?;
+        if expired {
+            self.purge(key)?;
+        }
+        Ok(value)
+    }
+
+    /// Removes the key if it has expired. Reads don't wait for a backup to purge what they find
+    /// expired, that is left for later.
+    fn purge(&self, key: &[u8]) -> Result<()> {
+        match self.writes.try_read() {
+            Ok(_writing) => self.remove_if_expired(key),
+            Err(TryLockError::WouldBlock) => Ok(()),
+            Err(TryLockError::Poisoned(err)) => Err(err.into()),
+        }
+    }
+
+    /// Removes every key that has expired.
+    fn purge_expired(&self) -> Result<()> {
+        let _writing = self.writes.read()?;
+        for entry in &self.expiry {
+            let (key, expires_at) = entry?;
+            if decode_expiry(&expires_at).has_passed() {
+                self.remove_if_expired(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the expiry again in the transaction, as the key may have been set since.
+    fn remove_if_expired(&self, key: &[u8]) -> Result<()> {
+        (&*self.index, &self.expiry, &self.versions)
+            .transaction(|(values, expiry, versions)| {
+                if has_expired_in(expiry, key)? {
+                    values.remove(key)?;
+                    expiry.remove(key)?;
+                    versions.remove(key)?;
+                }
+                Ok(())
+            })
+            .map_err(into_kvs_error)
+    }
+
+    /// Collects up to `limit` entries that haven't expired.
+    fn collect_entries(
+        &self,
+        entries: impl Iterator<Item = sled::Result<(IVec, IVec)>>,
+        limit: usize,
+    ) -> Result<Vec<(Key, Value)>> {
+        let mut collected = Vec::new();
+        for entry in entries {
+            if collected.len() >= limit {
+                break;
+            }
+            let (key, value) = entry?;
+            if !self.has_expired(&key)? {
+                collected.push((Key::from(&*key), Value::from(&*value)));
+            } else if let Some(value) = self.live_value(&key)? {
+                // Set again since it was read
+                collected.push((Key::from(&*key), Value::from(&*value)));
+            }
+        }
+        Ok(collected)
+    }
+}
+
+/// Optimistic transaction over a `SledKvsEngine`, started by `SledKvsEngine::begin`.
+///
+/// Sled has no snapshots to read from, so unlike a `KvStoreTransaction`, each key is read when
+/// the transaction first uses it rather than as of `begin`. The transaction remembers the value
+/// and version it saw then, which keeps its reads repeatable. When it commits, a sled transaction
+/// checks that every one of those keys is still at the version it saw before applying the writes,
+/// so a key that was changed and then changed back is a conflict too.
+#[allow(clippy::module_name_repetitions)]
+pub struct SledTransaction {
+    engine: SledKvsEngine,
+    /// Value of each key the transaction has used, or `None` if it didn't exist
+    observed: HashMap<Key, Option<Value>>,
+    /// Version of each key the transaction has used, or `None` if it didn't exist
+    versions: HashMap<Key, Option<u64>>,
+    /// Values to save when committing, or `None` to remove the key
+    writes: BTreeMap<Key, Option<Value>>,
+}
+
+impl SledTransaction {
+    /// Returns the value of the key as the transaction first saw it, recording its version the
+    /// first time the key is used.
+    fn observe(&mut self, key: &Key) -> Result<Option<Value>> {
+        if let Some(value) = self.observed.get(key) {
+            return Ok(value.clone());
+        }
+        let engine = &self.engine;
+        let (value, version) = (&*engine.index, &engine.expiry, &engine.versions)
+            .transaction(|(values, expiry, versions)| {
+                if has_expired_in(expiry, key)? {
+                    return Ok((None, None));
+                }
+                let value = values.get(&**key)?.map(|value| Value::from(&*value));
+                Ok((value, version_in(versions, key)?))
+            })
+            .map_err(into_kvs_error)?;
+        self.observed.insert(key.clone(), value.clone());
+        self.versions.insert(key.clone(), version);
+        Ok(value)
+    }
+}
+
+impl Transaction for SledTransaction {
+    fn get(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
+        let key = key.into();
+        match self.writes.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => self.observe(&key),
         }
     }
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.index.insert(key, value.as_str())?;
+    fn set(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        let key = key.into();
+        self.observe(&key)?;
+        self.writes.insert(key, Some(value.into()));
         Ok(())
     }
+
+    fn remove(&mut self, key: impl Into<Key>) -> Result<()> {
+        let key = key.into();
+        if self.get(key.clone())?.is_none() {
+            return Err(KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let engine = &self.engine;
+        let _writing = engine.writes.read()?;
+        (&*engine.index, &engine.expiry, &engine.versions)
+            .transaction(|(values, expiry, versions)| {
+                for (key, observed) in &self.observed {
+                    let expired = has_expired_in(expiry, key)?;
+                    let (current, version) = if expired {
+                        (None, None)
+                    } else {
+                        (values.get(&**key)?, version_in(versions, key)?)
+                    };
+                    // Keys written before versions were kept have none, so the value is checked too
+                    if current.as_deref() != observed.as_deref()
+                        || version != self.versions.get(key).copied().flatten()
+                    {
+                        return abort(());
+                    }
+                }
+                for (key, value) in &self.writes {
+                    if let Some(value) = value {
+                        values.insert(&**key, &**value)?;
+                        bump_version(versions, key)?;
+                    } else {
+                        values.remove(&**key)?;
+                        versions.remove(&**key)?;
+                    }
+                    expiry.remove(&**key)?;
+                }
+                Ok(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(()) => TransactionConflict,
+                TransactionError::Storage(err) => err.into(),
+            })
+    }
+}
+
+/// Checks whether the key has expired, as part of a sled transaction.
+fn has_expired_in(
+    expiry: &TransactionalTree,
+    key: &[u8],
+) -> std::result::Result<bool, UnabortableTransactionError> {
+    Ok(expiry
+        .get(key)?
+        .is_some_and(|expires_at| decode_expiry(&expires_at).has_passed()))
+}
+
+/// Gives the key a new version, as part of a sled transaction that writes it.
+fn bump_version(
+    versions: &TransactionalTree,
+    key: &[u8],
+) -> std::result::Result<(), UnabortableTransactionError> {
+    versions.insert(key, &versions.generate_id()?.to_be_bytes())?;
+    Ok(())
+}
+
+/// Reads the version of the key, as part of a sled transaction.
+fn version_in(
+    versions: &TransactionalTree,
+    key: &[u8],
+) -> std::result::Result<Option<u64>, UnabortableTransactionError> {
+    Ok(versions
+        .get(key)?
+        .map(|version| (*version).try_into().map_or(0, u64::from_be_bytes)))
+}
+
+/// Anything that isn't 8 bytes long wasn't written by `insert`, so it is treated as expired.
+fn decode_expiry(expires_at: &[u8]) -> Expiry {
+    Expiry::At(expires_at.try_into().map_or(0, u64::from_be_bytes))
+}
+
+fn into_kvs_error(err: TransactionError<sled::Error>) -> KvsError {
+    match err {
+        TransactionError::Abort(err) | TransactionError::Storage(err) => err.into(),
+    }
 }
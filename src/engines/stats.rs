@@ -0,0 +1,66 @@
+use crate::shared::format_ttl;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+/// How big a store is and how much of it is garbage, as returned by `KvsEngine::stats`.
+///
+/// Figures an engine doesn't keep track of are left as `None`, or empty.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct EngineStats {
+    /// Name of the engine, as recorded in the store's directory
+    pub engine: String,
+    /// Number of keys in the store. `sled` also counts expired keys it hasn't purged yet.
+    pub keys: u64,
+    /// Bytes taken up by the values of keys that haven't expired, and whatever they were written
+    /// along with
+    pub live_bytes: Option<u64>,
+    /// Bytes the store takes up on disk
+    pub disk_bytes: u64,
+    /// Breakdown by log, oldest first
+    pub logs: Vec<LogStats>,
+    /// Compactions that compacted any logs since the store was opened
+    pub compactions: Option<u64>,
+    /// How long the last of those compactions took
+    pub last_compaction: Option<Duration>,
+}
+
+/// Usage of a single log of a `KvStore`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct LogStats {
+    pub log_id: u64,
+    /// Size of the log on disk
+    pub size: u64,
+    /// Bytes of records the store still reads from
+    pub live_bytes: u64,
+    /// Bytes of records that have been overwritten, removed or have expired, which compaction
+    /// reclaims
+    pub stale_bytes: u64,
+}
+
+impl Display for EngineStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "engine\t{}", self.engine)?;
+        writeln!(f, "keys\t{}", self.keys)?;
+        if let Some(live_bytes) = self.live_bytes {
+            writeln!(f, "live_bytes\t{live_bytes}")?;
+        }
+        writeln!(f, "disk_bytes\t{}", self.disk_bytes)?;
+        if let Some(compactions) = self.compactions {
+            writeln!(f, "compactions\t{compactions}")?;
+        }
+        if let Some(duration) = self.last_compaction {
+            writeln!(f, "last_compaction\t{}", format_ttl(duration))?;
+        }
+        for log in &self.logs {
+            writeln!(
+                f,
+                "log {}\t{} bytes, {} live, {} stale",
+                log.log_id, log.size, log.live_bytes, log.stale_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
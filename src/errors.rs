@@ -1,3 +1,4 @@
+use crate::shared::Value;
 use std::sync::PoisonError;
 use thiserror::Error;
 pub type Result<T> = anyhow::Result<T, KvsError>;
@@ -10,15 +11,38 @@ pub enum KvsError {
     #[error("IO Error: {0}")]
     IoError(#[from] std::io::Error),
 
+    /// The server wasn't given a directory to write backups to.
+    #[error("Backups are disabled on this server")]
+    BackupsDisabled,
+
     #[error("BufReader Error: {0}")]
     BufReaderError(String, std::io::Error),
 
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+
+    /// A conditional write wasn't applied, along with the value the key held instead.
+    #[error("Condition failed")]
+    ConditionFailed(Option<Value>),
+
+    #[error("Corrupt record in log {0} at offset {1}")]
+    CorruptLog(u64, u64),
+
     #[error("Error: {0}")]
     GeneralError(String),
 
     #[error("Can't glob given pattern")]
     GlobPatternError(#[from] glob::PatternError),
 
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+
+    #[error("Invalid backup path {0}: it must be relative to the backup directory, without `..`")]
+    InvalidBackupPath(String),
+
+    #[error("Invalid dump: {0}")]
+    InvalidDump(String),
+
     #[error("Key not found")]
     KeyNotFound,
 
@@ -28,6 +52,25 @@ pub enum KvsError {
     #[error("Can't parse log index ID")]
     LogIndexParseError(#[from] std::num::ParseIntError),
 
+    /// Compaction removed the log an entry was being read from, and the entry couldn't be found
+    /// in the log it was moved to either.
+    #[error("Log {0} was removed while it was being read")]
+    LogRemoved(u64),
+
+    /// A message, key or value was bigger than the receiving end accepts.
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+
+    #[error("Log {0} is listed in the manifest but missing")]
+    MissingLog(u64),
+
+    #[error("No transaction in progress")]
+    NoTransaction,
+
+    /// The peer doesn't speak the protocol, or a version of it this end does.
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+
     #[error("PoisonError: {0}")]
     PoisonError(String),
 
@@ -40,9 +83,22 @@ pub enum KvsError {
     #[error("Sled DB Error")]
     SledDB(#[from] sled::Error),
 
+    #[error("Store is locked by process {0}")]
+    StoreLocked(u32),
+
     #[error("Thread Error: {0}")]
     ThreadError(String),
 
+    /// A key the transaction used was written by someone else before it committed.
+    #[error("Transaction conflict")]
+    TransactionConflict,
+
+    #[error("Truncated record")]
+    TruncatedRecord,
+
+    #[error("Unsupported on-disk format version {0}")]
+    UnsupportedFormatVersion(u32),
+
     #[error("UTF8 Error")]
     Utf8Error(#[from] std::string::FromUtf8Error),
 
@@ -8,10 +8,16 @@
 pub mod client;
 mod engines;
 mod errors;
+pub mod protocol;
 pub mod serde;
 pub mod server;
 pub mod shared;
 pub mod thread_pool;
 
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    dump,
+    kvs::{compaction, durability, inspect},
+    EngineStats, KvStore, KvStoreOptions, KvStoreSnapshot, KvStoreTransaction, KvsEngine, LogStats,
+    SledKvsEngine, SledTransaction, Transaction,
+};
 pub use errors::{KvsError, Result};
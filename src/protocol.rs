@@ -0,0 +1,455 @@
+//! The protocol spoken between `KvsClient` and `KvsServer`.
+//!
+//! Every message is sent as a frame: the magic bytes `KVSP`, the little-endian `u16` protocol
+//! version, a `u8` message type, the little-endian `u32` length of the payload, then the
+//! bincode-encoded payload.
+//!
+//! A connection starts with the client sending a `Hello` with the range of versions and the
+//! features it supports. The server answers with a `Welcome` naming the highest version and the
+//! features both ends share, which every later frame is sent with, or with an `Error` if they
+//! have no version in common. After that, the client sends `Request`s and the server answers each
+//! with a `Response`.
+//!
+//! Each end bounds the size of the frames it reads and of the keys and values in them with
+//! `Limits`. The payload of a `Request` starts with its id, so a request that is too big can still
+//! be answered, with a `LimitExceeded` response, without closing the connection.
+
+use crate::{
+    shared::{
+        BatchOperation, Command, CommandResponse, ConditionFailed, Key, Request, Response,
+        ResultWithEntries, ResultWithPossibleValue, Set, Value,
+    },
+    KvsError::{self, GeneralError, LimitExceeded, ProtocolError},
+    Result,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, ErrorKind, Read, Write},
+    ops::Bound,
+};
+
+/// Starts every frame
+pub const MAGIC: [u8; 4] = *b"KVSP";
+/// Highest protocol version this build speaks
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Lowest protocol version this build still speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Requests can be sent before the responses to earlier ones have been read
+pub const PIPELINING: &str = "pipelining";
+/// `Begin` starts a transaction on the connection
+pub const TRANSACTIONS: &str = "transactions";
+/// Features this build supports
+pub const FEATURES: [&str; 2] = [PIPELINING, TRANSACTIONS];
+/// Magic, version, message type and payload length
+const HEADER_SIZE: usize = 11;
+/// Size of the id a `Request` payload starts with
+const REQUEST_ID_SIZE: usize = 8;
+
+/// Largest messages, keys and values an end of a connection accepts from the other.
+///
+/// Frames are read in full before they are decoded, so `max_frame_size` also bounds how much
+/// memory decoding one can take.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// Largest payload of a frame, in bytes
+    pub max_frame_size: u32,
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 64 * 1024 * 1024,
+            max_key_size: 64 * 1024,
+            max_value_size: 32 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks the keys and values a command carries.
+    pub fn check_command(&self, command: &Command) -> Result<()> {
+        match command {
+            Command::Set(set) => self.check_set(set),
+            Command::Get(get) => self.check_key(&get.key),
+            Command::Rm(remove) => self.check_key(&remove.key),
+            Command::Ttl(ttl) => self.check_key(&ttl.key),
+            Command::Scan(scan) => {
+                for bound in [&scan.start, &scan.end] {
+                    if let Bound::Included(key) | Bound::Excluded(key) = bound {
+                        self.check_key(key)?;
+                    }
+                }
+                Ok(())
+            }
+            Command::Cas(cas) => {
+                self.check_key(&cas.key)?;
+                self.check_value(&cas.expected)?;
+                self.check_value(&cas.new)
+            }
+            Command::SetIfAbsent(cmd) => {
+                self.check_key(&cmd.key)?;
+                self.check_value(&cmd.value)
+            }
+            Command::RmIfEquals(cmd) => {
+                self.check_key(&cmd.key)?;
+                self.check_value(&cmd.expected)
+            }
+            Command::Batch(batch) => {
+                for operation in batch.operations() {
+                    match operation {
+                        BatchOperation::Set(set) => self.check_set(set)?,
+                        BatchOperation::Rm(remove) => self.check_key(&remove.key)?,
+                    }
+                }
+                Ok(())
+            }
+            Command::Transaction(_) | Command::Admin(_) => Ok(()),
+        }
+    }
+
+    /// Checks the keys and values a response carries.
+    pub fn check_response(&self, response: &CommandResponse) -> Result<()> {
+        match response {
+            CommandResponse::ResultWithPossibleValue(ResultWithPossibleValue::Ok(Some(value)))
+            | CommandResponse::ConditionFailed(ConditionFailed {
+                current: Some(value),
+            }) => self.check_value(value),
+            CommandResponse::ResultWithEntries(ResultWithEntries::Ok(entries)) => {
+                for (key, value) in entries {
+                    self.check_key(key)?;
+                    self.check_value(value)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_set(&self, set: &Set) -> Result<()> {
+        self.check_key(&set.key)?;
+        self.check_value(&set.value)
+    }
+
+    pub fn check_key(&self, key: &Key) -> Result<()> {
+        check_size("key", key.len(), self.max_key_size)
+    }
+
+    pub fn check_value(&self, value: &Value) -> Result<()> {
+        check_size("value", value.len(), self.max_value_size)
+    }
+}
+
+fn check_size(what: &str, size: usize, limit: usize) -> Result<()> {
+    if size > limit {
+        return Err(LimitExceeded(format!(
+            "{what} of {size} bytes is over the limit of {limit}"
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MessageType {
+    Hello = 1,
+    Welcome = 2,
+    Request = 3,
+    Response = 4,
+    /// Sent instead of the expected message when the peer can't be served, with the reason
+    Error = 5,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = KvsError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => Self::Hello,
+            2 => Self::Welcome,
+            3 => Self::Request,
+            4 => Self::Response,
+            5 => Self::Error,
+            _ => return Err(ProtocolError(format!("unknown message type {value}"))),
+        })
+    }
+}
+
+impl Display for MessageType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// Sent by the client to open a connection.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    /// Features the client supports. Features the server doesn't know are ignored.
+    pub features: Vec<String>,
+}
+
+/// Sent by the server in answer to a `Hello`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Welcome {
+    /// The version every later frame on the connection is sent with
+    pub version: u16,
+    /// Features both ends support
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    /// Picks the highest version and the features both ends support, or returns why there are
+    /// none.
+    pub fn negotiate(&self) -> Result<Welcome> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Err(ProtocolError(format!(
+                "the client speaks versions {} to {} but the server speaks {} to {}",
+                self.min_version, self.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+        let features = self
+            .features
+            .iter()
+            .filter(|feature| FEATURES.contains(&feature.as_str()))
+            .cloned()
+            .collect();
+        Ok(Welcome { version, features })
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Welcome {
+    #[must_use]
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+}
+
+/// A message as it was read off a connection, before its payload is decoded.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub version: u16,
+    pub kind: MessageType,
+    /// Size of the payload that was sent
+    pub length: u32,
+    /// The payload, or only as much of it as holds the id of a request if it was over the limit
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Reads the next frame. The payload of a frame bigger than `max_size` is skipped, which
+    /// `decode` then reports.
+    ///
+    /// # Errors
+    ///
+    /// `ProtocolError` if the peer doesn't speak this protocol, or if the frame is cut short.
+    pub fn read(mut reader: impl Read, max_size: u32) -> Result<Self> {
+        let mut header = [0; HEADER_SIZE];
+        reader.read_exact(&mut header).map_err(truncated)?;
+        let (magic, rest) = header.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(ProtocolError(
+                "the peer doesn't speak the kvs protocol".to_owned(),
+            ));
+        }
+        let (version, rest) = rest.split_at(2);
+        let (kind, length) = rest.split_at(1);
+        let version = u16::from_le_bytes(version.try_into().expect("version is 2 bytes"));
+        let kind = MessageType::try_from(kind[0])?;
+        let length = u32::from_le_bytes(length.try_into().expect("length is 4 bytes"));
+
+        let kept = if length > max_size {
+            REQUEST_ID_SIZE.min(length as usize)
+        } else {
+            length as usize
+        };
+        // Read through `take` so a bogus length can't trigger a huge up-front allocation
+        let mut payload = Vec::new();
+        let mut remaining = reader.take(u64::from(length));
+        remaining
+            .by_ref()
+            .take(kept as u64)
+            .read_to_end(&mut payload)?;
+        let skipped = io::copy(&mut remaining, &mut io::sink())?;
+        if payload.len() as u64 + skipped < u64::from(length) {
+            return Err(truncated(ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(Self {
+            version,
+            kind,
+            length,
+            payload,
+        })
+    }
+
+    /// Writes a message as a single frame. The writer isn't flushed.
+    pub fn write<T: Serialize>(
+        mut writer: impl Write,
+        version: u16,
+        kind: MessageType,
+        message: &T,
+    ) -> Result<()> {
+        let payload = bincode::serialize(message)?;
+        let length = u32::try_from(payload.len()).map_err(|e| GeneralError(e.to_string()))?;
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&version.to_le_bytes());
+        header.push(kind as u8);
+        header.extend_from_slice(&length.to_le_bytes());
+        writer.write_all(&header)?;
+        writer.write_all(&payload)?;
+        Ok(())
+    }
+
+    /// Decodes the payload of a frame that must be of the given type and version.
+    ///
+    /// # Errors
+    ///
+    /// `LimitExceeded` if the payload was skipped for being over the limit. `ProtocolError` with
+    /// the reason the peer gave if it sent an `Error`, or if the frame isn't what was expected.
+    pub fn decode<T: DeserializeOwned>(&self, kind: MessageType, version: u16) -> Result<T> {
+        if self.payload.len() < self.length as usize {
+            return Err(LimitExceeded(format!(
+                "{} of {} bytes is over the limit",
+                self.kind, self.length
+            )));
+        }
+        if self.kind == MessageType::Error {
+            let reason = bincode::deserialize::<String>(&self.payload)?;
+            return Err(ProtocolError(reason));
+        }
+        if self.kind != kind {
+            return Err(ProtocolError(format!(
+                "expected a {kind} but got a {}",
+                self.kind
+            )));
+        }
+        if self.version != version {
+            return Err(ProtocolError(format!(
+                "expected version {version} but got version {}",
+                self.version
+            )));
+        }
+        Ok(bincode::deserialize(&self.payload)?)
+    }
+
+    /// Returns the id of the request the frame holds, even if its payload was skipped or can't be
+    /// decoded.
+    #[must_use]
+    pub fn request_id(&self) -> Option<u64> {
+        let id = self.payload.get(..REQUEST_ID_SIZE)?;
+        match self.kind {
+            MessageType::Request => Some(u64::from_le_bytes(id.try_into().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+/// Sends the reason the peer can't be served.
+pub fn write_error(writer: impl Write, error: &KvsError) -> Result<()> {
+    let reason = match error {
+        ProtocolError(reason) => reason.clone(),
+        error => error.to_string(),
+    };
+    Frame::write(writer, PROTOCOL_VERSION, MessageType::Error, &reason)
+}
+
+/// Sends a `Hello` and waits for the server to answer it.
+///
+/// # Errors
+///
+/// `ProtocolError` if the server doesn't speak any version the client does.
+pub fn say_hello(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    limits: &Limits,
+) -> Result<Welcome> {
+    let hello = Hello::new();
+    Frame::write(&mut writer, PROTOCOL_VERSION, MessageType::Hello, &hello)?;
+    writer.flush()?;
+    let frame = Frame::read(&mut reader, limits.max_frame_size)?;
+    let welcome: Welcome = frame.decode(MessageType::Welcome, frame.version)?;
+    if welcome.version != frame.version
+        || !(hello.min_version..=hello.max_version).contains(&welcome.version)
+    {
+        return Err(ProtocolError(format!(
+            "the server picked version {}, which the client doesn't speak",
+            welcome.version
+        )));
+    }
+    Ok(welcome)
+}
+
+/// Waits for a `Hello` and answers it, or sends an `Error` if the client can't be served.
+///
+/// # Errors
+///
+/// `ProtocolError` if the client doesn't speak this protocol or any version the server does.
+pub fn welcome(mut reader: impl Read, mut writer: impl Write, limits: &Limits) -> Result<Welcome> {
+    let result = Frame::read(&mut reader, limits.max_frame_size).and_then(|frame| {
+        // The client doesn't know which version it will speak yet
+        frame
+            .decode::<Hello>(MessageType::Hello, frame.version)?
+            .negotiate()
+    });
+    match &result {
+        Ok(welcome) => Frame::write(&mut writer, welcome.version, MessageType::Welcome, welcome)?,
+        Err(err) => write_error(&mut writer, err)?,
+    }
+    writer.flush()?;
+    result
+}
+
+impl Request {
+    /// Reads a request sent with the given version, and checks it against the limits.
+    pub fn read(reader: impl Read, version: u16, limits: &Limits) -> Result<Self> {
+        let request: Self =
+            Frame::read(reader, limits.max_frame_size)?.decode(MessageType::Request, version)?;
+        limits.check_command(&request.command)?;
+        Ok(request)
+    }
+
+    pub fn write(&self, writer: impl Write, version: u16) -> Result<()> {
+        Frame::write(writer, version, MessageType::Request, self)
+    }
+}
+
+impl Response {
+    /// Reads a response sent with the given version, and checks it against the limits.
+    pub fn read(reader: impl Read, version: u16, limits: &Limits) -> Result<Self> {
+        let response: Self =
+            Frame::read(reader, limits.max_frame_size)?.decode(MessageType::Response, version)?;
+        limits.check_response(&response.response)?;
+        Ok(response)
+    }
+
+    pub fn write(&self, writer: impl Write, version: u16) -> Result<()> {
+        Frame::write(writer, version, MessageType::Response, self)
+    }
+}
+
+fn truncated(error: std::io::Error) -> KvsError {
+    match error.kind() {
+        ErrorKind::UnexpectedEof => ProtocolError("the connection closed mid-frame".to_owned()),
+        _ => error.into(),
+    }
+}
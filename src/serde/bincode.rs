@@ -1,38 +1,68 @@
+use crate::KvsError::{ChecksumMismatch, GeneralError, TruncatedRecord};
 use serde::{de::DeserializeOwned, Serialize};
-use std::{
-    io::{BufRead, Seek, Write},
-    net::TcpStream,
-};
+use std::io::{BufRead, ErrorKind, Read, Seek, Write};
+
+/// Every record written by `serialize_into_writer` is prefixed by a header containing the
+/// little-endian `u32` length of the payload followed by the little-endian `u32` CRC32 of it.
+pub const FRAME_HEADER_SIZE: usize = 8;
 
 pub trait Serde {
-    fn serialize_into_stream(&self, writer: &TcpStream) -> crate::Result<()>
+    /// Writes `self` as a single length-prefixed, checksummed frame and returns the position the
+    /// frame starts at.
+    fn serialize_into_writer<T: Write + Seek>(&self, mut writer: T) -> crate::Result<u64>
     where
         Self: Serialize,
     {
-        Ok(bincode::serialize_into(writer, self)?)
-    }
-
-    fn deserialize_from_stream(reader: &TcpStream) -> crate::Result<Self>
-    where
-        Self: DeserializeOwned,
-    {
-        Ok(bincode::deserialize_from::<_, Self>(reader)?)
+        let log_position = writer.stream_position()?;
+        self.write_frame(&mut writer)?;
+        writer.flush()?;
+        Ok(log_position)
     }
 
-    fn serialize_into_writer<T: Write + Seek>(&self, mut writer: T) -> crate::Result<u64>
+    /// Writes `self` as a single frame, in the same format as `serialize_into_writer`, to a
+    /// writer that may not be able to seek. The writer isn't flushed.
+    fn write_frame<T: Write>(&self, mut writer: T) -> crate::Result<()>
     where
         Self: Serialize,
     {
-        let log_position = writer.stream_position()?;
-        bincode::serialize_into(&mut writer, self)?;
-        writer.flush()?;
-        Ok(log_position)
+        let payload = bincode::serialize(self)?;
+        let length = u32::try_from(payload.len()).map_err(|e| GeneralError(e.to_string()))?;
+        let checksum = crc32fast::hash(&payload);
+        writer.write_all(&length.to_le_bytes())?;
+        writer.write_all(&checksum.to_le_bytes())?;
+        writer.write_all(&payload)?;
+        Ok(())
     }
 
-    fn deserialize_from_reader<T: BufRead>(reader: T) -> crate::Result<Self>
+    /// Reads a frame written by `serialize_into_writer`.
+    ///
+    /// Returns `TruncatedRecord` if the reader runs out of data before the end of the frame and
+    /// `ChecksumMismatch` if the payload doesn't match the checksum in the header.
+    fn deserialize_from_reader<T: BufRead>(mut reader: T) -> crate::Result<Self>
     where
         Self: DeserializeOwned,
     {
-        Ok(bincode::deserialize_from::<_, Self>(reader)?)
+        let mut header = [0; FRAME_HEADER_SIZE];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => TruncatedRecord,
+            _ => e.into(),
+        })?;
+        let (length, checksum) = header.split_at(4);
+        let length = u32::from_le_bytes(length.try_into().expect("header is 8 bytes"));
+        let checksum = u32::from_le_bytes(checksum.try_into().expect("header is 8 bytes"));
+
+        // Read through `take` so a corrupted length can't trigger a huge up-front allocation
+        let mut payload = Vec::new();
+        reader
+            .by_ref()
+            .take(u64::from(length))
+            .read_to_end(&mut payload)?;
+        if payload.len() < length as usize {
+            return Err(TruncatedRecord);
+        }
+        if crc32fast::hash(&payload) != checksum {
+            return Err(ChecksumMismatch);
+        }
+        Ok(bincode::deserialize::<Self>(&payload)?)
     }
 }
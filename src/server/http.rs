@@ -0,0 +1,464 @@
+//! Serves a small HTTP/1.1 gateway to the store, for clients that would rather not speak the
+//! binary protocol.
+//!
+//! - `GET /keys/{key}` returns the value as the body
+//! - `PUT /keys/{key}[?ttl=30s]` saves the body as the value, expiring after `ttl` if given
+//! - `DELETE /keys/{key}` removes the key
+//! - `GET /keys[?prefix=&start_after=&limit=]` lists keys in order, as
+//!   `{"keys": [...], "next": ...}`, where `next` is the `start_after` of the next page or `null`
+//!   on the last one
+//!
+//! Keys are percent-decoded from the path and the query. Listed keys and `next` are
+//! percent-encoded, so they can be put in a path or a query as they are, whatever bytes the keys
+//! hold. Errors are returned as
+//! `{"error": "key_not_found", "message": "Key not found"}`, with a status matching the
+//! `KvsError` they came from.
+
+use super::{read_line, run_on, wait_for_data, IDLE_TIMEOUT};
+use crate::{
+    protocol::Limits,
+    shared::{prefix_range, Expiry, Key, Value},
+    thread_pool::ThreadPool,
+    KvsEngine,
+    KvsError::{self, KeyNotFound, LimitExceeded, ProtocolError},
+    Result,
+};
+use serde::Serialize;
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    ops::Bound,
+};
+
+/// Most header lines a request may have
+const MAX_HEADERS: usize = 100;
+/// Keys listed when the request doesn't give a `limit`
+const DEFAULT_LIST_LIMIT: usize = 100;
+/// Most keys listed at once, whatever the `limit`
+const MAX_LIST_LIMIT: usize = 10_000;
+
+/// Handles the requests sent over the stream one after the other, until the client closes it,
+/// asks for it to be closed or leaves it idle for longer than `IDLE_TIMEOUT`. Requests are read
+/// on the calling thread and run on the pool.
+///
+/// A request that isn't valid HTTP, or whose body is larger than `max_frame_size`, is answered
+/// with an error and the connection is closed, as there is no telling where the next one starts.
+pub(super) fn process_stream<Engine: KvsEngine>(
+    engine: &Engine,
+    pool: &impl ThreadPool,
+    stream: &TcpStream,
+    limits: &Limits,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        // Responses to pipelined requests go out together, once every request that has arrived
+        // has been handled
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if !wait_for_data(&mut reader)? {
+            return Ok(());
+        }
+        let request = match read_request(&mut reader, &mut writer, limits) {
+            Ok(request) => request,
+            Err(err) => {
+                Response::error(&err).write(&mut writer, false)?;
+                writer.flush()?;
+                return Err(err.into());
+            }
+        };
+        let (engine, limits, keep_alive) = (engine.clone(), *limits, request.keep_alive);
+        run_on(pool, move || handle(&engine, &limits, &request))?.write(&mut writer, keep_alive)?;
+        if !keep_alive {
+            writer.flush()?;
+            return Ok(());
+        }
+    }
+}
+
+/// An HTTP request, with its path and query parameters decoded.
+struct Request {
+    method: String,
+    path: Vec<u8>,
+    query: Vec<(Vec<u8>, Vec<u8>)>,
+    body: Vec<u8>,
+    /// Whether the connection stays open after the response
+    keep_alive: bool,
+}
+
+impl Request {
+    /// Returns the last value given for the query parameter, if any.
+    fn parameter(&self, name: &str) -> Option<&[u8]> {
+        self.query
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name.as_bytes())
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns the query parameter as text, failing if it isn't valid UTF-8.
+    fn text_parameter(&self, name: &str) -> Result<Option<&str>> {
+        self.parameter(name)
+            .map(|value| {
+                std::str::from_utf8(value)
+                    .map_err(|_| ProtocolError(format!("'{name}' is not valid UTF-8")))
+            })
+            .transpose()
+    }
+}
+
+/// Reads the next request along with its body.
+///
+/// A client that sent `Expect: 100-continue` is told to go ahead once its body is known to fit.
+fn read_request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    limits: &Limits,
+) -> Result<Request> {
+    let line = read_line(reader)?;
+    let line = String::from_utf8_lossy(&line);
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ProtocolError(format!("malformed request line '{line}'")));
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(ProtocolError(format!("unsupported version '{version}'"))),
+    };
+
+    let mut content_length = None;
+    let mut expect_continue = false;
+    for count in 0.. {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            return Err(ProtocolError(format!("more than {MAX_HEADERS} headers")));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ProtocolError(format!("malformed header '{line}'")));
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            // Ends that disagree on the length would disagree on where the next request starts
+            "content-length" if content_length.is_some() => {
+                return Err(ProtocolError("more than one Content-Length".to_owned()))
+            }
+            "content-length" => {
+                content_length = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|_| value.bytes().all(|byte| byte.is_ascii_digit()))
+                        .ok_or_else(|| {
+                            ProtocolError(format!("invalid Content-Length '{value}'"))
+                        })?,
+                );
+            }
+            "transfer-encoding" => {
+                return Err(ProtocolError(
+                    "Transfer-Encoding isn't supported, send a Content-Length".to_owned(),
+                ))
+            }
+            "connection" => {
+                for option in value.split(',').map(str::trim) {
+                    if option.eq_ignore_ascii_case("close") {
+                        keep_alive = false;
+                    } else if option.eq_ignore_ascii_case("keep-alive") {
+                        keep_alive = true;
+                    }
+                }
+            }
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+
+    let content_length = content_length.unwrap_or(0);
+    if content_length > u64::from(limits.max_frame_size) {
+        return Err(LimitExceeded(format!(
+            "body of {content_length} bytes is larger than {}",
+            limits.max_frame_size
+        )));
+    }
+    if expect_continue && content_length > 0 {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    let mut body = Vec::new();
+    reader.take(content_length).read_to_end(&mut body)?;
+    if (body.len() as u64) < content_length {
+        return Err(ProtocolError(
+            "the connection closed mid-request".to_owned(),
+        ));
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|parameter| !parameter.is_empty())
+        .map(|parameter| {
+            let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            Ok((percent_decode(name, true)?, percent_decode(value, true)?))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Request {
+        method: method.to_owned(),
+        path: percent_decode(path, false)?,
+        query,
+        body,
+        keep_alive,
+    })
+}
+
+/// Escapes every byte but the unreserved characters of RFC 3986 as `%XX`.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            let hex = |digit: u8| char::from(b"0123456789ABCDEF"[usize::from(digit)]);
+            encoded.extend(['%', hex(byte >> 4), hex(byte & 0xf)]);
+        }
+    }
+    encoded
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn percent_decode(text: &str, plus_as_space: bool) -> Result<Vec<u8>> {
+    let mut bytes = text.bytes();
+    let mut decoded = Vec::with_capacity(text.len());
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'%' => {
+                let escape = [bytes.next(), bytes.next()];
+                let [Some(high), Some(low)] = escape else {
+                    return Err(ProtocolError(format!("truncated escape in '{text}'")));
+                };
+                std::str::from_utf8(&[high, low])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| ProtocolError(format!("invalid escape in '{text}'")))?
+            }
+            b'+' if plus_as_space => b' ',
+            byte => byte,
+        });
+    }
+    Ok(decoded)
+}
+
+fn handle<Engine: KvsEngine>(engine: &Engine, limits: &Limits, request: &Request) -> Response {
+    let result = if request.path == b"/keys" {
+        match request.method.as_str() {
+            "GET" => list(engine, request),
+            _ => return Response::method_not_allowed("GET"),
+        }
+    } else if let Some(key) = request.path.strip_prefix(b"/keys/") {
+        let key = Key::from(key);
+        if let Err(err) = limits.check_key(&key) {
+            return Response::error(&err);
+        }
+        match request.method.as_str() {
+            "GET" => get(engine, key),
+            "PUT" => put(engine, limits, key, request),
+            "DELETE" => engine.remove(key).map(|()| Response::no_content()),
+            _ => return Response::method_not_allowed("GET, PUT, DELETE"),
+        }
+    } else {
+        return Response::json(
+            404,
+            &ErrorBody {
+                error: "not_found",
+                message: format!("No such path '{}'", String::from_utf8_lossy(&request.path)),
+            },
+        );
+    };
+    result.unwrap_or_else(|err| Response::error(&err))
+}
+
+fn get(engine: &impl KvsEngine, key: Key) -> Result<Response> {
+    let value = engine.get(key)?.ok_or(KeyNotFound)?;
+    Ok(Response {
+        status: 200,
+        content_type: Some("application/octet-stream"),
+        allow: None,
+        body: value.into_vec(),
+    })
+}
+
+fn put(engine: &impl KvsEngine, limits: &Limits, key: Key, request: &Request) -> Result<Response> {
+    let value = Value::from(request.body.as_slice());
+    limits.check_value(&value)?;
+    match request.text_parameter("ttl")? {
+        Some(ttl) => {
+            let ttl = humantime::parse_duration(ttl)
+                .map_err(|err| ProtocolError(format!("invalid ttl '{ttl}': {err}")))?;
+            engine.set_with_expiry(key, value, Expiry::After(ttl))?;
+        }
+        None => engine.set(key, value)?,
+    }
+    Ok(Response::no_content())
+}
+
+/// A page of keys, and where the next one starts.
+#[derive(Serialize)]
+struct KeyList {
+    keys: Vec<String>,
+    next: Option<String>,
+}
+
+/// Lists the keys that start with `prefix`, after `start_after`, up to `limit` of them.
+fn list(engine: &impl KvsEngine, request: &Request) -> Result<Response> {
+    let limit = match request.text_parameter("limit")? {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if limit > 0 => limit.min(MAX_LIST_LIMIT),
+            _ => return Err(ProtocolError(format!("invalid limit '{limit}'"))),
+        },
+        None => DEFAULT_LIST_LIMIT,
+    };
+    let start_after = request.parameter("start_after").map(Key::from);
+    let keys = match request.parameter("prefix") {
+        None | Some([]) => engine.keys(start_after, limit)?,
+        Some(prefix) => {
+            let (start, end) = prefix_range(prefix);
+            let start = match start_after {
+                Some(after) if after.starts_with(prefix) => Bound::Excluded(after),
+                // Every key with the prefix comes before it
+                Some(after) if *after > *prefix => return Ok(KeyList::empty().into()),
+                _ => start,
+            };
+            engine
+                .scan((start, end), limit)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect()
+        }
+    };
+    let next = match keys.last() {
+        Some(last) if keys.len() == limit => Some(percent_encode(last)),
+        _ => None,
+    };
+    let keys = keys.iter().map(|key| percent_encode(key)).collect();
+    Ok(KeyList { keys, next }.into())
+}
+
+impl KeyList {
+    fn empty() -> Self {
+        Self {
+            keys: Vec::new(),
+            next: None,
+        }
+    }
+}
+
+impl From<KeyList> for Response {
+    fn from(list: KeyList) -> Self {
+        Response::json(200, &list)
+    }
+}
+
+/// The body of an error response.
+#[derive(Serialize)]
+struct ErrorBody {
+    /// Stable name of the kind of error, for clients to match on
+    error: &'static str,
+    message: String,
+}
+
+struct Response {
+    status: u16,
+    content_type: Option<&'static str>,
+    /// Methods the path supports, sent with `405 Method Not Allowed`
+    allow: Option<&'static str>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            content_type: None,
+            allow: None,
+            body: Vec::new(),
+        }
+    }
+
+    fn json(status: u16, body: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: Some("application/json"),
+            allow: None,
+            body: serde_json::to_vec(body).unwrap_or_default(),
+        }
+    }
+
+    fn error(err: &KvsError) -> Self {
+        let (status, error) = match err {
+            KeyNotFound => (404, "key_not_found"),
+            LimitExceeded(_) => (413, "limit_exceeded"),
+            ProtocolError(_) => (400, "bad_request"),
+            KvsError::ConditionFailed(_) | KvsError::TransactionConflict => (409, "conflict"),
+            _ => (500, "internal"),
+        };
+        Self::json(
+            status,
+            &ErrorBody {
+                error,
+                message: err.to_string(),
+            },
+        )
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Self {
+            allow: Some(allow),
+            ..Self::json(
+                405,
+                &ErrorBody {
+                    error: "method_not_allowed",
+                    message: format!("The path only allows {allow}"),
+                },
+            )
+        }
+    }
+
+    fn write(&self, writer: &mut impl Write, keep_alive: bool) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if let Some(content_type) = self.content_type {
+            write!(writer, "Content-Type: {content_type}\r\n")?;
+        }
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {allow}\r\n")?;
+        }
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        write!(writer, "Connection: {connection}\r\n\r\n")?;
+        writer.write_all(&self.body)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
@@ -1,31 +1,57 @@
+mod http;
+mod resp;
 mod spawned_listener;
 
 use crate::{
-    serde::bincode::Serde, server::spawned_listener::SpawnedListener, shared::Command,
-    thread_pool::ThreadPool, KvsEngine, KvsError::WrongEngine, Result,
+    engines::engine_name,
+    protocol::{self, Frame, Limits, MessageType},
+    server::spawned_listener::SpawnedListener,
+    shared::{AdminCommand, Command, CommandResponse, Request, Response, TransactionControl},
+    thread_pool::ThreadPool,
+    KvsEngine,
+    KvsError::{
+        self, BackupsDisabled, InvalidBackupPath, LimitExceeded, ProtocolError, SerializationError,
+        WrongEngine,
+    },
+    Result, Transaction,
 };
 use crossbeam::{
     channel,
     channel::{Receiver, Sender},
 };
 use std::{
-    any::type_name,
     fmt::Display,
-    fs, io,
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
     net::{SocketAddr, TcpStream},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     str::FromStr,
-    sync::{Arc, Once, RwLock},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Once, RwLock,
+    },
+    thread,
+    time::Duration,
 };
-use tracing::{debug, error, info, Level};
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::{
     fmt, fmt::writer::MakeWriterExt, layer::SubscriberExt, util::SubscriberInitExt, Registry,
 };
 
 static INIT_LOGGING: Once = Once::new();
 
+/// How long a connection may wait for its next request before the server closes it, so idle
+/// clients don't pile up connection threads
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Connections served at once, across every protocol, unless the server is given another limit
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// Longest line a text protocol may send, such as a RESP inline command or an HTTP header
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
 pub enum Message {
     Stream(TcpStream),
+    /// A connection to the HTTP gateway
+    HttpStream(TcpStream),
     Ready,
     ShuttingDown,
     Shutdown,
@@ -37,6 +63,17 @@ impl From<TcpStream> for Message {
     }
 }
 
+/// Protocol the server speaks with its clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Protocol {
+    /// The framed protocol of `KvsClient`
+    #[default]
+    Kvs,
+    /// RESP2, as spoken by Redis clients
+    Resp,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum State {
     Starting,
@@ -49,8 +86,19 @@ pub enum State {
 pub struct KvsServer<Engine: KvsEngine, Pool: ThreadPool> {
     address: SocketAddr,
     engine: Engine,
-    pool: Pool,
+    /// Runs the requests read by the thread of each connection
+    pool: Arc<Pool>,
     path: PathBuf,
+    protocol: Protocol,
+    /// Where the HTTP gateway listens, if it is enabled
+    http_address: Option<SocketAddr>,
+    limits: Limits,
+    /// Where clients may write backups to, if they may at all
+    backup_dir: Option<PathBuf>,
+    /// Most connections served at once, as each one has a thread of its own
+    max_connections: usize,
+    /// Connections being served
+    connections: Arc<AtomicUsize>,
     state: Arc<RwLock<State>>,
     pub sender: Sender<Message>,
     pub receiver: Receiver<Message>,
@@ -64,25 +112,65 @@ impl<Engine: KvsEngine, Pool: ThreadPool> KvsServer<Engine, Pool> {
             address,
             engine,
             path: path.to_owned(),
+            protocol: Protocol::default(),
+            http_address: None,
+            limits: Limits::default(),
+            backup_dir: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            connections: Arc::default(),
             state,
-            pool,
+            pool: Arc::new(pool),
             sender,
             receiver,
         }
     }
 
+    /// Sets the protocol the server speaks with its clients.
+    #[must_use]
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Serves an HTTP/JSON gateway to the store on its own address, alongside the protocol of the
+    /// server. Its requests are handled by the same thread pool.
+    #[must_use]
+    pub fn with_http(mut self, address: SocketAddr) -> Self {
+        self.http_address = Some(address);
+        self
+    }
+
+    /// Sets the largest requests, keys and values the server accepts.
+    #[must_use]
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Lets clients write backups of the store to directories inside `path`. Backups are
+    /// disabled otherwise.
+    #[must_use]
+    pub fn with_backup_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(path.into());
+        self
+    }
+
+    /// Sets how many connections the server serves at once. Connections past that are closed as
+    /// soon as they are accepted.
+    #[must_use]
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
     pub fn is_ready(&self) -> bool {
-        self.state
-            .read()
-            .map(|state| *state == State::Ready)
-            .unwrap_or_default()
+        self.state.read().is_ok_and(|state| *state == State::Ready)
     }
 
     pub fn is_shutdown(&self) -> bool {
         self.state
             .read()
-            .map(|state| *state == State::Shutdown)
-            .unwrap_or_default()
+            .is_ok_and(|state| *state == State::Shutdown)
     }
 
     pub fn shutdown(&self) {
@@ -97,41 +185,82 @@ impl<Engine: KvsEngine, Pool: ThreadPool> KvsServer<Engine, Pool> {
     }
 
     pub fn start(&self, num_listeners: usize) -> anyhow::Result<()> {
-        let engine: Vec<&str> = type_name::<Engine>().split("::").collect();
-        let engine = engine.get(2).expect("Unable to parse engine.");
+        let engine = engine_name::<Engine>();
 
-        startup_logging(self.address, engine);
+        startup_logging(self.address, engine, self.protocol);
         self.check_or_save_engine(engine)?;
         let (tx, rx) = (self.sender.clone(), self.receiver.clone());
-        let spawned_listener =
-            SpawnedListener::<Message>::new(num_listeners, self.address, tx.clone()).bind();
+        let mut spawned_listeners =
+            vec![SpawnedListener::<Message>::new(num_listeners, self.address, tx.clone()).bind()];
+        if let Some(address) = self.http_address {
+            info!("Serving HTTP on {}", address);
+            spawned_listeners.push(
+                SpawnedListener::new(num_listeners, address, tx.clone())
+                    .with_messages(Message::HttpStream)
+                    .bind(),
+            );
+        }
         tx.send(Message::Ready)
             .expect("Unable to switch to 'Ready' state.");
 
-        self.process_messages(&rx, &spawned_listener);
+        self.process_messages(&rx, &spawned_listeners);
         Ok(())
     }
 
     fn process_messages(
         &self,
         rx: &Receiver<Message>,
-        spawned_listener: &Arc<SpawnedListener<Message>>,
+        spawned_listeners: &[Arc<SpawnedListener<Message>>],
     ) {
         loop {
             let next_message = rx.recv();
             match next_message {
                 Ok(message) => match message {
                     Message::Stream(stream) => {
-                        let engine = self.engine.clone();
-                        self.pool.spawn(move || {
-                            if let Err(e) = process_stream(&engine, &stream) {
+                        let Some(slot) = self.connection_slot() else {
+                            continue;
+                        };
+                        let (engine, pool) = (self.engine.clone(), self.pool.clone());
+                        let (protocol, limits) = (self.protocol, self.limits);
+                        let backup_dir = self.backup_dir.clone();
+                        spawn_connection(move || {
+                            let _slot = slot;
+                            let result = match protocol {
+                                Protocol::Kvs => process_stream(
+                                    &engine,
+                                    &*pool,
+                                    &stream,
+                                    &limits,
+                                    backup_dir.as_deref(),
+                                ),
+                                Protocol::Resp => {
+                                    resp::process_stream(engine, &*pool, &stream, &limits)
+                                }
+                            };
+                            if let Err(e) = result {
                                 error!("Error processing stream: {:?}", e);
                             }
                         });
                     }
+                    Message::HttpStream(stream) => {
+                        let Some(slot) = self.connection_slot() else {
+                            continue;
+                        };
+                        let (engine, pool) = (self.engine.clone(), self.pool.clone());
+                        let limits = self.limits;
+                        spawn_connection(move || {
+                            let _slot = slot;
+                            if let Err(e) = http::process_stream(&engine, &*pool, &stream, &limits)
+                            {
+                                error!("Error processing HTTP stream: {:?}", e);
+                            }
+                        });
+                    }
                     Message::ShuttingDown => {
                         info!("Shutdown signal received: Shutting down server.");
-                        spawned_listener.shutdown();
+                        for spawned_listener in spawned_listeners {
+                            spawned_listener.shutdown();
+                        }
                         self.sender
                             .send(Message::Shutdown)
                             .expect("Unable to send 'Shutdown' message");
@@ -154,6 +283,19 @@ impl<Engine: KvsEngine, Pool: ThreadPool> KvsServer<Engine, Pool> {
         }
     }
 
+    /// Counts a new connection towards `max_connections`, or returns `None` if the server is
+    /// already serving that many, in which case the connection is closed when it is dropped.
+    fn connection_slot(&self) -> Option<ConnectionSlot> {
+        let slot = ConnectionSlot::take(&self.connections, self.max_connections);
+        if slot.is_none() {
+            warn!(
+                "Refusing a connection, {} are being served already",
+                self.max_connections
+            );
+        }
+        slot
+    }
+
     fn check_or_save_engine(&self, engine: &str) -> Result<()> {
         let file = self.path.join("engine");
         if !file.exists() {
@@ -168,19 +310,274 @@ impl<Engine: KvsEngine, Pool: ThreadPool> KvsServer<Engine, Pool> {
     }
 }
 
+/// Counts a connection towards the limit of the server until it is dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(connections: &Arc<AtomicUsize>, max_connections: usize) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < max_connections).then_some(count + 1)
+            })
+            .ok()?;
+        Some(Self(connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves a connection on a thread of its own.
+///
+/// The thread reads requests and hands them to the pool one at a time, so a connection only
+/// takes up a worker while one of its requests is being handled, and idle clients never keep
+/// other clients waiting.
+fn spawn_connection(serve: impl FnOnce() + Send + 'static) {
+    let result = thread::Builder::new()
+        .name("connection".to_owned())
+        .spawn(serve);
+    if let Err(err) = result {
+        error!("Unable to spawn a thread for a connection: {}", err);
+    }
+}
+
+/// Runs `job` on the pool and waits for its result.
+pub(super) fn run_on<T: Send + 'static>(
+    pool: &impl ThreadPool,
+    job: impl FnOnce() -> T + Send + 'static,
+) -> anyhow::Result<T> {
+    let (sender, receiver) = channel::bounded(1);
+    pool.spawn(move || {
+        // The receiver only goes away if the thread of the connection failed
+        let _ = sender.send(job());
+    });
+    receiver
+        .recv()
+        .map_err(|_| anyhow::anyhow!("The request panicked while it was handled"))
+}
+
+/// Handles the requests sent over the stream one after the other, until the client closes it or
+/// leaves it idle for longer than `IDLE_TIMEOUT`. Requests are read on the calling thread and
+/// run on the pool.
+///
+/// Requests over the limits are answered with a `LimitExceeded` response and skipped. Backups
+/// are written inside `backup_dir`, and refused without one.
 pub fn process_stream<Engine: KvsEngine>(
     engine: &Engine,
+    pool: &impl ThreadPool,
     stream: &TcpStream,
+    limits: &Limits,
+    backup_dir: Option<&Path>,
 ) -> anyhow::Result<()> {
-    let command = Command::deserialize_from_stream(stream)?;
-    let response = command.process(engine);
-    response.serialize_into_stream(stream)?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let Some(mut connection) = Connection::accept(stream, limits)? else {
+        return Ok(());
+    };
+    while let Some(Request { id, command }) = connection.next_request()? {
+        let engine = engine.clone();
+        let response = match command {
+            Command::Transaction(TransactionControl::Begin) => {
+                process_transaction(engine, pool, &mut connection, id)?;
+                continue;
+            }
+            Command::Admin(AdminCommand::Backup(path)) => {
+                let path = backup_path(backup_dir, &path);
+                run_on(pool, move || {
+                    path.and_then(|path| engine.backup_to(path)).into()
+                })?
+            }
+            command => {
+                let limits = *limits;
+                run_on(pool, move || command.process(&engine, &limits))?
+            }
+        };
+        connection.respond(id, response)?;
+    }
     Ok(())
 }
 
-fn startup_logging(address: impl Display, engine: impl Display) {
+/// Resolves the path a client asked for a backup to be written to inside the backup directory.
+///
+/// Only relative paths that stay inside it are accepted, so clients can't write anywhere else
+/// on the server.
+fn backup_path(backup_dir: Option<&Path>, path: &Path) -> Result<PathBuf> {
+    let backup_dir = backup_dir.ok_or(BackupsDisabled)?;
+    let is_confined = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        && path
+            .components()
+            .any(|component| matches!(component, Component::Normal(_)));
+    if !is_confined {
+        return Err(InvalidBackupPath(path.display().to_string()));
+    }
+    Ok(backup_dir.join(path))
+}
+
+/// Runs a transaction with the commands sent over the connection, until the client commits or
+/// rolls it back. The transaction is rolled back if the connection is closed before then.
+///
+/// The transaction is handed to the pool along with each command that runs in it.
+fn process_transaction<Engine: KvsEngine>(
+    engine: Engine,
+    pool: &impl ThreadPool,
+    connection: &mut Connection,
+    begin_id: u64,
+) -> anyhow::Result<()> {
+    let mut transaction = match run_on(pool, move || engine.begin())? {
+        Ok(transaction) => transaction,
+        Err(err) => return connection.respond(begin_id, Err::<(), _>(err).into()),
+    };
+    connection.respond(begin_id, Ok(()).into())?;
+    while let Some(Request { id, command }) = connection.next_request()? {
+        match command {
+            Command::Transaction(TransactionControl::Commit) => {
+                let response = run_on(pool, move || transaction.commit().into())?;
+                return connection.respond(id, response);
+            }
+            Command::Transaction(TransactionControl::Rollback) => {
+                return connection.respond(id, Ok(()).into());
+            }
+            command => {
+                let response;
+                (response, transaction) = run_on(pool, move || {
+                    let response = command.process_in(&mut transaction);
+                    (response, transaction)
+                })?;
+                connection.respond(id, response)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The server's end of a connection, which buffers responses while more requests are waiting.
+struct Connection<'a> {
+    reader: BufReader<&'a TcpStream>,
+    writer: BufWriter<&'a TcpStream>,
+    /// Protocol version agreed on with the client
+    version: u16,
+    limits: &'a Limits,
+}
+
+impl<'a> Connection<'a> {
+    /// Agrees on a protocol version with the client, or returns `None` if it closed the
+    /// connection or left it idle without sending anything.
+    ///
+    /// A client that can't be served is sent the reason before the connection is closed.
+    fn accept(stream: &'a TcpStream, limits: &'a Limits) -> anyhow::Result<Option<Self>> {
+        let mut reader = BufReader::new(stream);
+        let mut writer = BufWriter::new(stream);
+        if !wait_for_data(&mut reader)? {
+            return Ok(None);
+        }
+        let welcome = protocol::welcome(&mut reader, &mut writer, limits)?;
+        debug!(
+            "Speaking protocol version {} with features {:?}",
+            welcome.version, welcome.features
+        );
+        Ok(Some(Self {
+            reader,
+            writer,
+            version: welcome.version,
+            limits,
+        }))
+    }
+
+    /// Waits for the next request, or returns `None` once the client has closed the connection
+    /// or left it idle.
+    ///
+    /// Requests that are too big or can't be decoded are answered with an error and skipped, as
+    /// long as their frame could be read.
+    fn next_request(&mut self) -> anyhow::Result<Option<Request>> {
+        loop {
+            // Responses to pipelined requests go out together, once every request that has
+            // arrived has been handled
+            if self.reader.buffer().is_empty() {
+                self.writer.flush()?;
+            }
+            if !wait_for_data(&mut self.reader)? {
+                return Ok(None);
+            }
+            let frame = match Frame::read(&mut self.reader, self.limits.max_frame_size) {
+                Ok(frame) => frame,
+                Err(err) => return Err(self.close_with(err)),
+            };
+            let request = frame
+                .decode::<Request>(MessageType::Request, self.version)
+                .and_then(|request| {
+                    self.limits.check_command(&request.command)?;
+                    Ok(request)
+                });
+            match (request, frame.request_id()) {
+                (Ok(request), _) => return Ok(Some(request)),
+                (Err(err @ (LimitExceeded(_) | SerializationError(_))), Some(id)) => {
+                    debug!("Rejecting request {id}: {err}");
+                    self.respond(id, Err::<(), _>(err).into())?;
+                }
+                (Err(err), _) => return Err(self.close_with(err)),
+            }
+        }
+    }
+
+    /// Tells the client why the connection is about to be closed, if it broke the protocol.
+    fn close_with(&mut self, err: KvsError) -> anyhow::Error {
+        if let ProtocolError(_) = err {
+            if let Err(err) = protocol::write_error(&mut self.writer, &err)
+                .and_then(|()| Ok(self.writer.flush()?))
+            {
+                debug!("Unable to send the reason the connection is closed: {err}");
+            }
+        }
+        err.into()
+    }
+
+    fn respond(&mut self, id: u64, response: CommandResponse) -> anyhow::Result<()> {
+        Response::new(id, response).write(&mut self.writer, self.version)?;
+        Ok(())
+    }
+}
+
+/// Waits until the client sends something, or returns `false` once it has closed the connection
+/// or left it idle.
+fn wait_for_data(reader: &mut BufReader<&TcpStream>) -> anyhow::Result<bool> {
+    match reader.fill_buf() {
+        Ok([]) => Ok(false),
+        Ok(_) => Ok(true),
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            debug!("Closing idle connection");
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads a line of a text protocol, without the `\r\n` it ends with.
+pub(super) fn read_line(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.pop() != Some(b'\n') {
+        return Err(ProtocolError(if line.len() >= MAX_LINE_LENGTH - 1 {
+            "line too long".to_owned()
+        } else {
+            "the connection closed mid-request".to_owned()
+        }));
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn startup_logging(address: impl Display, engine: impl Display, protocol: Protocol) {
     info!("Starting KVS Server Version {}.", env!("CARGO_PKG_VERSION"));
     info!("Using {} engine, listening on {}", engine, address);
+    info!("Speaking the {} protocol", protocol);
     let _ = std::env::var("RUST_LOG").map(|log_level| debug!("Log level: {}", log_level));
 }
 
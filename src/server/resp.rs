@@ -0,0 +1,417 @@
+//! Serves clients that speak RESP2, the protocol of Redis, so tools built for Redis can use the
+//! store.
+//!
+//! `GET`, `SET`, `DEL`, `EXISTS`, `PING`, `INFO`, `SCAN` and `EXPIRE` are supported, sent either
+//! as arrays of bulk strings or as inline commands typed into a terminal.
+
+use super::{read_line, run_on, wait_for_data, IDLE_TIMEOUT};
+use crate::{
+    protocol::Limits,
+    shared::{Expiry, Key, Value},
+    thread_pool::ThreadPool,
+    KvsEngine,
+    KvsError::{self, KeyNotFound, ProtocolError},
+    Result,
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+/// Most arguments a command may have, as in Redis
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+/// Keys `SCAN` goes through when it isn't given a `COUNT`
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Most keys `SCAN` goes through at once, whatever `COUNT` it is given
+const MAX_SCAN_COUNT: usize = 10_000;
+/// Bytes a `SCAN` reply takes besides its keys, allowing for the cursor
+const SCAN_REPLY_OVERHEAD: usize = 64;
+/// Bytes each key takes in a `SCAN` reply besides the key itself
+const SCAN_KEY_OVERHEAD: usize = 16;
+/// Most `SCAN` cursors a connection keeps, dropping the oldest once there are more
+const MAX_CURSORS: usize = 1024;
+
+/// Handles the commands sent over the stream one after the other, until the client closes it or
+/// leaves it idle for longer than `IDLE_TIMEOUT`. Commands are read on the calling thread and run
+/// on the pool, along with the session they belong to.
+///
+/// A command that isn't valid RESP is answered with an error and the connection is closed, as
+/// there is no telling where the next one starts.
+pub(super) fn process_stream<Engine: KvsEngine>(
+    engine: Engine,
+    pool: &impl ThreadPool,
+    stream: &TcpStream,
+    limits: &Limits,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+    let mut session = Session {
+        engine,
+        limits: *limits,
+        cursors: BTreeMap::new(),
+        next_cursor: 1,
+    };
+    loop {
+        // Replies to pipelined commands go out together, once every command that has arrived
+        // has been handled
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if !wait_for_data(&mut reader)? {
+            return Ok(());
+        }
+        match read_command(&mut reader, limits) {
+            Ok(arguments) if arguments.is_empty() => {}
+            Ok(arguments) => {
+                let reply;
+                (reply, session) = run_on(pool, move || {
+                    let reply = session.run(&arguments);
+                    (reply, session)
+                })?;
+                reply.write(&mut writer)?;
+            }
+            Err(err) => {
+                Reply::error(&err).write(&mut writer)?;
+                writer.flush()?;
+                return Err(err.into());
+            }
+        }
+    }
+}
+
+/// Reads the arguments of the next command, the first of which is its name.
+fn read_command(reader: &mut impl BufRead, limits: &Limits) -> Result<Vec<Vec<u8>>> {
+    let line = read_line(reader)?;
+    let Some(count) = line.strip_prefix(b"*") else {
+        return Ok(line
+            .split(u8::is_ascii_whitespace)
+            .filter(|argument| !argument.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect());
+    };
+    // Redis ignores empty and null arrays
+    let count = usize::try_from(parse_integer(count)?).unwrap_or_default();
+    if count > MAX_ARGUMENTS {
+        return Err(ProtocolError("invalid multibulk length".to_owned()));
+    }
+
+    let mut remaining = limits.max_frame_size as usize;
+    let mut arguments = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(reader)?;
+        let length = line.strip_prefix(b"$").ok_or_else(|| {
+            ProtocolError(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line[..line.len().min(1)])
+            ))
+        })?;
+        let length = usize::try_from(parse_integer(length)?)
+            .map_err(|_| ProtocolError("invalid bulk length".to_owned()))?;
+        // Bounds what the whole command can take up, not just each argument
+        remaining = remaining.checked_sub(length).ok_or_else(|| {
+            ProtocolError(format!(
+                "command of more than {} bytes",
+                limits.max_frame_size
+            ))
+        })?;
+        let mut argument = Vec::new();
+        reader.take(length as u64 + 2).read_to_end(&mut argument)?;
+        if argument.len() < length + 2 {
+            return Err(ProtocolError(
+                "the connection closed mid-request".to_owned(),
+            ));
+        }
+        if !argument.ends_with(b"\r\n") {
+            return Err(ProtocolError("expected CRLF after bulk string".to_owned()));
+        }
+        argument.truncate(length);
+        arguments.push(argument);
+    }
+    Ok(arguments)
+}
+
+fn parse_integer(bytes: &[u8]) -> Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| {
+            ProtocolError(format!(
+                "invalid length '{}'",
+                String::from_utf8_lossy(bytes)
+            ))
+        })
+}
+
+/// The state of a connection, which commands run against.
+struct Session<Engine: KvsEngine> {
+    engine: Engine,
+    limits: Limits,
+    /// Last key returned by each `SCAN` that hasn't finished, by the cursor it returned
+    cursors: BTreeMap<u64, Key>,
+    next_cursor: u64,
+}
+
+impl<Engine: KvsEngine> Session<Engine> {
+    fn run(&mut self, arguments: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&arguments[0]).to_ascii_lowercase();
+        let result = match (name.as_str(), &arguments[1..]) {
+            ("ping", []) => Ok(Reply::Simple("PONG")),
+            ("ping", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+            ("get", [key]) => self.get(key),
+            ("set", [key, value, options @ ..]) => self.set(key, value, options),
+            ("del", keys) if !keys.is_empty() => self.del(keys),
+            ("exists", keys) if !keys.is_empty() => self.exists(keys),
+            ("expire", [key, seconds]) => self.expire(key, seconds),
+            // Every section is returned, whichever is asked for
+            ("info", [] | [_]) => self.info(),
+            ("scan", [cursor, options @ ..]) => self.scan(cursor, options),
+            ("ping" | "get" | "set" | "del" | "exists" | "expire" | "info" | "scan", _) => {
+                Ok(Reply::Error(format!(
+                    "ERR wrong number of arguments for '{name}' command"
+                )))
+            }
+            _ => Ok(Reply::Error(format!("ERR unknown command '{name}'"))),
+        };
+        result.unwrap_or_else(|err| Reply::error(&err))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Reply> {
+        let key = self.key(key)?;
+        let value = self.engine.get(key)?;
+        Ok(Reply::Bulk(value.map(Key::into_vec)))
+    }
+
+    /// `SET key value [EX seconds | PX milliseconds]`
+    fn set(&self, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+        let key = self.key(key)?;
+        let value = Value::from(value);
+        self.limits.check_value(&value)?;
+        let ttl = match options {
+            [] => None,
+            [unit, amount] => {
+                let duration: fn(u64) -> Duration = if unit.eq_ignore_ascii_case(b"ex") {
+                    Duration::from_secs
+                } else if unit.eq_ignore_ascii_case(b"px") {
+                    Duration::from_millis
+                } else {
+                    return Ok(syntax_error());
+                };
+                let Ok(amount) = parse_integer(amount) else {
+                    return Ok(not_an_integer());
+                };
+                match u64::try_from(amount) {
+                    Ok(amount) if amount > 0 => Some(duration(amount)),
+                    _ => return Ok(invalid_expire_time()),
+                }
+            }
+            _ => return Ok(syntax_error()),
+        };
+        match ttl {
+            Some(ttl) => self
+                .engine
+                .set_with_expiry(key, value, Expiry::After(ttl))?,
+            None => self.engine.set(key, value)?,
+        }
+        Ok(Reply::Simple("OK"))
+    }
+
+    fn del(&self, keys: &[Vec<u8>]) -> Result<Reply> {
+        let mut removed = 0;
+        for key in keys {
+            match self.engine.remove(self.key(key)?) {
+                Ok(()) => removed += 1,
+                Err(KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Reply::Integer(removed))
+    }
+
+    fn exists(&self, keys: &[Vec<u8>]) -> Result<Reply> {
+        let mut found = 0;
+        for key in keys {
+            if self.engine.get(self.key(key)?)?.is_some() {
+                found += 1;
+            }
+        }
+        Ok(Reply::Integer(found))
+    }
+
+    /// Makes the key expire in the given number of seconds, or removes it if that isn't positive.
+    fn expire(&self, key: &[u8], seconds: &[u8]) -> Result<Reply> {
+        let key = self.key(key)?;
+        let Ok(seconds) = parse_integer(seconds) else {
+            return Ok(not_an_integer());
+        };
+        let result = match u64::try_from(seconds) {
+            Ok(seconds) if seconds > 0 => {
+                let expiry = Expiry::After(Duration::from_secs(seconds));
+                self.engine.expire(key, expiry)
+            }
+            _ => self.engine.remove(key),
+        };
+        match result {
+            Ok(()) => Ok(Reply::Integer(1)),
+            Err(KeyNotFound) => Ok(Reply::Integer(0)),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn info(&self) -> Result<Reply> {
+        let stats = self.engine.stats()?;
+        let mut lines = vec![
+            "# Server".to_owned(),
+            format!("kvs_version:{}", env!("CARGO_PKG_VERSION")),
+            format!("engine:{}", stats.engine),
+            String::new(),
+            "# Stats".to_owned(),
+            format!("disk_bytes:{}", stats.disk_bytes),
+        ];
+        if let Some(live_bytes) = stats.live_bytes {
+            lines.push(format!("live_bytes:{live_bytes}"));
+        }
+        if let Some(compactions) = stats.compactions {
+            lines.push(format!("compactions:{compactions}"));
+        }
+        lines.push(String::new());
+        lines.push("# Keyspace".to_owned());
+        lines.push(format!("db0:keys={}", stats.keys));
+        let info = lines.join("\r\n") + "\r\n";
+        Ok(Reply::Bulk(Some(info.into_bytes())))
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`
+    ///
+    /// Cursors belong to the connection they were returned on. As in Redis, `COUNT` is how many
+    /// keys are gone through, before they are matched against the pattern. It is capped at
+    /// `MAX_SCAN_COUNT`, and keys that wouldn't fit in a frame are left for the next call.
+    fn scan(&mut self, cursor: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in options.chunks(2) {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case(b"match") => {
+                    let value = String::from_utf8_lossy(value);
+                    pattern = Some(glob::Pattern::new(&value)?);
+                }
+                [name, value] if name.eq_ignore_ascii_case(b"count") => {
+                    match parse_integer(value).map(usize::try_from) {
+                        Ok(Ok(value)) if value > 0 => count = value.min(MAX_SCAN_COUNT),
+                        Ok(_) => return Ok(syntax_error()),
+                        Err(_) => return Ok(not_an_integer()),
+                    }
+                }
+                _ => return Ok(syntax_error()),
+            }
+        }
+        let start_after = match parse_integer(cursor).map(u64::try_from) {
+            Ok(Ok(0)) => None,
+            Ok(Ok(cursor)) => match self.cursors.remove(&cursor) {
+                Some(key) => Some(key),
+                None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+            },
+            _ => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+        };
+
+        let mut keys = self.engine.keys(start_after, count)?;
+        let mut budget = (self.limits.max_frame_size as usize).saturating_sub(SCAN_REPLY_OVERHEAD);
+        // At least one key is returned, so the cursor always moves on
+        let fitting = keys
+            .iter()
+            .position(|key| {
+                let size = key.len() + SCAN_KEY_OVERHEAD;
+                let fits = size <= budget;
+                budget = budget.saturating_sub(size);
+                !fits
+            })
+            .unwrap_or(keys.len())
+            .max(1);
+        let more = keys.len() == count || fitting < keys.len();
+        keys.truncate(fitting);
+        let next_cursor = match keys.last() {
+            Some(last) if more => {
+                let cursor = self.next_cursor;
+                self.next_cursor += 1;
+                self.cursors.insert(cursor, last.clone());
+                if self.cursors.len() > MAX_CURSORS {
+                    self.cursors.pop_first();
+                }
+                cursor
+            }
+            _ => 0,
+        };
+        let keys = keys
+            .into_iter()
+            .filter(|key| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| pattern.matches(&key.to_string()))
+            })
+            .map(|key| Reply::Bulk(Some(key.into_vec())))
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next_cursor.to_string().into_bytes())),
+            Reply::Array(keys),
+        ]))
+    }
+
+    fn key(&self, key: &[u8]) -> Result<Key> {
+        let key = Key::from(key);
+        self.limits.check_key(&key)?;
+        Ok(key)
+    }
+}
+
+/// A RESP2 reply.
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    /// `None` is the null bulk string Redis returns for missing keys
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(err: &KvsError) -> Self {
+        // An error reply ends at the first line break
+        let message = err.to_string().replace(['\r', '\n'], " ");
+        Self::Error(format!("ERR {message}"))
+    }
+
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Self::Simple(text) => write!(writer, "+{text}\r\n"),
+            Self::Error(message) => write!(writer, "-{message}\r\n"),
+            Self::Integer(number) => write!(writer, ":{number}\r\n"),
+            Self::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Self::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Self::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies {
+                    reply.write(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_owned())
+}
+
+fn invalid_expire_time() -> Reply {
+    Reply::Error("ERR invalid expire time in 'set' command".to_owned())
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_owned())
+}
@@ -21,6 +21,8 @@ where
     address: SocketAddr,
     cpus: usize,
     stream_sender: Sender<T>,
+    /// Wraps each accepted stream in the message sent for it
+    into_message: fn(TcpStream) -> T,
     state_sender: Sender<ListenerState>,
     state_receiver: Receiver<ListenerState>,
 }
@@ -37,11 +39,20 @@ where
             address,
             cpus,
             stream_sender,
+            into_message: T::from,
             state_sender,
             state_receiver,
         }
     }
 
+    /// Wraps accepted streams with `into_message` rather than `From`, so streams from different
+    /// listeners can be told apart.
+    #[must_use]
+    pub fn with_messages(mut self, into_message: fn(TcpStream) -> T) -> Self {
+        self.into_message = into_message;
+        self
+    }
+
     pub fn bind(self) -> Arc<SpawnedListener<T>> {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.cpus)
@@ -75,7 +86,8 @@ where
                     }
                     match stream {
                         Ok(stream) => {
-                            if let Err(err) = spawned.stream_sender.send(stream.into()) {
+                            let message = (spawned.into_message)(stream);
+                            if let Err(err) = spawned.stream_sender.send(message) {
                                 error!("Stream sender error: {}", err);
                             }
                         }
@@ -1,19 +1,23 @@
 use crate::{
+    engines::recover_swap,
+    protocol::Limits,
     serde::bincode::Serde,
-    KvsEngine,
-    KvsError::{BufReaderError, KeyNotFound},
-    Result,
+    EngineStats, KvsEngine,
+    KvsError::{self, BackupsDisabled, BufReaderError, GeneralError, KeyNotFound, NoTransaction},
+    Result, Transaction,
 };
-use clap::{Args, Subcommand};
 use derive_more::{Constructor, From};
 use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Borrow,
     fmt::{Display, Formatter},
     fs,
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter},
+    ops::{Bound, Deref},
     path::{Path, PathBuf},
     sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 // TODO: move to config files
@@ -22,12 +26,26 @@ pub const LOG_DIRECTORY_PREFIX: &str = "log_index";
 pub static LOG_ROTATION_MIN_SIZE_BYTES: OnceLock<u64> = OnceLock::new();
 pub const LOG_ROTATION_MIN_SIZE_BYTES_DEFAULT: u64 = 1024 * 1024 * 1024;
 
-pub const LOG_COMPACTION_MAX_KEY_DENSITY_PERCENT: u64 = 30;
+/// Entries a scan reads from the engine at a time
+const SCAN_PAGE_SIZE: usize = 256;
+/// Bytes a scan entry takes up in a response besides its key and value: the length of each
+const SCAN_ENTRY_OVERHEAD: usize = 16;
+/// Bytes a scan response takes up besides its entries
+const SCAN_RESPONSE_OVERHEAD: usize = 64;
 
 #[derive(Clone, Debug, From, Serialize, Deserialize)]
 pub enum CommandResponse {
     ResultWithNoResponse(ResultWithNoResponse),
     ResultWithPossibleValue(ResultWithPossibleValue),
+    ResultWithEntries(ResultWithEntries),
+    ResultWithTtl(ResultWithTtl),
+    ResultWithStats(ResultWithStats),
+    /// A conditional write wasn't applied
+    ConditionFailed(ConditionFailed),
+    /// A transaction wasn't committed because a key it used was written by someone else
+    TransactionConflict(TransactionConflict),
+    /// The request, or a key or value in it, was bigger than the server accepts
+    LimitExceeded(LimitExceeded),
 }
 
 impl CommandResponse {
@@ -37,17 +55,107 @@ impl CommandResponse {
             *self,
             Self::ResultWithNoResponse(ResultWithNoResponse::Err(_))
                 | Self::ResultWithPossibleValue(ResultWithPossibleValue::Err(_))
+                | Self::ResultWithEntries(ResultWithEntries::Err(_))
+                | Self::ResultWithTtl(ResultWithTtl::Err(_))
+                | Self::ResultWithStats(ResultWithStats::Err(_))
+                | Self::ConditionFailed(_)
+                | Self::TransactionConflict(_)
+                | Self::LimitExceeded(_)
         )
     }
+
+    /// Returns the value sent back by the server, or the error it reported.
+    pub fn into_result(self) -> Result<Option<Value>> {
+        match self {
+            Self::ResultWithNoResponse(ResultWithNoResponse::Ok(())) => Ok(None),
+            Self::ResultWithPossibleValue(ResultWithPossibleValue::Ok(value)) => Ok(value),
+            Self::ResultWithEntries(ResultWithEntries::Ok(_)) => {
+                Err(GeneralError("Unexpected response with entries".to_owned()))
+            }
+            Self::ResultWithTtl(ResultWithTtl::Ok(_)) => {
+                Err(GeneralError("Unexpected response with a ttl".to_owned()))
+            }
+            Self::ResultWithStats(ResultWithStats::Ok(_)) => {
+                Err(GeneralError("Unexpected response with stats".to_owned()))
+            }
+            Self::ResultWithNoResponse(ResultWithNoResponse::Err(err))
+            | Self::ResultWithPossibleValue(ResultWithPossibleValue::Err(err))
+            | Self::ResultWithEntries(ResultWithEntries::Err(err))
+            | Self::ResultWithTtl(ResultWithTtl::Err(err))
+            | Self::ResultWithStats(ResultWithStats::Err(err)) => Err(GeneralError(err)),
+            Self::ConditionFailed(ConditionFailed { current }) => {
+                Err(KvsError::ConditionFailed(current))
+            }
+            Self::TransactionConflict(TransactionConflict) => Err(KvsError::TransactionConflict),
+            Self::LimitExceeded(LimitExceeded { reason }) => Err(KvsError::LimitExceeded(reason)),
+        }
+    }
+
+    /// Returns the entries sent back by the server, or the error it reported.
+    pub fn into_entries(self) -> Result<Vec<(Key, Value)>> {
+        match self {
+            Self::ResultWithEntries(ResultWithEntries::Ok(entries)) => Ok(entries),
+            response => response.into_result().and(Err(GeneralError(
+                "Expected a response with entries".to_owned(),
+            ))),
+        }
+    }
+
+    /// Returns the remaining lifetime sent back by the server, or the error it reported.
+    pub fn into_ttl(self) -> Result<Option<Duration>> {
+        match self {
+            Self::ResultWithTtl(ResultWithTtl::Ok(ttl)) => Ok(ttl),
+            response => response.into_result().and(Err(GeneralError(
+                "Expected a response with a ttl".to_owned(),
+            ))),
+        }
+    }
+
+    /// Returns the stats of the store sent back by the server, or the error it reported.
+    pub fn into_stats(self) -> Result<EngineStats> {
+        match self {
+            Self::ResultWithStats(ResultWithStats::Ok(stats)) => Ok(stats),
+            response => response.into_result().and(Err(GeneralError(
+                "Expected a response with stats".to_owned(),
+            ))),
+        }
+    }
 }
 
 impl Serde for CommandResponse {}
 
+/// A command sent to the server, tagged with an id that comes back with its response.
+///
+/// A connection carries any number of requests, which the server handles one after the other, so
+/// a client can send several before reading their responses.
+#[derive(Constructor, Clone, Debug, Deserialize, Serialize)]
+pub struct Request {
+    pub id: u64,
+    pub command: Command,
+}
+
+/// The response to the request with the same id.
+#[derive(Constructor, Clone, Debug, Deserialize, Serialize)]
+pub struct Response {
+    pub id: u64,
+    pub response: CommandResponse,
+}
+
 impl Display for CommandResponse {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let value = match self {
             CommandResponse::ResultWithNoResponse(e) => e.to_string(),
             CommandResponse::ResultWithPossibleValue(val) => val.to_string(),
+            CommandResponse::ResultWithEntries(entries) => entries.to_string(),
+            CommandResponse::ResultWithTtl(ttl) => ttl.to_string(),
+            CommandResponse::ResultWithStats(stats) => stats.to_string(),
+            CommandResponse::ConditionFailed(_) => format!("{}\n", KvsError::ConditionFailed(None)),
+            CommandResponse::TransactionConflict(_) => {
+                format!("{}\n", KvsError::TransactionConflict)
+            }
+            CommandResponse::LimitExceeded(LimitExceeded { reason }) => {
+                format!("{}\n", KvsError::LimitExceeded(reason.clone()))
+            }
         };
         write!(f, "{value}")
     }
@@ -55,14 +163,16 @@ impl Display for CommandResponse {
 
 #[derive(Clone, Debug, From, Serialize, Deserialize)]
 pub enum ResultWithPossibleValue {
-    Ok(Option<String>),
+    Ok(Option<Value>),
     Err(String),
 }
 
 impl Display for ResultWithPossibleValue {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let value = match self {
-            ResultWithPossibleValue::Ok(ok) => ok.clone().unwrap_or_default(),
+            ResultWithPossibleValue::Ok(ok) => {
+                ok.as_ref().map(ToString::to_string).unwrap_or_default()
+            }
             ResultWithPossibleValue::Err(e) => e.into(),
         };
         if value.is_empty() {
@@ -89,8 +199,114 @@ impl Display for ResultWithNoResponse {
     }
 }
 
-impl From<Result<Option<String>>> for CommandResponse {
-    fn from(value: Result<Option<String>>) -> Self {
+#[derive(Clone, Debug, From, Serialize, Deserialize)]
+pub enum ResultWithEntries {
+    Ok(Vec<(Key, Value)>),
+    Err(String),
+}
+
+impl Display for ResultWithEntries {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResultWithEntries::Ok(entries) => {
+                for (key, value) in entries {
+                    writeln!(f, "{key}\t{value}")?;
+                }
+                Ok(())
+            }
+            ResultWithEntries::Err(e) => writeln!(f, "{e}"),
+        }
+    }
+}
+
+/// Sent back instead of an error when a conditional write wasn't applied, so the client can tell
+/// it apart from a failure.
+#[derive(Constructor, Clone, Debug, Serialize, Deserialize)]
+pub struct ConditionFailed {
+    /// The value the key held instead, or `None` if it didn't exist
+    pub current: Option<Value>,
+}
+
+/// Sent back instead of an error when a transaction conflicted, so the client knows it can retry.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionConflict;
+
+/// Sent back instead of an error when a request was too big, so the client can tell it apart from
+/// a failure of the store.
+#[derive(Constructor, Clone, Debug, Serialize, Deserialize)]
+pub struct LimitExceeded {
+    /// What was too big, and the limit it went over
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, From, Serialize, Deserialize)]
+pub enum ResultWithTtl {
+    /// How long the key has left, or `None` if it never expires
+    Ok(Option<Duration>),
+    Err(String),
+}
+
+impl Display for ResultWithTtl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResultWithTtl::Ok(Some(ttl)) => writeln!(f, "{}", format_ttl(*ttl)),
+            ResultWithTtl::Ok(None) => writeln!(f, "none"),
+            ResultWithTtl::Err(e) => writeln!(f, "{e}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, From, Serialize, Deserialize)]
+pub enum ResultWithStats {
+    Ok(EngineStats),
+    Err(String),
+}
+
+impl Display for ResultWithStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResultWithStats::Ok(stats) => write!(f, "{stats}"),
+            ResultWithStats::Err(e) => writeln!(f, "{e}"),
+        }
+    }
+}
+
+/// Formats a remaining lifetime to the millisecond, e.g. `29s 998ms`.
+#[must_use]
+pub fn format_ttl(ttl: Duration) -> String {
+    let millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    humantime::format_duration(Duration::from_millis(millis)).to_string()
+}
+
+impl From<Result<Option<Duration>>> for CommandResponse {
+    fn from(value: Result<Option<Duration>>) -> Self {
+        match value {
+            Ok(ttl) => ResultWithTtl::Ok(ttl).into(),
+            Err(err) => ResultWithTtl::Err(err.to_string()).into(),
+        }
+    }
+}
+
+impl From<Result<EngineStats>> for CommandResponse {
+    fn from(value: Result<EngineStats>) -> Self {
+        match value {
+            Ok(stats) => ResultWithStats::Ok(stats).into(),
+            Err(err) => ResultWithStats::Err(err.to_string()).into(),
+        }
+    }
+}
+
+impl From<Result<Vec<(Key, Value)>>> for CommandResponse {
+    fn from(value: Result<Vec<(Key, Value)>>) -> Self {
+        match value {
+            Ok(entries) => ResultWithEntries::Ok(entries).into(),
+            Err(err) => ResultWithEntries::Err(err.to_string()).into(),
+        }
+    }
+}
+
+impl From<Result<Option<Value>>> for CommandResponse {
+    fn from(value: Result<Option<Value>>) -> Self {
         match value {
             Ok(val) => ResultWithPossibleValue::Ok(val).into(),
             Err(err) => ResultWithPossibleValue::Err(err.to_string()).into(),
@@ -101,7 +317,10 @@ impl From<Result<Option<String>>> for CommandResponse {
 impl From<Result<()>> for CommandResponse {
     fn from(value: Result<()>) -> Self {
         match value {
-            Ok(_) => ResultWithNoResponse::Ok(()).into(),
+            Ok(()) => ResultWithNoResponse::Ok(()).into(),
+            Err(KvsError::ConditionFailed(current)) => ConditionFailed::new(current).into(),
+            Err(KvsError::TransactionConflict) => TransactionConflict.into(),
+            Err(KvsError::LimitExceeded(reason)) => LimitExceeded::new(reason).into(),
             Err(err) => ResultWithNoResponse::Err(err.to_string()).into(),
         }
     }
@@ -109,6 +328,8 @@ impl From<Result<()>> for CommandResponse {
 
 pub fn initialize_log_directory(path: &Path) -> Result<PathBuf> {
     let path = path.join(LOG_DIRECTORY_PREFIX);
+    // A restore or migration cut short may have left the store renamed out of the way
+    recover_swap(&path)?;
     fs::create_dir_all(&path)?;
     Ok(path)
 }
@@ -128,39 +349,422 @@ pub fn new_writer(file: PathBuf) -> Result<BufWriter<File>> {
     ))
 }
 
-#[derive(Subcommand, Clone, Debug, From, Deserialize, Serialize)]
+/// Replaces `file` with the serialized `value` by writing it to a temporary file and renaming it
+/// into place, so readers only ever see the old or the new contents.
+pub fn save_atomically<T: Serde + Serialize>(value: &T, file: &Path) -> Result<()> {
+    let mut temp_file = file.as_os_str().to_owned();
+    temp_file.push(".tmp");
+    let temp_file = PathBuf::from(temp_file);
+    let mut writer = File::create(&temp_file)?;
+    value.serialize_into_writer(&mut writer)?;
+    writer.sync_all()?;
+    fs::rename(&temp_file, file)?;
+    if let Some(directory) = file.parent() {
+        File::open(directory)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, From, Deserialize, Serialize)]
 pub enum Command {
-    /// Save the given string value to the given string key
+    /// Save the given value to the given key
     Set(Set),
-    /// Get the string value of a given string key
+    /// Get the value of a given key
     Get(Get),
-    /// Remove the given string key
+    /// Remove the given key
     Rm(Remove),
+    /// List the keys in a range along with their values
+    Scan(Scan),
+    /// Get how long a given key has left before it expires
+    Ttl(Ttl),
+    /// Save the given value to the given key if it currently holds the expected value
+    Cas(CompareAndSwap),
+    /// Save the given value to the given key if it doesn't exist
+    SetIfAbsent(SetIfAbsent),
+    /// Remove the given key if it currently holds the expected value
+    RmIfEquals(RemoveIfEquals),
+    /// Apply a group of writes all at once
+    Batch(WriteBatch),
+    /// Start, commit or roll back a transaction on the connection
+    Transaction(TransactionControl),
+    /// Maintain the store
+    Admin(AdminCommand),
 }
 
-type Key = String;
-type Value = String;
+pub type Key = Bytes;
+pub type Value = Bytes;
+
+/// An arbitrary sequence of bytes, used for keys and values.
+///
+/// Logs written when keys and values were strings predate framed records, and are rewritten in
+/// the current format by `LogIndex::upgrade_unframed_logs` when the store is opened.
+#[derive(
+    Clone, Debug, Default, From, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize,
+)]
+pub struct Bytes(Vec<u8>);
+
+impl Bytes {
+    #[must_use]
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl From<String> for Bytes {
+    fn from(value: String) -> Self {
+        Self(value.into_bytes())
+    }
+}
+
+impl From<&str> for Bytes {
+    fn from(value: &str) -> Self {
+        Self(value.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for Bytes {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Shows the bytes as text, replacing anything that isn't valid UTF-8.
+impl Display for Bytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
 
-#[derive(Args, Constructor, Clone, Debug, Default, From, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Set {
     pub key: Key,
     pub value: Value,
+    /// When the key expires, if ever. Always `Expiry::At` once written to a log.
+    pub expiry: Option<Expiry>,
 }
 
-#[derive(Args, Constructor, Clone, Debug, Default, From, Serialize, Deserialize)]
+impl Set {
+    #[must_use]
+    pub fn new(key: Key, value: Value) -> Self {
+        Self {
+            key,
+            value,
+            expiry: None,
+        }
+    }
+
+    /// Makes the key expire at the given time.
+    #[must_use]
+    pub fn with_expiry(mut self, expiry: Expiry) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+
+    /// Turns a relative expiry into the absolute time it ends at, counted from now.
+    #[must_use]
+    pub fn resolve_expiry(mut self) -> Self {
+        self.expiry = self.expiry.map(|expiry| Expiry::At(expiry.unix_millis()));
+        self
+    }
+
+    /// Returns the Unix time in milliseconds the key expires at, if it expires.
+    #[must_use]
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expiry.map(Expiry::unix_millis)
+    }
+}
+
+/// When a key stops being visible, as if it had been removed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum Expiry {
+    /// Once the given time has passed, counted from when the command is applied
+    After(Duration),
+    /// At the given Unix time, in milliseconds
+    At(u64),
+}
+
+impl Expiry {
+    /// Returns the Unix time in milliseconds this expiry is at.
+    #[must_use]
+    pub fn unix_millis(self) -> u64 {
+        match self {
+            Expiry::After(ttl) => {
+                unix_millis_now().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+            }
+            Expiry::At(expires_at) => expires_at,
+        }
+    }
+
+    /// Returns how long is left until the expiry, or `None` if it has passed.
+    #[must_use]
+    pub fn remaining(self) -> Option<Duration> {
+        let remaining = self.unix_millis().checked_sub(unix_millis_now())?;
+        (remaining > 0).then(|| Duration::from_millis(remaining))
+    }
+
+    #[must_use]
+    pub fn has_passed(self) -> bool {
+        self.remaining().is_none()
+    }
+}
+
+/// Returns the current Unix time in milliseconds.
+#[must_use]
+pub fn unix_millis_now() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    u64::try_from(now.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[derive(Constructor, Clone, Debug, Default, From, Serialize, Deserialize)]
 pub struct Get {
     pub key: Key,
 }
 
-#[derive(Args, Constructor, Clone, Debug, Default, From, Deserialize, Serialize)]
+#[derive(Constructor, Clone, Debug, Default, From, Deserialize, Serialize)]
 pub struct Remove {
     pub key: Key,
 }
 
+/// A group of writes that are logged as a single record, so they are applied all together or,
+/// after a crash part way through writing them, not at all.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct WriteBatch {
+    operations: Vec<BatchOperation>,
+}
+
+#[derive(Clone, Debug, From, Deserialize, Serialize)]
+pub enum BatchOperation {
+    Set(Set),
+    /// Removing a key that doesn't exist does nothing, rather than failing the batch
+    Rm(Remove),
+}
+
+impl WriteBatch {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Saves the given value to the given key.
+    #[must_use]
+    pub fn set(self, key: impl Into<Key>, value: impl Into<Value>) -> Self {
+        self.push(Set::new(key.into(), value.into()))
+    }
+
+    /// Saves the given value to the given key until `expiry`.
+    #[must_use]
+    pub fn set_with_expiry(
+        self,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        expiry: Expiry,
+    ) -> Self {
+        self.push(Set::new(key.into(), value.into()).with_expiry(expiry))
+    }
+
+    /// Removes the given key, if it exists.
+    #[must_use]
+    pub fn remove(self, key: impl Into<Key>) -> Self {
+        self.push(Remove::new(key.into()))
+    }
+
+    #[must_use]
+    pub fn push(mut self, operation: impl Into<BatchOperation>) -> Self {
+        self.operations.push(operation.into());
+        self
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Returns the writes in the order they are applied.
+    #[must_use]
+    pub fn operations(&self) -> &[BatchOperation] {
+        &self.operations
+    }
+
+    #[must_use]
+    pub fn into_operations(self) -> Vec<BatchOperation> {
+        self.operations
+    }
+
+    /// Returns the value the batch leaves the given key with, if it sets it.
+    #[must_use]
+    pub fn value_of(&self, key: &[u8]) -> Option<&Value> {
+        match self.operations.iter().rev().find(|op| **op.key() == *key)? {
+            BatchOperation::Set(cmd) => Some(&cmd.value),
+            BatchOperation::Rm(_) => None,
+        }
+    }
+}
+
+impl BatchOperation {
+    #[must_use]
+    pub fn key(&self) -> &Key {
+        match self {
+            BatchOperation::Set(cmd) => &cmd.key,
+            BatchOperation::Rm(cmd) => &cmd.key,
+        }
+    }
+}
+
+#[derive(Constructor, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CompareAndSwap {
+    pub key: Key,
+    pub expected: Value,
+    pub new: Value,
+}
+
+#[derive(Constructor, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SetIfAbsent {
+    pub key: Key,
+    pub value: Value,
+}
+
+#[derive(Constructor, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RemoveIfEquals {
+    pub key: Key,
+    pub expected: Value,
+}
+
+/// Maintenance of the store the server runs on.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum AdminCommand {
+    /// Write a backup of the store to a directory on the server, relative to its backup directory
+    Backup(PathBuf),
+    /// Report how big the store is and how much of it is garbage
+    Info,
+}
+
+/// Controls the transaction of a connection.
+///
+/// After `Begin`, the `get`, `set` and `rm` commands sent over the same connection run in the
+/// transaction, until it is committed or rolled back. Closing the connection rolls it back.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum TransactionControl {
+    Begin,
+    Commit,
+    Rollback,
+}
+
+#[derive(Constructor, Clone, Debug, Default, From, Deserialize, Serialize)]
+pub struct Ttl {
+    pub key: Key,
+}
+
+#[derive(Constructor, Clone, Debug, Deserialize, Serialize)]
+pub struct Scan {
+    pub start: Bound<Key>,
+    pub end: Bound<Key>,
+    /// Most entries to return, or all of them if `None`. The server returns fewer if they
+    /// wouldn't fit in a response, so the scan has to be sent again from the last key returned
+    /// until it comes back empty.
+    pub limit: Option<usize>,
+}
+
+/// Returns the range of keys that start with `prefix`.
+#[must_use]
+pub fn prefix_range(prefix: impl Into<Key>) -> (Bound<Key>, Bound<Key>) {
+    let prefix = prefix.into();
+    // The first key after the prefix is the prefix with its last byte that isn't 0xff
+    // incremented, and anything after that byte dropped
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&u8::MAX) {
+        end.pop();
+    }
+    let end = match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            Bound::Excluded(end.into())
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix), end)
+}
+
+/// Returns up to `limit` entries in the range, in key order, stopping before they would no longer
+/// fit in a response frame under `limits`.
+///
+/// The engine is read a page at a time, so a scan without a limit never holds much more than a
+/// response's worth of entries.
+fn scan_within<Engine: KvsEngine>(
+    kv: &Engine,
+    Scan { start, end, limit }: Scan,
+    limits: &Limits,
+) -> Result<Vec<(Key, Value)>> {
+    let mut remaining = limit.unwrap_or(usize::MAX);
+    let mut budget = (limits.max_frame_size as usize).saturating_sub(SCAN_RESPONSE_OVERHEAD);
+    let mut start = start;
+    let mut entries = Vec::new();
+    while remaining > 0 {
+        let page_size = remaining.min(SCAN_PAGE_SIZE);
+        let page = kv.scan((start, end.clone()), page_size)?;
+        let last_page = page.len() < page_size;
+        for (key, value) in page {
+            let size = key.len() + value.len() + SCAN_ENTRY_OVERHEAD;
+            if size > budget {
+                if entries.is_empty() {
+                    return Err(KvsError::LimitExceeded(format!(
+                        "entry of {size} bytes is over the response limit of {budget}"
+                    )));
+                }
+                return Ok(entries);
+            }
+            budget -= size;
+            remaining -= 1;
+            entries.push((key, value));
+        }
+        match entries.last() {
+            Some((key, _)) if !last_page => start = Bound::Excluded(key.clone()),
+            _ => break,
+        }
+    }
+    Ok(entries)
+}
+
 impl Command {
-    pub fn process<Engine: KvsEngine>(self, kv: &Engine) -> CommandResponse {
+    /// Runs the command on the engine. `limits` bound the responses it returns.
+    pub fn process<Engine: KvsEngine>(self, kv: &Engine, limits: &Limits) -> CommandResponse {
         match self {
-            Command::Set(Set { key, value }) => kv.set(key, value).into(),
+            Command::Set(Set {
+                key,
+                value,
+                expiry: None,
+            }) => kv.set(key, value).into(),
+            Command::Set(Set {
+                key,
+                value,
+                expiry: Some(expiry),
+            }) => kv.set_with_expiry(key, value, expiry).into(),
             Command::Get(Get { key }) => match kv.get(key) {
                 Ok(data) => match data {
                     None => Err(KeyNotFound),
@@ -170,14 +774,68 @@ impl Command {
             }
             .into(),
             Command::Rm(Remove { key }) => kv.remove(key).into(),
+            Command::Scan(scan) => scan_within(kv, scan, limits).into(),
+            Command::Ttl(Ttl { key }) => kv.ttl(key).into(),
+            Command::Cas(CompareAndSwap { key, expected, new }) => {
+                kv.compare_and_swap(key, expected, new).into()
+            }
+            Command::SetIfAbsent(SetIfAbsent { key, value }) => kv.set_if_absent(key, value).into(),
+            Command::RmIfEquals(RemoveIfEquals { key, expected }) => {
+                kv.remove_if_equals(key, expected).into()
+            }
+            Command::Batch(batch) => kv.write_batch(batch).into(),
+            // Transactions need a connection to live on, so the server handles them itself
+            Command::Transaction(_) => Result::<()>::Err(NoTransaction).into(),
+            // Backups are confined to the directory the server was given, so the server handles
+            // them itself
+            Command::Admin(AdminCommand::Backup(_)) => Result::<()>::Err(BackupsDisabled).into(),
+            Command::Admin(AdminCommand::Info) => kv.stats().into(),
+        }
+    }
+
+    /// Runs the command in the given transaction.
+    ///
+    /// Only `get`, `set` without an expiry and `rm` can run in a transaction. Unlike outside of
+    /// one, `get` returns `None` rather than an error for a key that doesn't exist.
+    pub fn process_in<T: Transaction>(self, transaction: &mut T) -> CommandResponse {
+        match self {
+            Command::Get(Get { key }) => transaction.get(key).into(),
+            Command::Set(Set {
+                key,
+                value,
+                expiry: None,
+            }) => transaction.set(key, value).into(),
+            Command::Rm(Remove { key }) => transaction.remove(key).into(),
+            Command::Set(_)
+            | Command::Scan(_)
+            | Command::Ttl(_)
+            | Command::Cas(_)
+            | Command::SetIfAbsent(_)
+            | Command::RmIfEquals(_)
+            | Command::Batch(_)
+            | Command::Transaction(_)
+            | Command::Admin(_) => Result::<()>::Err(GeneralError(
+                "Command can't run in a transaction".to_owned(),
+            ))
+            .into(),
         }
     }
 
+    /// Returns the value the command saves to the given key, if any.
     #[must_use]
-    pub fn value(&self) -> Option<&Value> {
+    pub fn value_of(&self, key: &[u8]) -> Option<&Value> {
         match self {
-            Command::Set(cmd) => Some(&cmd.value),
-            Command::Rm(_) | Command::Get(_) => None,
+            Command::Set(cmd) => (*cmd.key == *key).then_some(&cmd.value),
+            Command::Batch(batch) => batch.value_of(key),
+            Command::Rm(_)
+            | Command::Get(_)
+            | Command::Scan(_)
+            | Command::Ttl(_)
+            | Command::Cas(_)
+            | Command::SetIfAbsent(_)
+            | Command::RmIfEquals(_)
+            | Command::Transaction(_)
+            | Command::Admin(_) => None,
         }
     }
 }
@@ -47,7 +47,7 @@ fn spawn_receiver(rx: ReceiverManager) {
     });
     if let Err(e) = result {
         error!("Failed to spawn a new thread: {}", e);
-    };
+    }
 }
 
 impl Drop for ReceiverManager {
@@ -1,7 +1,9 @@
 use assert_cmd::prelude::*;
-use predicates::str::{contains, is_empty};
+use kvs::{shared::LOG_DIRECTORY_PREFIX, KvStore, KvsEngine};
+use predicates::str::{contains, is_empty, starts_with};
 use std::{
     fs::{self, File},
+    io::{Read, Write},
     net::{SocketAddr, TcpStream},
     process::Command,
     str::FromStr,
@@ -155,6 +157,21 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server` should refuse sync policies of zero
+#[test]
+fn server_cli_invalid_sync_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    for policy in ["0ms", "0b"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--sync", policy])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("Invalid sync policy"));
+    }
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -168,6 +185,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     println!("[{}]", content);
@@ -189,6 +207,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -208,6 +227,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -230,7 +250,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         let _recv = receiver.recv(); // wait for main thread to finish
         thread::sleep(Duration::from_secs(1)); // Give the server time to persist data
         child.kill().expect("server exited before killed");
-        let _ = child.wait(); // Wait for the process to exit properly before restarting later
+        child.wait().expect("failed to wait on server");
     });
     wait_for_server_to_start(addr);
 
@@ -312,6 +332,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     wait_for_server_to_start(addr);
     Command::cargo_bin("kvs-client")
@@ -332,6 +353,492 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+// Values can be read from a file or stdin and printed encoded
+#[test]
+fn cli_binary_values() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+
+    let value_path = temp_dir.path().join("value");
+    fs::write(&value_path, [0, 159, 146, 150, 255]).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key1",
+            "--file",
+            value_path.to_str().unwrap(),
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--output", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("009f9296ff\n");
+
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "--file", "-", "--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin("from stdin")
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--output", "base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("ZnJvbSBzdGRpbg==\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Keys are listed in order, filtered by prefix or range, even when the server only returns a
+// couple of them at a time
+#[test]
+fn cli_scan() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--max-frame-size", "128"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+
+    for key in ["b2", "a1", "b1", "c1"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, &format!("value-{key}"), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--prefix", "b", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b1\tvalue-b1\nb2\tvalue-b2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--start", "a2", "--limit", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b1\tvalue-b1\nb2\tvalue-b2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--end", "b1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a1\tvalue-a1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a1\tvalue-a1\nb1\tvalue-b1\nb2\tvalue-b2\nc1\tvalue-c1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_ttl() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "1h", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(starts_with("59m"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("none\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ttl", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "soon", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_batch() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key3", "value3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let batch = temp_dir.path().join("batch");
+    fs::write(
+        &batch,
+        "# Comments are skipped\nset key1 value 1\n\nset key2 value2\nrm key3\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", batch.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue 1\nkey2\tvalue2\n");
+
+    // Nothing is applied when a line is invalid
+    let invalid_batch = temp_dir.path().join("invalid_batch");
+    fs::write(&invalid_batch, "rm key1\nset key2\n").unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["batch", invalid_batch.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("line 2"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value 1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_backup_and_restore() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let store_dir = temp_dir.path().join("store");
+    fs::create_dir(&store_dir).unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--backup-dir"])
+        .arg(temp_dir.path().join("backups"))
+        .current_dir(&store_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // Relative to the backup directory of the server, whatever the directory of the client
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "backup", "backup", "--addr", addr])
+        .current_dir(&store_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+    // Nothing outside of the backup directory can be written to
+    for dir in ["../escaped", "/tmp/escaped", "backup/../../escaped"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["admin", "backup", dir, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("Invalid backup path"));
+    }
+    assert!(!temp_dir.path().join("escaped").exists());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--restore"])
+        .arg(temp_dir.path().join("backups").join("backup"))
+        .current_dir(&store_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\n");
+    // Backups are disabled without a backup directory
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("disabled"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `kvs-admin migrate` should convert the store of a server to another engine
+#[test]
+fn cli_migrate_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already uses"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Migrated 1 entries"));
+    // The store is sled now, whatever engine it is said to be
+    for args in [
+        &["migrate", "--from", "kvs", "--to", "sled"][..],
+        &["export", "dump", "--engine", "kvs"],
+        &["import", "dump", "--engine", "kvs"],
+    ] {
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("isn't a kvs store"));
+    }
+
+    let addr = "127.0.0.1:4013";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `kvs-inspect` should report the logs of a store, fail on damage and write a repaired copy
+#[test]
+fn cli_inspect_and_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let store_dir = temp_dir.path().join(LOG_DIRECTORY_PREFIX);
+    fs::create_dir(&store_dir).unwrap();
+    let store = KvStore::open(&store_dir).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key1", "value2").unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg("--records")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("2 records (1 live, 1 stale)"))
+        .stdout(contains("stale set key1"));
+
+    // Flip a byte inside the payload of the first record
+    let log_file = store_dir.join("0");
+    let mut contents = fs::read(&log_file).unwrap();
+    contents[12] ^= 0xFF;
+    fs::write(&log_file, contents).unwrap();
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("damaged at offset 0"));
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .args(["--repair", "repaired"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .args(["--dir", "repaired"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("log 0 (active): 0 bytes"));
+}
+
+// `kvs-client info` should show the stats of the store the server runs on
+#[test]
+fn cli_info() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+    for key in ["key1", "key2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["info", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(starts_with("engine\tkvs\nkeys\t2\n"))
+        .stdout(contains("compactions\t0\n"))
+        .stdout(contains("log 0\t"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `kvs-server --protocol resp` should speak to Redis clients
+#[test]
+fn cli_resp_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--protocol", "resp", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server_to_start(addr);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\nGET key1\r\n")
+        .unwrap();
+    let expected = b"+OK\r\n$6\r\nvalue1\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, expected);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
 fn wait_for_server_to_start(addr: &str) {
     for _ in 1..=5 {
         let stream = TcpStream::connect_timeout(
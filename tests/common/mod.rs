@@ -1,7 +1,8 @@
 use crossbeam_utils::Backoff;
 use fake::Fake;
 use kvs::{
-    server::KvsServer,
+    protocol::Limits,
+    server::{KvsServer, Protocol},
     shared::{Command, Set},
     thread_pool::ThreadPool,
     KvsEngine,
@@ -27,7 +28,7 @@ pub fn generate_write_commands(
         let key = format!("key{}_{}", i, (min_length..=max_length).fake::<String>());
         let value = format!("value{}_{}", i, (min_length..=max_length).fake::<String>());
         list.insert(key.clone(), value.clone());
-        let command = Command::from(Set::new(key, value));
+        let command = Command::from(Set::new(key.into(), value.into()));
         list_vec.push(command);
     }
     (list, list_vec)
@@ -70,12 +71,63 @@ where
     Pool: ThreadPool,
 {
     pub fn new(address: SocketAddr, cpus: Option<usize>) -> Self {
+        Self::with_protocol(address, cpus, Protocol::Kvs)
+    }
+
+    pub fn with_protocol(address: SocketAddr, cpus: Option<usize>, protocol: Protocol) -> Self {
+        Self::configured(address, cpus, |server| server.with_protocol(protocol))
+    }
+
+    #[allow(dead_code)]
+    /// Serves the HTTP gateway on `http_address` as well as the binary protocol on `address`.
+    pub fn with_http(address: SocketAddr, cpus: Option<usize>, http_address: SocketAddr) -> Self {
+        Self::configured(address, cpus, |server| server.with_http(http_address))
+    }
+
+    #[allow(dead_code)]
+    /// Serves `protocol` with `limits` instead of the default ones.
+    pub fn with_limits(
+        address: SocketAddr,
+        cpus: Option<usize>,
+        protocol: Protocol,
+        limits: Limits,
+    ) -> Self {
+        Self::configured(address, cpus, |server| {
+            server.with_protocol(protocol).with_limits(limits)
+        })
+    }
+
+    #[allow(dead_code)]
+    /// Serves the HTTP gateway as well, and no more than `max_connections` connections at once.
+    pub fn with_max_connections(
+        address: SocketAddr,
+        cpus: Option<usize>,
+        http_address: SocketAddr,
+        max_connections: usize,
+    ) -> Self {
+        Self::configured(address, cpus, |server| {
+            server
+                .with_http(http_address)
+                .with_max_connections(max_connections)
+        })
+    }
+
+    fn configured(
+        address: SocketAddr,
+        cpus: Option<usize>,
+        configure: impl FnOnce(KvsServer<Engine, Pool>) -> KvsServer<Engine, Pool>,
+    ) -> Self {
         let temp_dir = Arc::new(TempDir::new().unwrap());
         let engine = Engine::open(temp_dir.path()).unwrap();
         let cpus = cpus.unwrap_or(num_cpus::get());
         let pool = Pool::new(cpus as u32).unwrap();
 
-        let server = Arc::new(KvsServer::new(address, engine, pool, temp_dir.path()));
+        let server = Arc::new(configure(KvsServer::new(
+            address,
+            engine,
+            pool,
+            temp_dir.path(),
+        )));
         Self { server, temp_dir }
     }
 
@@ -1,17 +1,49 @@
-use kvs::{shared::LOG_ROTATION_MIN_SIZE_BYTES, KvStore, KvsEngine};
+use kvs::{
+    compaction::{
+        CompactionPolicy, GarbageThresholdPolicy, LogUsage, SizeTieredPolicy, StaleRatioPolicy,
+    },
+    shared::{Expiry, WriteBatch, LOG_ROTATION_MIN_SIZE_BYTES},
+    KvStore, KvStoreOptions, KvsEngine, KvsError, Transaction,
+};
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 // Placing in separate file to prevent race condition of
 // other tests initializing LOG_ROTATION_MIN_SIZE_BYTES first.
+const LOG_ROTATION_MIN_SIZE_BYTES_TEST: u64 = 256 * 1024;
+
+fn init_log_rotation_size() {
+    let size = LOG_ROTATION_MIN_SIZE_BYTES.get_or_init(|| LOG_ROTATION_MIN_SIZE_BYTES_TEST);
+    assert_eq!(
+        *size, LOG_ROTATION_MIN_SIZE_BYTES_TEST,
+        "Failed to initialize 'LOG_ROTATION_MIN_SIZE_BYTES'"
+    );
+}
+
+fn log_ids(path: &Path) -> Vec<u64> {
+    let mut ids = fs::read_dir(path)
+        .expect("unable to read log directory")
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> kvs::Result<()> {
-    const LOG_ROTATION_MIN_SIZE_BYTES_TEST: u64 = 256 * 1024;
-    LOG_ROTATION_MIN_SIZE_BYTES
-        .set(LOG_ROTATION_MIN_SIZE_BYTES_TEST)
-        .expect("Failed to initialize 'LOG_ROTATION_MIN_SIZE_BYTES'");
+    init_log_rotation_size();
 
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
@@ -47,10 +79,601 @@ fn compaction() -> kvs::Result<()> {
         let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get(key)?, Some(format!("{}", iter).into()));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
+
+// Stale logs should be kept while compaction is paused and removed once it is resumed
+#[test]
+fn pause_and_resume_compaction() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.compaction().pause()?;
+
+    for iter in 0..40 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.compaction().wait()?;
+    let logs_before_compaction = log_ids(temp_dir.path());
+    assert!(logs_before_compaction.len() > 2, "No log rotation detected");
+
+    store.compaction().resume()?;
+    store.compaction().wait()?;
+    let logs_after_compaction = log_ids(temp_dir.path());
+    eprintln!(
+        "DEBUG {:?} {:?}",
+        logs_before_compaction, logs_after_compaction
+    );
+    assert!(logs_after_compaction.len() < logs_before_compaction.len());
+    assert!(!logs_after_compaction.contains(&logs_before_compaction[0]));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("39".into()));
+    }
+
+    Ok(())
+}
+
+// Writes after reopening a store whose active log is still empty should be read back from where
+// they were written
+#[test]
+fn write_after_reopening_with_an_empty_active_log() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.compaction().pause()?;
+    for iter in 0..40 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.compaction().resume()?;
+    store.compaction().wait()?;
+
+    // Compaction moves writes to a new log, which nothing has been written to yet
+    let active_log_id = *log_ids(temp_dir.path()).last().unwrap();
+    let active_log = temp_dir.path().join(active_log_id.to_string());
+    assert_eq!(fs::metadata(&active_log)?.len(), 0);
+
+    // Without hint files, the sealed logs are replayed record by record
+    drop(store);
+    for log_id in log_ids(temp_dir.path()) {
+        let _ = fs::remove_file(temp_dir.path().join(format!("{log_id}.hint")));
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("new_key", "value")?;
+    assert_eq!(store.get("new_key")?, Some("value".into()));
+    assert_eq!(store.get("key1")?, Some("39".into()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("new_key")?, Some("value".into()));
+
+    Ok(())
+}
+
+// Stats should count compactions and show the garbage they reclaimed
+#[test]
+fn report_compactions_in_stats() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.compaction().pause()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, Some(0));
+    assert_eq!(stats.last_compaction, None);
+
+    for iter in 0..40 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.compaction().wait()?;
+    let before = store.stats()?;
+    assert_eq!(before.keys, 1000);
+    assert!(before.logs.len() > 2, "No log rotation detected");
+    let sealed = &before.logs[0];
+    assert_eq!(sealed.live_bytes + sealed.stale_bytes, sealed.size);
+    assert!(sealed.stale_bytes > 0);
+
+    store.compaction().resume()?;
+    store.compaction().wait()?;
+    let after = store.stats()?;
+    assert_eq!(after.keys, 1000);
+    assert_eq!(after.live_bytes, before.live_bytes);
+    assert!(after.compactions >= Some(1));
+    assert!(after.last_compaction.is_some());
+    let stale_bytes =
+        |stats: &kvs::EngineStats| -> u64 { stats.logs.iter().map(|log| log.stale_bytes).sum() };
+    assert!(stale_bytes(&after) < stale_bytes(&before));
+    Ok(())
+}
+
+// Live entries and removals should survive a compaction triggered while writes continue
+#[test]
+fn trigger_compaction_while_writing() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.compaction().pause()?;
+
+    // Fill the first logs with keys that are rarely updated, then bury them under updates
+    for key_id in 0..10_000 {
+        store.set(format!("cold{}", key_id), "cold".to_owned())?;
+    }
+    for key_id in (0..10_000).step_by(2) {
+        store.remove(format!("cold{}", key_id))?;
+    }
+    for iter in 0..20 {
+        for key_id in 0..1000 {
+            store.set(format!("hot{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    store.compaction().resume()?;
+    store.compaction().trigger()?;
+    for key_id in 0..1000 {
+        store.set(format!("hot{}", key_id), "latest".to_owned())?;
+    }
+    store.compaction().wait()?;
+
+    let check = |store: &KvStore| -> kvs::Result<()> {
+        for key_id in 0..10_000 {
+            let expected = (key_id % 2 == 1).then(|| "cold".into());
+            assert_eq!(store.get(format!("cold{}", key_id))?, expected);
+        }
+        for key_id in 0..1000 {
+            assert_eq!(store.get(format!("hot{}", key_id))?, Some("latest".into()));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// Reads racing with compaction should find keys whose logs are removed under them
+#[test]
+fn read_while_compacting() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("cold{:04}", key_id), "cold".to_owned())?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            let done = done.clone();
+            thread::spawn(move || -> kvs::Result<()> {
+                while !done.load(Ordering::Relaxed) {
+                    for key_id in 0..1000 {
+                        let value = store.get(format!("cold{:04}", key_id))?;
+                        assert_eq!(value, Some("cold".into()));
+                    }
+                    let entries = store.scan_prefix("cold", usize::MAX)?;
+                    assert_eq!(entries.len(), 1000);
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Keep rotating logs, so compaction keeps moving the cold keys and removing their logs
+    for iter in 0..60 {
+        for key_id in 0..1000 {
+            store.set(format!("hot{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.compaction().wait()?;
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().expect("reader panicked")?;
+    }
+    Ok(())
+}
+
+// Writes made in batches should be kept by compaction, whether or not they were overwritten
+#[test]
+fn compact_batches() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.compaction().pause()?;
+
+    for iter in 0..60 {
+        let batch = (0..1000).fold(WriteBatch::new(), |batch, key_id| {
+            // Only every other key is written again after the first batch
+            if iter == 0 || key_id % 2 == 0 {
+                batch.set(format!("key{}", key_id), format!("{}", iter))
+            } else {
+                batch
+            }
+        });
+        store.write_batch(batch)?;
+    }
+    let logs_before_compaction = log_ids(temp_dir.path());
+    assert!(logs_before_compaction.len() > 2, "No log rotation detected");
+
+    store.compaction().resume()?;
+    store.compaction().trigger()?;
+    store.compaction().wait()?;
+    assert_ne!(log_ids(temp_dir.path()), logs_before_compaction);
+
+    let check = |store: &KvStore| -> kvs::Result<()> {
+        for key_id in 0..1000 {
+            let expected = if key_id % 2 == 0 { "59" } else { "0" };
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(expected.into()));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// Snapshots should keep the logs they read from until they are dropped
+#[test]
+fn keep_logs_pinned_by_snapshots() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.compaction().pause()?;
+
+    for iter in 0..60 {
+        let batch = (0..1000).fold(WriteBatch::new(), |batch, key_id| {
+            if iter == 0 || key_id % 2 == 0 {
+                batch.set(format!("key{}", key_id), format!("{}", iter))
+            } else {
+                batch
+            }
+        });
+        store.write_batch(batch)?;
+    }
+    let snapshot = store.snapshot()?;
+    let mut transaction = store.begin()?;
+    store.set("key1", "changed")?;
+    let logs_before_compaction = log_ids(temp_dir.path());
+
+    store.compaction().resume()?;
+    store.compaction().trigger()?;
+    store.compaction().wait()?;
+    // Only the files are kept, the store doesn't use them anymore
+    assert_eq!(log_ids(temp_dir.path())[0], logs_before_compaction[0]);
+    assert_eq!(store.get("key1")?, Some("changed".into()));
+
+    assert_eq!(snapshot.get("key1")?, Some("0".into()));
+    assert_eq!(snapshot.get("key2")?, Some("59".into()));
+    assert_eq!(snapshot.iter().count(), 1000);
+    assert_eq!(transaction.get("key1")?, Some("0".into()));
+    transaction.set("key1", "in transaction")?;
+    assert!(matches!(
+        transaction.commit(),
+        Err(KvsError::TransactionConflict)
+    ));
+
+    drop(snapshot);
+    assert!(!log_ids(temp_dir.path()).contains(&logs_before_compaction[0]));
+    assert_eq!(store.get("key1")?, Some("changed".into()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("changed".into()));
+    assert_eq!(store.get("key3")?, Some("0".into()));
+    Ok(())
+}
+
+// Backups should share the sealed logs with the store and hold every log it had
+#[test]
+fn backup_sealed_logs() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let backup = temp_dir.path().join("backup");
+    fs::create_dir(&path)?;
+    let store = KvStore::open(&path)?;
+    store.compaction().pause()?;
+    for iter in 0..60 {
+        let batch = (0..1000).fold(WriteBatch::new(), |batch, key_id| {
+            batch.set(format!("key{}", key_id), format!("{}", iter))
+        });
+        store.write_batch(batch)?;
+    }
+    let logs = log_ids(&path);
+    assert!(logs.len() > 2, "No log rotation detected");
+
+    store.backup_to(&backup)?;
+    assert_eq!(log_ids(&backup), logs);
+    let sealed_log = fs::metadata(path.join(logs[0].to_string()))?;
+    assert!(sealed_log.nlink() > 1, "Sealed log wasn't hard linked");
+    // Compacting the store doesn't change the backup
+    store.compaction().resume()?;
+    store.compaction().trigger()?;
+    store.compaction().wait()?;
+    drop(store);
+
+    let restored = temp_dir.path().join("restored");
+    KvStore::restore(&backup, &restored)?;
+    let store = KvStore::open(&restored)?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("59".into()));
+    }
+    Ok(())
+}
+
+/// Compacts every sealed log except the first one.
+#[derive(Debug)]
+struct KeepFirstLogPolicy;
+
+impl CompactionPolicy for KeepFirstLogPolicy {
+    fn select(&self, logs: &[LogUsage]) -> Vec<u64> {
+        logs.iter()
+            .map(|log| log.id)
+            .filter(|id| *id != 0)
+            .collect()
+    }
+}
+
+// Expired keys should be dropped by compaction without older values coming back
+#[test]
+fn drop_expired_keys_during_compaction() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_policy(KeepFirstLogPolicy);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.compaction().pause()?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    for key_id in 0..10_000 {
+        store.set(format!("other{}", key_id), "other".to_owned())?;
+    }
+    for key_id in 0..1000 {
+        store.set_with_expiry(format!("key{}", key_id), "new".to_owned(), Expiry::At(1))?;
+    }
+    for key_id in 10_000..20_000 {
+        store.set(format!("other{}", key_id), "other".to_owned())?;
+    }
+    let logs_before_compaction = log_ids(temp_dir.path());
+    assert!(logs_before_compaction.len() > 2, "No log rotation detected");
+
+    store.compaction().resume()?;
+    store.compaction().trigger()?;
+    store.compaction().wait()?;
+    let logs_after_compaction = log_ids(temp_dir.path());
+    assert!(logs_after_compaction.contains(&0));
+    assert!(!logs_after_compaction.contains(&1));
+
+    let check = |store: &KvStore| -> kvs::Result<()> {
+        for key_id in 0..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, None);
+        }
+        for key_id in 0..20_000 {
+            assert_eq!(store.get(format!("other{}", key_id))?, Some("other".into()));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+fn usage(id: u64, live_bytes: u64, stale_bytes: u64) -> LogUsage {
+    LogUsage {
+        id,
+        live_bytes,
+        stale_bytes,
+    }
+}
+
+#[test]
+fn stale_ratio_policy() {
+    let logs = [
+        usage(0, 40, 60),
+        usage(1, 60, 40),
+        usage(2, 0, 100),
+        usage(3, 100, 0),
+    ];
+    assert_eq!(StaleRatioPolicy::new(50).select(&logs), vec![0, 2]);
+    assert_eq!(StaleRatioPolicy::new(30).select(&logs), vec![0, 1, 2]);
+}
+
+#[test]
+fn garbage_threshold_policy() {
+    let logs = [usage(0, 40, 60), usage(1, 100, 0), usage(2, 60, 40)];
+    assert!(GarbageThresholdPolicy::new(101).select(&logs).is_empty());
+    assert_eq!(GarbageThresholdPolicy::new(100).select(&logs), vec![0, 2]);
+}
+
+#[test]
+fn size_tiered_policy() {
+    let logs = [
+        usage(0, 1000, 0),
+        usage(1, 90, 10),
+        usage(2, 10, 100),
+        usage(3, 1100, 100),
+        usage(4, 80, 0),
+    ];
+    let mut selected = SizeTieredPolicy::new(3, 50, 150).select(&logs);
+    selected.sort_unstable();
+    assert_eq!(selected, vec![1, 2, 4]);
+    assert!(SizeTieredPolicy::new(4, 50, 150).select(&logs).is_empty());
+}
+
+// Logs that are mostly stale should have their live entries migrated before they are removed
+#[test]
+fn compact_logs_selected_by_policy() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compaction_policy(StaleRatioPolicy::new(50));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.compaction().pause()?;
+
+    for key_id in 0..10_000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let first_log = log_ids(temp_dir.path())[0];
+    for key_id in (0..10_000).filter(|key_id| key_id % 4 != 0) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(
+        log_ids(temp_dir.path()).len() > 2,
+        "No log rotation detected"
+    );
+
+    store.compaction().resume()?;
+    store.compaction().trigger()?;
+    store.compaction().wait()?;
+    assert!(!log_ids(temp_dir.path()).contains(&first_log));
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..10_000 {
+        let expected = (key_id % 4 == 0).then(|| "value".into());
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+/// Remembers the usage it was last given, without compacting anything.
+#[derive(Clone, Debug, Default)]
+struct RecordUsagePolicy(Arc<Mutex<Vec<LogUsage>>>);
+
+impl RecordUsagePolicy {
+    fn usage(&self) -> Vec<LogUsage> {
+        self.0.lock().expect("usage poisoned").clone()
+    }
+}
+
+impl CompactionPolicy for RecordUsagePolicy {
+    fn select(&self, logs: &[LogUsage]) -> Vec<u64> {
+        *self.0.lock().expect("usage poisoned") = logs.to_vec();
+        Vec::new()
+    }
+}
+
+// The usage compaction policies are given should count every batch record once, as stats do,
+// both while the store is open and after it is reopened from hint files
+#[test]
+fn count_batch_records_once() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let policy = RecordUsagePolicy::default();
+    let options = KvStoreOptions::default().compaction_policy(policy.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.compaction().pause()?;
+
+    for batch_id in 0..100 {
+        let batch = (0..4).fold(WriteBatch::new(), |batch, key_id| {
+            batch.set(format!("key{}-{}", batch_id, key_id), "value".repeat(20))
+        });
+        // A key that is removed by the batch that sets it leaves nothing live behind
+        store.write_batch(batch.set("removed", "value").remove("removed"))?;
+    }
+    // Overwriting some of the keys of a batch leaves the record live, all of them makes it stale
+    for batch_id in 0..100 {
+        let overwritten = if batch_id % 2 == 0 { 0..3 } else { 0..4 };
+        for key_id in overwritten {
+            store.set(format!("key{}-{}", batch_id, key_id), "new".to_owned())?;
+        }
+    }
+    store.write_batch(WriteBatch::new().remove("key0-0").remove("key1-0"))?;
+    let mut filler = 0;
+    while log_ids(temp_dir.path()).len() < 2 {
+        store.set(format!("filler{}", filler), "value".repeat(20))?;
+        filler += 1;
+    }
+
+    let check = |store: &KvStore| -> kvs::Result<()> {
+        store.compaction().resume()?;
+        store.compaction().trigger()?;
+        store.compaction().wait()?;
+        let stats = store.stats()?;
+        let usage = policy.usage();
+        assert_eq!(usage.len(), stats.logs.len() - 1);
+        for (usage, log) in usage.iter().zip(&stats.logs) {
+            assert_eq!(usage.id, log.log_id);
+            assert_eq!(usage.live_bytes, log.live_bytes);
+            assert_eq!(usage.stale_bytes, log.stale_bytes);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    assert!(temp_dir.path().join("0.hint").exists());
+    drop(store);
+    let options = KvStoreOptions::default().compaction_policy(policy.clone());
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+// Sealed logs should get a hint file that is used instead of the log on open, and the log should
+// be replayed in full if the hint is damaged
+#[test]
+fn hint_files_are_used_on_open() -> kvs::Result<()> {
+    init_log_rotation_size();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10_000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compaction().wait()?;
+    let first_log = log_ids(temp_dir.path())[0];
+    let hint_file = temp_dir.path().join(format!("{}.hint", first_log));
+    assert!(hint_file.exists());
+    drop(store);
+
+    // Damage the last value in the sealed log: the hint still lets the store open
+    let log_file = temp_dir.path().join(first_log.to_string());
+    let mut log = fs::read(&log_file)?;
+    let last_byte = log.len() - 1;
+    log[last_byte] ^= 0xFF;
+    fs::write(&log_file, &log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".into()));
+    drop(store);
+
+    // Without a valid hint the log is replayed and the damage is found
+    let mut hint = fs::read(&hint_file)?;
+    hint[10] ^= 0xFF;
+    fs::write(&hint_file, &hint)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(kvs::KvsError::CorruptLog(..))
+    ));
+
+    // Once the log is repaired it opens from the log itself
+    log[last_byte] ^= 0xFF;
+    fs::write(&log_file, &log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10_000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id).into())
+        );
+    }
+
+    Ok(())
+}
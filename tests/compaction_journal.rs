@@ -0,0 +1,67 @@
+use kvs::{
+    compaction::{CompactionAction, CompactionJournal, CompactionList, CompactionPhase},
+    KvStore, KvsEngine, Result,
+};
+use std::{collections::HashMap, fs, path::Path};
+use tempfile::TempDir;
+
+fn write_log(path: &Path, data: &[(&str, &str)]) -> Result<Vec<u8>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in data {
+        store.set((*key).to_owned(), (*value).to_owned())?;
+    }
+    drop(store);
+    let log = fs::read(temp_dir.path().join("0"))?;
+    fs::write(path, &log)?;
+    Ok(log)
+}
+
+// Should delete the remaining stale logs if the process died while removing them
+#[test]
+fn finish_compaction_interrupted_while_removing_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_log(
+        &temp_dir.path().join("0"),
+        &[("key1", "old1"), ("key2", "old2")],
+    )?;
+    write_log(
+        &temp_dir.path().join("1"),
+        &[("key1", "new1"), ("key2", "new2")],
+    )?;
+    let ids = HashMap::from([(0, CompactionAction::Remove)]);
+    CompactionJournal::new(CompactionPhase::Removing, CompactionList::new(ids))
+        .save(temp_dir.path())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("0").exists());
+    assert!(!temp_dir.path().join("compaction").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("new1".into()));
+    assert_eq!(store.get("key2".to_owned())?, Some("new2".into()));
+
+    Ok(())
+}
+
+// Should delete the partially written output log and keep the source logs if the process died
+// while migrating entries
+#[test]
+fn roll_back_compaction_interrupted_while_migrating() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = write_log(&temp_dir.path().join("0"), &[("key1", "value1")])?;
+    // Half of the migrated entry made it into the output log
+    fs::write(temp_dir.path().join("1"), &source[..source.len() / 2])?;
+    write_log(&temp_dir.path().join("2"), &[("key2", "value2")])?;
+
+    let ids = HashMap::from([(0, CompactionAction::Migrate)]);
+    let phase = CompactionPhase::Migrating { output_log_id: 1 };
+    CompactionJournal::new(phase, CompactionList::new(ids)).save(temp_dir.path())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(temp_dir.path().join("0").exists());
+    assert!(!temp_dir.path().join("1").exists());
+    assert!(!temp_dir.path().join("compaction").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".into()));
+
+    Ok(())
+}
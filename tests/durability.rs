@@ -0,0 +1,257 @@
+use kvs::{
+    durability::{FileLayer, SyncPolicy},
+    KvStore, KvStoreOptions, KvsEngine,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tempfile::TempDir;
+
+/// Records how much of each log has been synced, so a crash can be simulated by dropping
+/// everything that was not. Syncs can also be slowed down or made to fail.
+#[derive(Clone, Debug, Default)]
+struct FaultyFileLayer {
+    synced: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    syncs: Arc<AtomicUsize>,
+    fail: Arc<AtomicBool>,
+    delay: Duration,
+}
+
+impl FaultyFileLayer {
+    fn with_delay(delay: Duration) -> Self {
+        Self {
+            delay,
+            ..Self::default()
+        }
+    }
+
+    fn syncs(&self) -> usize {
+        self.syncs.load(Ordering::SeqCst)
+    }
+
+    fn synced_len(&self, path: &Path) -> u64 {
+        let synced = self.synced.lock().expect("synced lengths poisoned");
+        synced.get(path).copied().unwrap_or_default()
+    }
+
+    /// Truncates every log to the length that was last synced, as a power failure would.
+    fn crash(&self, path: &Path) {
+        for entry in fs::read_dir(path).expect("unable to read log directory") {
+            let log = entry.expect("unable to read log entry").path();
+            let is_log = log
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.parse::<u64>().is_ok());
+            if is_log {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(&log)
+                    .expect("unable to open log");
+                file.set_len(self.synced_len(&log))
+                    .expect("unable to truncate log");
+            }
+        }
+    }
+}
+
+impl FileLayer for FaultyFileLayer {
+    fn sync_data(&self, path: &Path, file: &File) -> io::Result<()> {
+        if self.fail.load(Ordering::SeqCst) {
+            return Err(io::Error::other("injected sync failure"));
+        }
+        thread::sleep(self.delay);
+        file.sync_data()?;
+        let len = file.metadata()?.len();
+        self.synced
+            .lock()
+            .expect("synced lengths poisoned")
+            .insert(path.to_owned(), len);
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+fn open(path: &Path, policy: SyncPolicy, layer: &FaultyFileLayer) -> kvs::Result<KvStore> {
+    let options = KvStoreOptions::default()
+        .sync_policy(policy)
+        .file_layer(layer.clone());
+    KvStore::open_with_options(path, options)
+}
+
+#[test]
+fn parse_sync_policy() {
+    assert_eq!("never".parse::<SyncPolicy>().ok(), Some(SyncPolicy::Never));
+    assert_eq!(
+        "always".parse::<SyncPolicy>().ok(),
+        Some(SyncPolicy::Always)
+    );
+    assert_eq!(
+        "100ms".parse::<SyncPolicy>().ok(),
+        Some(SyncPolicy::Interval(Duration::from_millis(100)))
+    );
+    assert_eq!(
+        "4096b".parse::<SyncPolicy>().ok(),
+        Some(SyncPolicy::Bytes(4096))
+    );
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+    assert!("10s".parse::<SyncPolicy>().is_err());
+    assert!("0ms".parse::<SyncPolicy>().is_err());
+    assert!("0b".parse::<SyncPolicy>().is_err());
+    assert!("-5ms".parse::<SyncPolicy>().is_err());
+    assert_eq!(
+        SyncPolicy::Interval(Duration::from_millis(5)).to_string(),
+        "5ms"
+    );
+}
+
+// Every acknowledged write survives a crash when syncing every write.
+#[test]
+fn always_survives_crash() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = FaultyFileLayer::default();
+    let store = open(temp_dir.path(), SyncPolicy::Always, &layer)?;
+    for key_id in 0..100 {
+        store.set(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(layer.syncs() > 0);
+    drop(store);
+
+    layer.crash(temp_dir.path());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{key_id}"))?,
+            Some(format!("value{key_id}").into())
+        );
+    }
+    Ok(())
+}
+
+// Writes are never synced, so a crash loses them.
+#[test]
+fn never_does_not_sync() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = FaultyFileLayer::default();
+    let store = open(temp_dir.path(), SyncPolicy::Never, &layer)?;
+    for key_id in 0..100 {
+        store.set(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    assert_eq!(layer.syncs(), 0);
+    drop(store);
+
+    layer.crash(temp_dir.path());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, None);
+    Ok(())
+}
+
+// No more than the configured number of bytes is ever left unsynced after a write.
+#[test]
+fn bytes_policy_bounds_unsynced_data() -> kvs::Result<()> {
+    const MAX_UNSYNCED_BYTES: u64 = 1024;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = FaultyFileLayer::default();
+    let store = open(
+        temp_dir.path(),
+        SyncPolicy::Bytes(MAX_UNSYNCED_BYTES),
+        &layer,
+    )?;
+    let log = temp_dir.path().join("0");
+    for key_id in 0..200 {
+        store.set(format!("key{key_id}"), format!("value{key_id}"))?;
+        let written = fs::metadata(&log)?.len();
+        assert!(written - layer.synced_len(&log) < MAX_UNSYNCED_BYTES);
+    }
+    assert!(layer.syncs() > 0);
+    assert!(layer.syncs() < 200);
+    Ok(())
+}
+
+// The background flusher syncs writes without them waiting for it.
+#[test]
+fn interval_policy_syncs_in_background() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = FaultyFileLayer::default();
+    let store = open(
+        temp_dir.path(),
+        SyncPolicy::Interval(Duration::from_millis(10)),
+        &layer,
+    )?;
+    for key_id in 0..100 {
+        store.set(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert!(layer.syncs() > 0);
+    drop(store);
+
+    layer.crash(temp_dir.path());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".into()));
+    Ok(())
+}
+
+// Concurrent writers waiting on the same sync share it.
+#[test]
+fn group_commit_shares_syncs() -> kvs::Result<()> {
+    const THREADS: usize = 8;
+    const WRITES: usize = 20;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = FaultyFileLayer::with_delay(Duration::from_millis(5));
+    let store = open(temp_dir.path(), SyncPolicy::Always, &layer)?;
+
+    let handles = (0..THREADS)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> kvs::Result<()> {
+                for write in 0..WRITES {
+                    store.set(format!("key{thread_id}-{write}"), format!("{write}"))?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().expect("writer thread panicked")?;
+    }
+    assert!(layer.syncs() < THREADS * WRITES);
+    drop(store);
+
+    layer.crash(temp_dir.path());
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..THREADS {
+        for write in 0..WRITES {
+            assert_eq!(
+                store.get(format!("key{thread_id}-{write}"))?,
+                Some(format!("{write}").into())
+            );
+        }
+    }
+    Ok(())
+}
+
+// A write is not acknowledged if it could not be synced.
+#[test]
+fn sync_failure_fails_the_write() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layer = FaultyFileLayer::default();
+    let store = open(temp_dir.path(), SyncPolicy::Always, &layer)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    layer.fail.store(true, Ordering::SeqCst);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+
+    layer.fail.store(false, Ordering::SeqCst);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    Ok(())
+}
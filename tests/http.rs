@@ -0,0 +1,398 @@
+use kvs::{client::KvsClient, thread_pool::SharedQueueThreadPool, KvStore};
+use serde_json::{json, Value as Json};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+#[allow(dead_code)]
+mod common;
+use common::TestKvsServer;
+
+/// An HTTP response, as read off the socket.
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json(&self) -> Json {
+        assert_eq!(self.header("Content-Type"), Some("application/json"));
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(address: SocketAddr) -> Self {
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Self {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    /// Sends a request with the given body, if any, and reads the response.
+    fn send(&mut self, method: &str, target: &str, body: Option<&[u8]>) -> Response {
+        self.write(&encode(method, target, &[], body));
+        self.read()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).unwrap();
+    }
+
+    fn read(&mut self) -> Response {
+        let status_line = self.read_line();
+        let mut parts = status_line.splitn(3, ' ');
+        assert_eq!(parts.next(), Some("HTTP/1.1"));
+        let status = parts.next().unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let line = self.read_line();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_owned(), value.trim().to_owned()));
+        }
+        let mut response = Response {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        let length = response
+            .header("Content-Length")
+            .map_or(0, |length| length.parse().unwrap());
+        response.body = vec![0; length];
+        self.reader.read_exact(&mut response.body).unwrap();
+        response
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "unterminated line {line:?}");
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    /// Whether the server has closed the connection.
+    fn is_closed(&mut self) -> bool {
+        self.reader.fill_buf().is_ok_and(<[u8]>::is_empty)
+    }
+}
+
+fn encode(method: &str, target: &str, headers: &[&str], body: Option<&[u8]>) -> Vec<u8> {
+    let mut bytes = format!("{method} {target} HTTP/1.1\r\nHost: localhost\r\n").into_bytes();
+    for header in headers {
+        bytes.extend_from_slice(format!("{header}\r\n").as_bytes());
+    }
+    if let Some(body) = body {
+        bytes.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    bytes.extend_from_slice(b"\r\n");
+    bytes.extend_from_slice(body.unwrap_or_default());
+    bytes
+}
+
+/// Starts a server with the binary protocol on `port` and the HTTP gateway on the port after it.
+fn start_server(
+    port: u16,
+) -> (
+    SocketAddr,
+    SocketAddr,
+    Arc<TestKvsServer<KvStore, SharedQueueThreadPool>>,
+) {
+    let address = SocketAddr::from_str(&format!("127.0.0.1:{port}")).unwrap();
+    let http_address = SocketAddr::from_str(&format!("127.0.0.1:{}", port + 1)).unwrap();
+    let server =
+        TestKvsServer::<KvStore, SharedQueueThreadPool>::with_http(address, Some(4), http_address)
+            .spawn(1);
+    server.wait_until_ready();
+    (address, http_address, server)
+}
+
+// Should read, write and remove keys in the same store the binary protocol uses
+#[test]
+fn http_get_put_delete() {
+    let (address, http_address, server) = start_server(9201);
+    let mut connection = Connection::open(http_address);
+
+    let response = connection.send("PUT", "/keys/key1", Some(b"value1"));
+    assert_eq!(response.status, 204);
+    let response = connection.send("GET", "/keys/key1", None);
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        response.header("Content-Type"),
+        Some("application/octet-stream")
+    );
+    assert_eq!(response.body, b"value1");
+
+    // Keys are percent-decoded, and may contain slashes
+    let response = connection.send("PUT", "/keys/a%20b/c", Some(b"\xff\x00"));
+    assert_eq!(response.status, 204);
+    let client = KvsClient::new(address);
+    assert_eq!(client.get("a b/c").unwrap(), Some(b"\xff\x00"[..].into()));
+    client.set("key2", "value2").unwrap();
+    assert_eq!(connection.send("GET", "/keys/key2", None).body, b"value2");
+
+    let response = connection.send("DELETE", "/keys/key1", None);
+    assert_eq!(response.status, 204);
+    let response = connection.send("GET", "/keys/key1", None);
+    assert_eq!(response.status, 404);
+    assert_eq!(
+        response.json(),
+        json!({"error": "key_not_found", "message": "Key not found"})
+    );
+    let response = connection.send("DELETE", "/keys/key1", None);
+    assert_eq!(response.status, 404);
+    assert_eq!(response.json()["error"], "key_not_found");
+
+    let response = connection.send("POST", "/keys/key1", Some(b"value1"));
+    assert_eq!(response.status, 405);
+    assert_eq!(response.header("Allow"), Some("GET, PUT, DELETE"));
+    assert_eq!(response.json()["error"], "method_not_allowed");
+    let response = connection.send("GET", "/values/key1", None);
+    assert_eq!(response.status, 404);
+    assert_eq!(response.json()["error"], "not_found");
+    let response = connection.send("GET", "/keys/key%zz", None);
+    assert_eq!(response.status, 400);
+    assert_eq!(response.json()["error"], "bad_request");
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Following `next` should go through every key once, in pages of `limit` keys
+#[test]
+fn http_list_keys() {
+    let (address, http_address, server) = start_server(9203);
+    let client = KvsClient::new(address);
+    let mut expected = Vec::new();
+    for i in 0..25 {
+        let key = format!("key{i:02}");
+        client.set(key.as_str(), "value").unwrap();
+        expected.push(key);
+    }
+    client.set("other", "value").unwrap();
+    let mut connection = Connection::open(http_address);
+
+    let mut list = |query: &str| {
+        let mut keys = Vec::new();
+        let mut pages = 0;
+        let mut target = format!("/keys?{query}");
+        loop {
+            let response = connection.send("GET", &target, None);
+            assert_eq!(response.status, 200);
+            let page = response.json();
+            keys.extend(
+                page["keys"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|key| key.as_str().unwrap().to_owned()),
+            );
+            pages += 1;
+            match page["next"].as_str() {
+                Some(next) => target = format!("/keys?{query}&start_after={next}"),
+                None => return (keys, pages),
+            }
+        }
+    };
+
+    let (keys, pages) = list("limit=10");
+    assert_eq!(pages, 3);
+    assert_eq!(keys[..25], expected);
+    assert_eq!(keys[25..], ["other"]);
+
+    let (keys, pages) = list("prefix=key1&limit=5");
+    assert_eq!(pages, 3);
+    assert_eq!(keys, expected[10..20]);
+
+    let (keys, _) = list("prefix=key&start_after=key22");
+    assert_eq!(keys, expected[23..]);
+    let (keys, _) = list("prefix=key&start_after=zzz");
+    assert!(keys.is_empty());
+
+    let response = connection.send("GET", "/keys?limit=0", None);
+    assert_eq!(response.status, 400);
+    let response = connection.send("DELETE", "/keys", None);
+    assert_eq!(response.status, 405);
+    assert_eq!(response.header("Allow"), Some("GET"));
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Listed keys and `next` should be percent-encoded, so keys that aren't text can be paged through
+// and read back
+#[test]
+fn http_list_keys_that_arent_text() {
+    let (address, http_address, server) = start_server(9209);
+    let client = KvsClient::new(address);
+    let raw_keys: [&[u8]; 6] = [
+        b"a b",
+        b"a%b",
+        b"a+b",
+        b"a/b",
+        "a\u{e9}".as_bytes(),
+        b"\xff\x00",
+    ];
+    for key in raw_keys.iter().rev() {
+        client.set(*key, *key).unwrap();
+    }
+    let mut connection = Connection::open(http_address);
+
+    let mut keys = Vec::new();
+    let mut target = "/keys?limit=1".to_owned();
+    loop {
+        let page = connection.send("GET", &target, None).json();
+        keys.extend(
+            page["keys"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|key| key.as_str().unwrap().to_owned()),
+        );
+        match page["next"].as_str() {
+            Some(next) => target = format!("/keys?limit=1&start_after={next}"),
+            None => break,
+        }
+    }
+    assert_eq!(
+        keys,
+        ["a%20b", "a%25b", "a%2Bb", "a%2Fb", "a%C3%A9", "%FF%00"]
+    );
+    // The listed keys name the keys they came from, as they are
+    for (key, raw_key) in keys.iter().zip(raw_keys) {
+        let response = connection.send("GET", &format!("/keys/{key}"), None);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, raw_key);
+    }
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Requests whose length is ambiguous should be refused, as the ends could disagree on where the
+// next one starts
+#[test]
+fn http_rejects_ambiguous_lengths() {
+    let (_, http_address, server) = start_server(9211);
+
+    for headers in [
+        ["Content-Length: 6", "Content-Length: 6"],
+        ["Content-Length: 6", "Content-Length: 0"],
+        ["Content-Length: +6", "X-Ignored: 0"],
+    ] {
+        let mut connection = Connection::open(http_address);
+        connection.write(&encode("PUT", "/keys/key1", &headers, None));
+        connection.write(b"value1");
+        let response = connection.read();
+        assert_eq!(response.status, 400);
+        assert_eq!(response.json()["error"], "bad_request");
+        assert!(connection.is_closed());
+    }
+    let mut connection = Connection::open(http_address);
+    assert_eq!(connection.send("GET", "/keys/key1", None).status, 404);
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Keys given a `ttl` should expire, and bodies over the limits should be refused
+#[test]
+fn http_ttl_and_limits() {
+    let (_, http_address, server) = start_server(9205);
+    let mut connection = Connection::open(http_address);
+
+    let response = connection.send("PUT", "/keys/key1?ttl=100ms", Some(b"value1"));
+    assert_eq!(response.status, 204);
+    let response = connection.send("PUT", "/keys/key2?ttl=soon", Some(b"value2"));
+    assert_eq!(response.status, 400);
+    assert_eq!(response.json()["error"], "bad_request");
+    assert_eq!(connection.send("GET", "/keys/key1", None).status, 200);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(connection.send("GET", "/keys/key1", None).status, 404);
+
+    // The body isn't read, so the connection is closed
+    connection.write(b"PUT /keys/key1 HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n");
+    let response = connection.read();
+    assert_eq!(response.status, 413);
+    assert_eq!(response.json()["error"], "limit_exceeded");
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert!(connection.is_closed());
+
+    let mut connection = Connection::open(http_address);
+    connection.write(b"PUT /keys/key1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+    assert_eq!(connection.read().status, 400);
+    assert!(connection.is_closed());
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Requests sent on one connection should be answered in order, until the client asks for it to
+// be closed
+#[test]
+fn http_keep_alive() {
+    let (_, http_address, server) = start_server(9207);
+    let mut connection = Connection::open(http_address);
+
+    connection.write(&encode("PUT", "/keys/key1", &[], Some(b"value1")));
+    connection.write(&encode("GET", "/keys/key1", &[], None));
+    connection.write(&encode("GET", "/keys?prefix=key", &[], None));
+    assert_eq!(connection.read().status, 204);
+    assert_eq!(connection.read().body, b"value1");
+    assert_eq!(
+        connection.read().json(),
+        json!({"keys": ["key1"], "next": null})
+    );
+
+    // The client waits to be told to go ahead before sending the body
+    connection.write(&encode(
+        "PUT",
+        "/keys/key2",
+        &["Expect: 100-continue", "Content-Length: 6"],
+        None,
+    ));
+    assert_eq!(connection.read_line(), "HTTP/1.1 100 Continue");
+    assert_eq!(connection.read_line(), "");
+    connection.write(b"value2");
+    assert_eq!(connection.read().status, 204);
+
+    connection.write(&encode("GET", "/keys/key2", &["Connection: close"], None));
+    let response = connection.read();
+    assert_eq!(response.body, b"value2");
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert!(connection.is_closed());
+
+    let mut connection = Connection::open(http_address);
+    connection.write(b"GET /keys/key1 HTTP/1.0\r\n\r\n");
+    assert_eq!(connection.read().body, b"value1");
+    assert!(connection.is_closed());
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
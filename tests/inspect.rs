@@ -0,0 +1,148 @@
+use kvs::{inspect, shared::WriteBatch, KvStore, KvsEngine, KvsError, Result};
+use std::fs::{self, OpenOptions};
+use tempfile::TempDir;
+
+// Should decode every record and tell the live ones from those that have been overwritten
+#[test]
+fn report_live_and_stale_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.set("key2", "value3")?;
+    store.remove("key2")?;
+    store.write_batch(
+        WriteBatch::new()
+            .set("key3", "value4")
+            .set("key4", "value5"),
+    )?;
+    store.set("key3", "value6")?;
+    drop(store);
+
+    let report = inspect::inspect(temp_dir.path())?;
+    assert!(report.has_manifest);
+    assert!(report.is_healthy());
+    assert_eq!(report.logs.len(), 1);
+    let log = &report.logs[0];
+    assert!(log.active);
+    assert_eq!(log.size, fs::metadata(temp_dir.path().join("0"))?.len());
+    let live = log
+        .records
+        .iter()
+        .map(|record| record.live)
+        .collect::<Vec<_>>();
+    // The batch stays live as long as `key4` points at it
+    assert_eq!(live, [false, true, false, false, true, true]);
+    assert_eq!(log.live_records(), 3);
+    assert_eq!(log.stale_records(), 3);
+    assert_eq!(log.live_bytes() + log.stale_bytes(), log.size);
+    // Records follow each other from the start of the log
+    let mut offset = 0;
+    for record in &log.records {
+        assert_eq!(record.offset, offset);
+        offset += record.length;
+    }
+    Ok(())
+}
+
+// A half-written record at the end of the active log is dropped when the store is opened
+#[test]
+fn report_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    drop(store);
+    let log_file = temp_dir.path().join("0");
+    let size = fs::metadata(&log_file)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(size - 3)?;
+
+    let report = inspect::inspect(temp_dir.path())?;
+    assert!(report.is_healthy());
+    let log = &report.logs[0];
+    assert_eq!(log.records.len(), 1);
+    let damage = log.damage.as_ref().expect("the log should be damaged");
+    assert!(damage.torn_tail);
+    assert_eq!(damage.offset, log.records[0].length);
+    // Inspecting leaves the log alone
+    assert_eq!(fs::metadata(&log_file)?.len(), size - 3);
+    Ok(())
+}
+
+// Should find a damaged record and write a copy of the store without it that can be opened
+#[test]
+fn repair_corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let repaired = temp_dir.path().join("repaired");
+    fs::create_dir(&path)?;
+    let store = KvStore::open(&path)?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+    drop(store);
+
+    // Flip a byte inside the payload of the second record
+    let report = inspect::inspect(&path)?;
+    let second = report.logs[0].records[1].offset;
+    let log_file = path.join("0");
+    let mut contents = fs::read(&log_file)?;
+    contents[usize::try_from(second).expect("offset fits in usize") + 12] ^= 0xFF;
+    fs::write(&log_file, &contents)?;
+    assert!(KvStore::open(&path).is_err());
+
+    let report = inspect::inspect(&path)?;
+    assert!(!report.is_healthy());
+    let damage = report.logs[0]
+        .damage
+        .as_ref()
+        .expect("the log should be damaged");
+    assert_eq!(damage.offset, second);
+    assert!(!damage.torn_tail);
+
+    inspect::repair(&path, &repaired)?;
+    assert_eq!(fs::read(&log_file)?, contents);
+    assert!(inspect::inspect(&repaired)?.is_healthy());
+    let store = KvStore::open(&repaired)?;
+    assert_eq!(
+        store.scan(.., usize::MAX)?,
+        vec![("key1".into(), "value1".into())]
+    );
+    store.set("key4", "value4")?;
+    drop(store);
+    let store = KvStore::open(&repaired)?;
+    assert_eq!(store.get("key4")?, Some("value4".into()));
+
+    // The copy is only ever written to an empty directory
+    assert!(inspect::repair(&path, &repaired).is_err());
+    Ok(())
+}
+
+// Should refuse to inspect or repair a store that is open
+#[test]
+fn refuse_open_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let repaired = temp_dir.path().join("repaired");
+    fs::create_dir(&path)?;
+    let store = KvStore::open(&path)?;
+    store.set("key1", "value1")?;
+
+    assert!(matches!(
+        inspect::inspect(&path),
+        Err(KvsError::StoreLocked(_))
+    ));
+    assert!(matches!(
+        inspect::repair(&path, &repaired),
+        Err(KvsError::StoreLocked(_))
+    ));
+    assert!(!repaired.exists());
+
+    drop(store);
+    assert!(inspect::inspect(&path)?.is_healthy());
+    inspect::repair(&path, &repaired)?;
+    Ok(())
+}
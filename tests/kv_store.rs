@@ -1,7 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    dump,
+    shared::{prefix_range, Expiry, Key, WriteBatch},
+    KvStore, KvsEngine, KvsError, Result, SledKvsEngine, Transaction,
+};
 use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    ops::Bound,
     sync::{Arc, Barrier},
     thread,
+    time::Duration,
 };
 use tempfile::TempDir;
 
@@ -14,14 +22,14 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".into()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".into()));
 
     Ok(())
 }
@@ -33,20 +41,894 @@ fn overwrite_value() -> Result<()> {
     let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".into()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".into()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".into()));
+
+    Ok(())
+}
+
+fn store_binary_data<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    let key = vec![0, 159, 146, 150];
+    let value = (0..=255).collect::<Vec<u8>>();
+
+    store.set(key.clone(), value.clone())?;
+    assert_eq!(store.get(key.clone())?, Some(value.clone().into()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = Engine::open(temp_dir.path())?;
+    assert_eq!(store.get(key.clone())?, Some(value.into()));
+    store.remove(key.clone())?;
+    assert_eq!(store.get(key)?, None);
+
+    Ok(())
+}
+
+// Should store keys and values that aren't valid UTF-8
+#[test]
+fn store_binary_data_in_kvs() -> Result<()> {
+    store_binary_data::<KvStore>()
+}
+
+#[test]
+fn store_binary_data_in_sled() -> Result<()> {
+    store_binary_data::<SledKvsEngine>()
+}
+
+fn scan_keys_in_order<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    for key in ["b2", "a1", "b1", "c1", "b3"] {
+        store.set(key, format!("value-{key}"))?;
+    }
+    store.remove("b2")?;
+    let entry = |key: &str| (key.into(), format!("value-{key}").into());
+
+    assert_eq!(
+        store.scan(Key::from("a1")..Key::from("b3"), usize::MAX)?,
+        vec![entry("a1"), entry("b1")]
+    );
+    assert_eq!(
+        store.scan(.., 3)?,
+        vec![entry("a1"), entry("b1"), entry("b3")]
+    );
+    assert_eq!(
+        store.scan_prefix("b", usize::MAX)?,
+        vec![entry("b1"), entry("b3")]
+    );
+    assert_eq!(store.scan_prefix("d", usize::MAX)?, vec![]);
+
+    let page = store.keys(None, 2)?;
+    assert_eq!(page, vec!["a1".into(), "b1".into()]);
+    let page = store.keys(page.last().cloned(), 2)?;
+    assert_eq!(page, vec!["b3".into(), "c1".into()]);
+    assert_eq!(store.keys(page.last().cloned(), 2)?, vec![]);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = Engine::open(temp_dir.path())?;
+    assert_eq!(
+        store.scan_prefix("b", usize::MAX)?,
+        vec![entry("b1"), entry("b3")]
+    );
+
+    Ok(())
+}
+
+// Should list keys and values in order
+#[test]
+fn scan_keys_in_order_in_kvs() -> Result<()> {
+    scan_keys_in_order::<KvStore>()
+}
+
+#[test]
+fn scan_keys_in_order_in_sled() -> Result<()> {
+    scan_keys_in_order::<SledKvsEngine>()
+}
+
+fn expire_keys<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(500);
+    store.set_with_expiry("short", "value", Expiry::After(ttl))?;
+    store.set_with_expiry("long", "value", Expiry::After(Duration::from_secs(3600)))?;
+    store.set_with_expiry("expired", "value", Expiry::At(1))?;
+    store.set("forever", "value")?;
+
+    let remaining = store.ttl("short")?.expect("key should expire");
+    assert!(remaining > Duration::ZERO && remaining <= ttl);
+    assert_eq!(store.ttl("forever")?, None);
+    assert_eq!(store.get("expired")?, None);
+    assert!(matches!(store.ttl("expired"), Err(KvsError::KeyNotFound)));
+    assert!(matches!(
+        store.remove("expired"),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(
+        store.keys(None, usize::MAX)?,
+        vec!["forever".into(), "long".into(), "short".into()]
+    );
+
+    // Setting a key again without a TTL stops it from expiring
+    store.set("long", "value")?;
+    assert_eq!(store.ttl("long")?, None);
+
+    // Giving a key an expiry keeps its value, and only works on keys that exist
+    store.expire("long", Expiry::After(Duration::from_secs(3600)))?;
+    assert!(store.ttl("long")?.is_some());
+    assert_eq!(store.get("long")?, Some("value".into()));
+    assert!(matches!(
+        store.expire("expired", Expiry::After(ttl)),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.expire("missing", Expiry::After(ttl)),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    thread::sleep(ttl);
+    assert_eq!(store.get("short")?, None);
+    assert_eq!(store.scan(.., usize::MAX)?.len(), 2);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = Engine::open(temp_dir.path())?;
+    assert_eq!(store.get("short")?, None);
+    assert_eq!(store.get("long")?, Some("value".into()));
+    assert!(store.ttl("long")?.is_some());
+    assert_eq!(store.ttl("forever")?, None);
+
+    Ok(())
+}
+
+// Should hide keys once their TTL has passed
+#[test]
+fn expire_keys_in_kvs() -> Result<()> {
+    expire_keys::<KvStore>()
+}
+
+#[test]
+fn expire_keys_in_sled() -> Result<()> {
+    expire_keys::<SledKvsEngine>()
+}
+
+fn conditional_writes<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+
+    store.set_if_absent("key1", "value1")?;
+    assert!(matches!(
+        store.set_if_absent("key1", "value2"),
+        Err(KvsError::ConditionFailed(Some(current))) if current == "value1".into()
+    ));
+    assert!(matches!(
+        store.compare_and_swap("key2", "value1", "value2"),
+        Err(KvsError::ConditionFailed(None))
+    ));
+    store.compare_and_swap("key1", "value1", "value2")?;
+    assert!(matches!(
+        store.remove_if_equals("key1", "value1"),
+        Err(KvsError::ConditionFailed(Some(_)))
+    ));
+    assert_eq!(store.get("key1")?, Some("value2".into()));
+
+    // Expired keys are absent
+    store.set_with_expiry("key3", "value3", Expiry::At(1))?;
+    store.set_if_absent("key3", "value4")?;
+    assert_eq!(store.ttl("key3")?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = Engine::open(temp_dir.path())?;
+    assert_eq!(store.get("key3")?, Some("value4".into()));
+    store.remove_if_equals("key1", "value2")?;
+    assert_eq!(store.get("key1")?, None);
+
+    Ok(())
+}
+
+// Should only apply conditional writes whose condition holds
+#[test]
+fn conditional_writes_in_kvs() -> Result<()> {
+    conditional_writes::<KvStore>()
+}
+
+#[test]
+fn conditional_writes_in_sled() -> Result<()> {
+    conditional_writes::<SledKvsEngine>()
+}
+
+fn concurrent_compare_and_swap<Engine: KvsEngine>() -> Result<()> {
+    const THREADS: usize = 8;
+    const INCREMENTS: usize = 100;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    store.set("counter", "0")?;
+
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles = (0..THREADS)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..INCREMENTS {
+                    let mut current = store.get("counter").unwrap().unwrap();
+                    loop {
+                        let count = current.to_string().parse::<usize>().unwrap();
+                        match store.compare_and_swap("counter", current, (count + 1).to_string()) {
+                            Ok(()) => break,
+                            Err(KvsError::ConditionFailed(Some(value))) => current = value,
+                            Err(err) => panic!("{err}"),
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let expected = (THREADS * INCREMENTS).to_string();
+    assert_eq!(store.get("counter")?, Some(expected.clone().into()));
+    drop(store);
+    let store = Engine::open(temp_dir.path())?;
+    assert_eq!(store.get("counter")?, Some(expected.into()));
+
+    Ok(())
+}
+
+// Should not lose any update when threads increment the same key at once
+#[test]
+fn concurrent_compare_and_swap_in_kvs() -> Result<()> {
+    concurrent_compare_and_swap::<KvStore>()
+}
+
+#[test]
+fn concurrent_compare_and_swap_in_sled() -> Result<()> {
+    concurrent_compare_and_swap::<SledKvsEngine>()
+}
+
+fn write_batches<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    store.set("key3", "value3")?;
+
+    let batch = WriteBatch::new()
+        .set("key1", "value1")
+        .set("key2", "value2")
+        .remove("key3")
+        .remove("missing")
+        .set("key2", "value4")
+        .set_with_expiry("key5", "value5", Expiry::After(Duration::from_secs(3600)));
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+
+    let check = |store: &Engine| -> Result<()> {
+        assert_eq!(
+            store.scan(.., usize::MAX)?,
+            vec![
+                ("key1".into(), "value1".into()),
+                ("key2".into(), "value4".into()),
+                ("key5".into(), "value5".into()),
+            ]
+        );
+        assert!(store.ttl("key5")?.is_some());
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    check(&Engine::open(temp_dir.path())?)
+}
+
+// Should apply every write in a batch
+#[test]
+fn write_batches_in_kvs() -> Result<()> {
+    write_batches::<KvStore>()
+}
+
+#[test]
+fn write_batches_in_sled() -> Result<()> {
+    write_batches::<SledKvsEngine>()
+}
+
+fn transactions<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+    let mut transaction = store.begin()?;
+    transaction.set("key1", "value3")?;
+    transaction.remove("key2")?;
+    transaction.set("key4", "value4")?;
+    assert_eq!(transaction.get("key1")?, Some("value3".into()));
+    assert_eq!(transaction.get("key2")?, None);
+    assert!(matches!(
+        transaction.remove("key2"),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        transaction.remove("missing"),
+        Err(KvsError::KeyNotFound)
+    ));
+    // Nothing is visible outside of the transaction until it commits
+    assert_eq!(store.get("key1")?, Some("value1".into()));
+    assert_eq!(store.get("key4")?, None);
+    transaction.commit()?;
+
+    // Dropping a transaction rolls it back
+    let mut transaction = store.begin()?;
+    transaction.set("key1", "value5")?;
+    drop(transaction);
+
+    let check = |store: &Engine| -> Result<()> {
+        assert_eq!(
+            store.scan(.., usize::MAX)?,
+            vec![
+                ("key1".into(), "value3".into()),
+                ("key4".into(), "value4".into()),
+            ]
+        );
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&Engine::open(temp_dir.path())?)
+}
+
+// Transactions should read their own writes and apply them all when they commit
+#[test]
+fn transactions_in_kvs() -> Result<()> {
+    transactions::<KvStore>()
+}
+
+#[test]
+fn transactions_in_sled() -> Result<()> {
+    transactions::<SledKvsEngine>()
+}
+
+fn transaction_conflicts<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    // Reads are repeatable, and a key written since it was read fails the commit
+    let mut transaction = store.begin()?;
+    assert_eq!(transaction.get("key1")?, Some("value1".into()));
+    store.set("key1", "value2")?;
+    assert_eq!(transaction.get("key1")?, Some("value1".into()));
+    transaction.set("key2", "value3")?;
+    assert!(matches!(
+        transaction.commit(),
+        Err(KvsError::TransactionConflict)
+    ));
+    assert_eq!(store.get("key2")?, None);
+
+    // So does a key written by someone else since the transaction wrote it
+    let mut transaction = store.begin()?;
+    transaction.set("key3", "value4")?;
+    store.set("key3", "value5")?;
+    assert!(matches!(
+        transaction.commit(),
+        Err(KvsError::TransactionConflict)
+    ));
+    assert_eq!(store.get("key3")?, Some("value5".into()));
+
+    // Even when the key has been changed back to the value the transaction read
+    let mut transaction = store.begin()?;
+    assert_eq!(transaction.get("key3")?, Some("value5".into()));
+    store.set("key3", "value8")?;
+    store.set("key3", "value5")?;
+    transaction.set("key2", "value9")?;
+    assert!(matches!(
+        transaction.commit(),
+        Err(KvsError::TransactionConflict)
+    ));
+    assert_eq!(store.get("key2")?, None);
+
+    // Writes to keys the transaction didn't use don't matter
+    let mut transaction = store.begin()?;
+    transaction.set("key1", "value6")?;
+    store.set("key4", "value7")?;
+    transaction.commit()?;
+    assert_eq!(store.get("key1")?, Some("value6".into()));
 
     Ok(())
 }
 
+// Transactions should fail to commit when a key they used was written since
+#[test]
+fn transaction_conflicts_in_kvs() -> Result<()> {
+    transaction_conflicts::<KvStore>()
+}
+
+#[test]
+fn transaction_conflicts_in_sled() -> Result<()> {
+    transaction_conflicts::<SledKvsEngine>()
+}
+
+fn concurrent_transactions<Engine: KvsEngine>() -> Result<()> {
+    const THREADS: usize = 8;
+    const TRANSFERS: usize = 50;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    store.set("from", (THREADS * TRANSFERS).to_string())?;
+    store.set("to", "0")?;
+
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles = (0..THREADS)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..TRANSFERS {
+                    loop {
+                        let mut transaction = store.begin().unwrap();
+                        for (key, change) in [("from", -1), ("to", 1)] {
+                            let value = transaction.get(key).unwrap().unwrap();
+                            let value = value.to_string().parse::<i64>().unwrap() + change;
+                            transaction.set(key, value.to_string()).unwrap();
+                        }
+                        match transaction.commit() {
+                            Ok(()) => break,
+                            Err(KvsError::TransactionConflict) => {}
+                            Err(err) => panic!("{err}"),
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("from")?, Some("0".into()));
+    assert_eq!(
+        store.get("to")?,
+        Some((THREADS * TRANSFERS).to_string().into())
+    );
+    Ok(())
+}
+
+// Should not lose any transfer when threads run transactions over the same keys at once
+#[test]
+fn concurrent_transactions_in_kvs() -> Result<()> {
+    concurrent_transactions::<KvStore>()
+}
+
+#[test]
+fn concurrent_transactions_in_sled() -> Result<()> {
+    concurrent_transactions::<SledKvsEngine>()
+}
+
+// Snapshots should keep showing the store as it was when they were taken
+#[test]
+fn read_from_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set_with_expiry("key3", "value3", Expiry::After(Duration::from_millis(200)))?;
+
+    let snapshot = store.snapshot()?;
+    store.set("key1", "value4")?;
+    store.remove("key2")?;
+    store.set("key5", "value5")?;
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(snapshot.get("key1")?, Some("value1".into()));
+    assert_eq!(snapshot.get("key2")?, Some("value2".into()));
+    assert_eq!(snapshot.get("key5")?, None);
+    // Keys are expired as of when the snapshot was taken
+    assert_eq!(snapshot.get("key3")?, Some("value3".into()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(
+        snapshot.iter().collect::<Result<Vec<_>>>()?,
+        vec![
+            ("key1".into(), "value1".into()),
+            ("key2".into(), "value2".into()),
+            ("key3".into(), "value3".into()),
+        ]
+    );
+    assert_eq!(
+        snapshot
+            .range((Bound::Excluded(Key::from("key1")), Bound::Unbounded))
+            .collect::<Result<Vec<_>>>()?,
+        vec![
+            ("key2".into(), "value2".into()),
+            ("key3".into(), "value3".into()),
+        ]
+    );
+
+    // The snapshot outlives the handle it was taken from
+    drop(store);
+    assert_eq!(snapshot.get("key1")?, Some("value1".into()));
+
+    Ok(())
+}
+
+fn backup_and_restore<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let backup = temp_dir.path().join("backup");
+    fs::create_dir(&path)?;
+    let store = Engine::open(&path)?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.remove("key2")?;
+    store.set_with_expiry("key3", "value3", Expiry::After(Duration::from_secs(3600)))?;
+
+    store.backup_to(&backup)?;
+    assert!(matches!(
+        store.backup_to(&backup),
+        Err(KvsError::InvalidBackup(_))
+    ));
+    store.set("key1", "value4")?;
+    store.set("key5", "value5")?;
+    // The store can't be replaced while it is open
+    assert!(matches!(
+        Engine::restore(&backup, &path),
+        Err(KvsError::StoreLocked(_))
+    ));
+    assert!(!temp_dir.path().join("store.swap").exists());
+    drop(store);
+
+    Engine::restore(&backup, &path)?;
+    let store = Engine::open(&path)?;
+    assert_eq!(
+        store.scan(.., usize::MAX)?,
+        vec![
+            ("key1".into(), "value1".into()),
+            ("key3".into(), "value3".into()),
+        ]
+    );
+    assert!(store.ttl("key3")?.is_some());
+    // The backup can be restored again
+    store.set("key6", "value6")?;
+    drop(store);
+    Engine::restore(&backup, &path)?;
+    assert_eq!(Engine::open(&path)?.get("key6")?, None);
+
+    Ok(())
+}
+
+// A backup should hold the store as it was when it was taken, and replace the store when restored
+#[test]
+fn backup_and_restore_in_kvs() -> Result<()> {
+    backup_and_restore::<KvStore>()
+}
+
+#[test]
+fn backup_and_restore_in_sled() -> Result<()> {
+    backup_and_restore::<SledKvsEngine>()
+}
+
+// Should refuse to restore an incomplete or damaged backup, and leave the store as it was
+#[test]
+fn reject_invalid_backups() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let backup = temp_dir.path().join("backup");
+    fs::create_dir(&path)?;
+    let store = KvStore::open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup_to(&backup)?;
+    drop(store);
+
+    assert!(matches!(
+        SledKvsEngine::restore(&backup, temp_dir.path().join("sled")),
+        Err(KvsError::InvalidBackup(_))
+    ));
+
+    // Flip a byte inside the payload of the first record
+    let log_file = backup.join("0");
+    let mut contents = fs::read(&log_file)?;
+    contents[12] ^= 0xFF;
+    fs::write(&log_file, contents)?;
+    assert!(matches!(
+        KvStore::restore(&backup, &path),
+        Err(KvsError::InvalidBackup(_))
+    ));
+
+    fs::remove_file(backup.join("engine"))?;
+    assert!(matches!(
+        KvStore::restore(&backup, &path),
+        Err(KvsError::InvalidBackup(_))
+    ));
+
+    let store = KvStore::open(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
+    Ok(())
+}
+
+// A restore cut short by a crash should be finished the next time the store is opened, whichever
+// step it got to
+#[test]
+fn finish_interrupted_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let backup = temp_dir.path().join("backup");
+    let staging = temp_dir.path().join("store.restore");
+    let previous = temp_dir.path().join("store.old");
+    let journal = temp_dir.path().join("store.swap");
+    fs::create_dir(&path)?;
+    let mut store = KvStore::open(&path)?;
+    store.set("key1", "value1")?;
+    store.backup_to(&backup)?;
+
+    // Nothing renamed yet, the store renamed out of the way, and the backup renamed into place
+    for renamed in 0..3 {
+        store.set("key1", "value2")?;
+        drop(store);
+        fs::create_dir(&staging)?;
+        for entry in fs::read_dir(&backup)? {
+            let entry = entry?;
+            fs::copy(entry.path(), staging.join(entry.file_name()))?;
+        }
+        fs::write(&journal, staging.as_os_str().as_encoded_bytes())?;
+        if renamed > 0 {
+            fs::rename(&path, &previous)?;
+        }
+        if renamed > 1 {
+            fs::rename(&staging, &path)?;
+        }
+
+        store = KvStore::open(&path)?;
+        assert_eq!(store.get("key1")?, Some("value1".into()));
+        for leftover in [&staging, &previous, &journal] {
+            assert!(!leftover.exists(), "{} was left behind", leftover.display());
+        }
+    }
+    Ok(())
+}
+
+fn export_and_import<Source: KvsEngine, Target: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source_path = temp_dir.path().join("source");
+    let target_path = temp_dir.path().join("target");
+    fs::create_dir(&source_path)?;
+    fs::create_dir(&target_path)?;
+    let source = Source::open(&source_path)?;
+    source.set("key1", "value1")?;
+    source.set("key2", "value2")?;
+    source.remove("key2")?;
+    source.set_with_expiry("key3", "value3", Expiry::After(Duration::from_secs(3600)))?;
+    source.set_with_expiry("key4", "value4", Expiry::After(Duration::from_millis(200)))?;
+
+    let mut dump = Vec::new();
+    assert_eq!(source.export(&mut dump)?, 3);
+    thread::sleep(Duration::from_millis(300));
+    let target = Target::open(&target_path)?;
+    target.set("key5", "value5")?;
+    // Entries that expired since the export are read but not imported
+    assert_eq!(target.import(dump.as_slice())?, 3);
+    assert_eq!(
+        target.scan(.., usize::MAX)?,
+        vec![
+            ("key1".into(), "value1".into()),
+            ("key3".into(), "value3".into()),
+            ("key5".into(), "value5".into()),
+        ]
+    );
+    let ttl = target.ttl("key3")?.expect("key3 should expire");
+    assert!(ttl > Duration::from_secs(3500));
+    Ok(())
+}
+
+// A dump exported by one engine should import into any engine, keeping expiries
+#[test]
+fn export_and_import_from_kvs_to_sled() -> Result<()> {
+    export_and_import::<KvStore, SledKvsEngine>()
+}
+
+#[test]
+fn export_and_import_from_sled_to_kvs() -> Result<()> {
+    export_and_import::<SledKvsEngine, KvStore>()
+}
+
+// Should refuse a dump that was damaged or cut short
+#[test]
+fn reject_invalid_dumps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    let mut dump = Vec::new();
+    store.export(&mut dump)?;
+
+    let mut corrupted = dump.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xFF;
+    assert!(matches!(
+        store.import(corrupted.as_slice()),
+        Err(KvsError::InvalidDump(_))
+    ));
+    assert!(matches!(
+        store.import(&dump[..dump.len() - 4]),
+        Err(KvsError::InvalidDump(_))
+    ));
+    assert!(matches!(
+        store.import(&b"not a dump"[..]),
+        Err(KvsError::InvalidDump(_))
+    ));
+    assert!(matches!(
+        store.import(&[][..]),
+        Err(KvsError::InvalidDump(_))
+    ));
+    Ok(())
+}
+
+fn migrate<Source: KvsEngine, Target: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    fs::create_dir(&path)?;
+    let store = Source::open(&path)?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set_with_expiry("key3", "value3", Expiry::After(Duration::from_secs(3600)))?;
+    drop(store);
+
+    // A store that uses another engine than the one given is left alone
+    assert!(matches!(
+        dump::migrate::<Target, Source>(&path),
+        Err(KvsError::WrongEngine)
+    ));
+    assert!(matches!(
+        dump::check_engine::<Target>(&path),
+        Err(KvsError::WrongEngine)
+    ));
+
+    assert_eq!(dump::migrate::<Source, Target>(&path)?, 3);
+    let store = Target::open(&path)?;
+    assert_eq!(
+        store.scan(.., usize::MAX)?,
+        vec![
+            ("key1".into(), "value1".into()),
+            ("key2".into(), "value2".into()),
+            ("key3".into(), "value3".into()),
+        ]
+    );
+    assert!(store.ttl("key3")?.is_some());
+    // Only the migrated store is left behind
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 1);
+    Ok(())
+}
+
+// Should convert a store to another engine in place
+#[test]
+fn migrate_from_kvs_to_sled() -> Result<()> {
+    migrate::<KvStore, SledKvsEngine>()
+}
+
+#[test]
+fn migrate_from_sled_to_kvs() -> Result<()> {
+    migrate::<SledKvsEngine, KvStore>()
+}
+
+fn stats<Engine: KvsEngine>(engine: &str) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, engine);
+    assert_eq!(stats.keys, 0);
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set("key2", "value3")?;
+    store.write_batch(
+        WriteBatch::new()
+            .set("key3", "value4")
+            .set("key4", "value5"),
+    )?;
+    store.remove("key4")?;
+    store.set_with_expiry("key5", "value6", Expiry::At(1))?;
+    let stats = store.stats()?;
+    // Expired keys aren't counted
+    assert_eq!(stats.keys, 3);
+    assert!(stats.disk_bytes > 0);
+    Ok(())
+}
+
+// Should report how many keys the store holds and how big it is
+#[test]
+fn stats_in_kvs() -> Result<()> {
+    stats::<KvStore>("kvs")?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.set_with_expiry("key2", "value3", Expiry::After(Duration::from_millis(100)))?;
+    thread::sleep(Duration::from_millis(200));
+    let stats = store.stats()?;
+    // The expired key is garbage, even though it hasn't been compacted away yet
+    assert_eq!(stats.keys, 1);
+    let log = &stats.logs[0];
+    assert_eq!(log.size, fs::metadata(temp_dir.path().join("0"))?.len());
+    assert_eq!(stats.live_bytes, Some(log.live_bytes));
+    assert!(log.stale_bytes > log.live_bytes);
+    assert_eq!(log.live_bytes + log.stale_bytes, log.size);
+    Ok(())
+}
+
+#[test]
+fn stats_in_sled() -> Result<()> {
+    stats::<SledKvsEngine>("sled")
+}
+
+// Should drop the whole batch when a crash cuts its record short
+#[test]
+fn discard_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.write_batch(
+        WriteBatch::new()
+            .set("key1", "value1")
+            .set("key2", "value2"),
+    )?;
+    let log_file = temp_dir.path().join("0");
+    let valid_size = fs::metadata(&log_file)?.len();
+    store.write_batch(
+        WriteBatch::new()
+            .set("key1", "value3")
+            .remove("key2")
+            .set("key4", "value4"),
+    )?;
+    drop(store);
+
+    // Simulate a crash in the middle of appending the second batch
+    let torn_size = (valid_size + fs::metadata(&log_file)?.len()) / 2;
+    OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(torn_size)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log_file)?.len(), valid_size);
+    assert_eq!(
+        store.scan(.., usize::MAX)?,
+        vec![
+            ("key1".into(), "value1".into()),
+            ("key2".into(), "value2".into()),
+        ]
+    );
+
+    Ok(())
+}
+
+// The range of a prefix should end after the last key that starts with it
+#[test]
+fn prefix_range_skips_trailing_max_bytes() {
+    assert_eq!(
+        prefix_range(vec![1, 255]),
+        (
+            Bound::Included(vec![1, 255].into()),
+            Bound::Excluded(vec![2].into())
+        )
+    );
+    assert_eq!(
+        prefix_range(vec![255]),
+        (Bound::Included(vec![255].into()), Bound::Unbounded)
+    );
+}
+
 // Should get `None` when getting a non-existent key
 #[test]
 fn get_non_existent_value() -> Result<()> {
@@ -87,27 +969,40 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
         });
+        handles.push(handle);
     }
     barrier.wait();
 
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i).into())
+        );
+    }
+
+    // Every handle has to be dropped before the store can be opened again
+    for handle in handles {
+        handle.join().unwrap();
     }
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}", i).into())
+        );
     }
 
     Ok(())
@@ -131,7 +1026,7 @@ fn concurrent_get() -> Result<()> {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
                     store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
+                    Some(format!("value{}", key_id).into())
                 );
             }
         });
@@ -152,7 +1047,7 @@ fn concurrent_get() -> Result<()> {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
                     store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
+                    Some(format!("value{}", key_id).into())
                 );
             }
         });
@@ -164,3 +1059,158 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should drop a half-written record at the end of the active log and keep accepting writes
+#[test]
+fn recover_from_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Simulate a crash in the middle of appending a record
+    let log_file = temp_dir.path().join("0");
+    let valid_size = fs::metadata(&log_file)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log_file)?;
+    file.write_all(&100_u32.to_le_bytes())?;
+    file.write_all(&[0xAB; 10])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log_file)?.len(), valid_size);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".into()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".into()));
+
+    Ok(())
+}
+
+// Should refuse to open a log that is damaged before its tail
+#[test]
+fn detect_corruption_in_the_middle_of_a_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Flip a byte inside the payload of the first record
+    let log_file = temp_dir.path().join("0");
+    let mut contents = fs::read(&log_file)?;
+    contents[12] ^= 0xFF;
+    fs::write(&log_file, contents)?;
+
+    let result = KvStore::open(temp_dir.path());
+    assert!(matches!(result, Err(KvsError::CorruptLog(0, 0))));
+
+    Ok(())
+}
+
+// Should refuse to open a log whose first record has a damaged length, rather than mistake
+// everything after it for a torn write
+#[test]
+fn detect_damaged_length_in_the_middle_of_a_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // The length of the first record now runs past the end of the log
+    let log_file = temp_dir.path().join("0");
+    let mut contents = fs::read(&log_file)?;
+    contents[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&log_file, &contents)?;
+
+    let result = KvStore::open(temp_dir.path());
+    assert!(matches!(result, Err(KvsError::CorruptLog(0, 0))));
+    assert_eq!(fs::read(&log_file)?, contents);
+
+    // Even when the only record after it is bigger than what is read at a time
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "v".repeat(200_000))?;
+    drop(store);
+    let log_file = temp_dir.path().join("0");
+    let mut contents = fs::read(&log_file)?;
+    contents[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&log_file, &contents)?;
+
+    let result = KvStore::open(temp_dir.path());
+    assert!(matches!(result, Err(KvsError::CorruptLog(0, 0))));
+    assert_eq!(fs::read(&log_file)?, contents);
+
+    Ok(())
+}
+
+// Should open a store written before log records were framed, keeping its contents
+#[test]
+fn upgrade_unframed_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // Records were bare bincode commands: a `u32` variant index, then each string as a `u64`
+    // length followed by its bytes
+    let record = |variant: u32, fields: &[&str]| {
+        let mut bytes = variant.to_le_bytes().to_vec();
+        for field in fields {
+            bytes.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes
+    };
+    let mut log = Vec::new();
+    log.extend(record(0, &["key1", "value1"]));
+    log.extend(record(0, &["key2", "value2"]));
+    log.extend(record(0, &["key1", "value3"]));
+    log.extend(record(2, &["key2"]));
+    fs::write(temp_dir.path().join("0"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".into()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".into()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".into()));
+
+    Ok(())
+}
+
+// Should drop a record cut short at the end of the last unframed log, but not of an earlier one
+#[test]
+fn upgrade_torn_unframed_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let record = |fields: &[&str]| {
+        let mut bytes = 0_u32.to_le_bytes().to_vec();
+        for field in fields {
+            bytes.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes
+    };
+    let mut log = record(&["key1", "value1"]);
+    let torn = record(&["key2", "value2"]);
+    log.extend(&torn[..torn.len() - 3]);
+    fs::write(temp_dir.path().join("0"), &log)?;
+    fs::write(temp_dir.path().join("1"), &log)?;
+
+    let offset = record(&["key1", "value1"]).len() as u64;
+    let result = KvStore::open(temp_dir.path());
+    assert!(matches!(result, Err(KvsError::CorruptLog(0, at)) if at == offset));
+
+    fs::remove_file(temp_dir.path().join("0"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use kvs::{KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
+use std::process;
+use tempfile::TempDir;
+
+fn lock_while_open<Engine: KvsEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Engine::open(temp_dir.path())?;
+    let handle = store.clone();
+
+    match Engine::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked(pid)) => assert_eq!(pid, process::id()),
+        Err(err) => panic!("expected the store to be locked, got {err}"),
+        Ok(_) => panic!("expected the store to be locked"),
+    }
+
+    // The lock is only released once every handle is gone
+    drop(store);
+    assert!(Engine::open(temp_dir.path()).is_err());
+    drop(handle);
+    Engine::open(temp_dir.path())?;
+    Ok(())
+}
+
+// Should refuse to open a store that is already open until it is closed
+#[test]
+fn lock_kvs_store_while_open() -> Result<()> {
+    lock_while_open::<KvStore>()
+}
+
+#[test]
+fn lock_sled_store_while_open() -> Result<()> {
+    lock_while_open::<SledKvsEngine>()
+}
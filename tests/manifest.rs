@@ -0,0 +1,56 @@
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// Files that aren't listed in the manifest should be left alone when opening the store
+#[test]
+fn ignore_unknown_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    fs::copy(temp_dir.path().join("0"), temp_dir.path().join("0.bak"))?;
+    fs::write(temp_dir.path().join("7"), b"not a log")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(fs::read(temp_dir.path().join("7"))?, b"not a log");
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".into()));
+    Ok(())
+}
+
+// A store written before the manifest existed should have one created from its logs
+#[test]
+fn create_manifest_for_existing_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(temp_dir.path().join("MANIFEST").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".into()));
+    Ok(())
+}
+
+// Should refuse to open if a log listed in the manifest is gone
+#[test]
+fn detect_missing_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join("0"))?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::MissingLog(0))
+    ));
+    Ok(())
+}
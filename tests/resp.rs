@@ -0,0 +1,397 @@
+use kvs::{protocol::Limits, server::Protocol, thread_pool::SharedQueueThreadPool, KvStore};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+#[allow(dead_code)]
+mod common;
+use common::TestKvsServer;
+
+/// A RESP2 reply, as read off the socket.
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+use Reply::{Array, Bulk, Error, Integer, Simple};
+
+fn bulk(text: &str) -> Reply {
+    Bulk(Some(text.to_owned()))
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(address: SocketAddr) -> Self {
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Self {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    /// Sends a command as an array of bulk strings, the way Redis clients do, and reads the reply.
+    fn send(&mut self, arguments: &[&str]) -> Reply {
+        self.write(&encode(arguments));
+        self.read()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).unwrap();
+    }
+
+    fn read(&mut self) -> Reply {
+        let line = self.read_line();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Simple(rest.to_owned()),
+            "-" => Error(rest.to_owned()),
+            ":" => Integer(rest.parse().unwrap()),
+            "$" => {
+                let Ok(length) = usize::try_from(rest.parse::<i64>().unwrap()) else {
+                    return Bulk(None);
+                };
+                let mut bytes = vec![0; length + 2];
+                self.reader.read_exact(&mut bytes).unwrap();
+                bytes.truncate(length);
+                Bulk(Some(String::from_utf8(bytes).unwrap()))
+            }
+            "*" => Array((0..rest.parse().unwrap()).map(|_| self.read()).collect()),
+            _ => panic!("unexpected reply {line:?}"),
+        }
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "unterminated line {line:?}");
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    /// Whether the server has closed the connection.
+    fn is_closed(&mut self) -> bool {
+        self.reader.fill_buf().is_ok_and(<[u8]>::is_empty)
+    }
+}
+
+fn encode(arguments: &[&str]) -> Vec<u8> {
+    let mut bytes = format!("*{}\r\n", arguments.len()).into_bytes();
+    for argument in arguments {
+        bytes.extend_from_slice(format!("${}\r\n{argument}\r\n", argument.len()).as_bytes());
+    }
+    bytes
+}
+
+fn start_server(
+    port: u16,
+) -> (
+    SocketAddr,
+    Arc<TestKvsServer<KvStore, SharedQueueThreadPool>>,
+) {
+    let address = SocketAddr::from_str(&format!("127.0.0.1:{port}")).unwrap();
+    let server = TestKvsServer::<KvStore, SharedQueueThreadPool>::with_protocol(
+        address,
+        Some(2),
+        Protocol::Resp,
+    )
+    .spawn(1);
+    server.wait_until_ready();
+    (address, server)
+}
+
+// Should answer the basic commands the way Redis does
+#[test]
+fn resp_get_set_del_exists() {
+    let (address, server) = start_server(9101);
+    let mut connection = Connection::open(address);
+
+    assert_eq!(connection.send(&["PING"]), Simple("PONG".into()));
+    assert_eq!(connection.send(&["ping", "hello"]), bulk("hello"));
+    assert_eq!(
+        connection.send(&["SET", "key1", "value1"]),
+        Simple("OK".into())
+    );
+    assert_eq!(connection.send(&["SET", "key2", ""]), Simple("OK".into()));
+    assert_eq!(connection.send(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(connection.send(&["GET", "key2"]), bulk(""));
+    assert_eq!(connection.send(&["GET", "missing"]), Bulk(None));
+    assert_eq!(
+        connection.send(&["EXISTS", "key1", "missing", "key1"]),
+        Integer(2)
+    );
+    assert_eq!(
+        connection.send(&["DEL", "key1", "missing", "key2"]),
+        Integer(2)
+    );
+    assert_eq!(connection.send(&["GET", "key1"]), Bulk(None));
+    assert_eq!(connection.send(&["EXISTS", "key1"]), Integer(0));
+
+    assert_eq!(
+        connection.send(&["GET"]),
+        Error("ERR wrong number of arguments for 'get' command".into())
+    );
+    assert_eq!(
+        connection.send(&["HSET", "key", "field", "value"]),
+        Error("ERR unknown command 'hset'".into())
+    );
+    assert_eq!(
+        connection.send(&["SET", "key1", "value1", "KEEPTTL"]),
+        Error("ERR syntax error".into())
+    );
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Keys set with an expiry, or given one by `EXPIRE`, should disappear once it has passed
+#[test]
+fn resp_expire() {
+    let (address, server) = start_server(9102);
+    let mut connection = Connection::open(address);
+
+    assert_eq!(
+        connection.send(&["SET", "key1", "value1", "PX", "100"]),
+        Simple("OK".into())
+    );
+    assert_eq!(
+        connection.send(&["SET", "key2", "value2"]),
+        Simple("OK".into())
+    );
+    assert_eq!(connection.send(&["EXPIRE", "key2", "1"]), Integer(1));
+    assert_eq!(connection.send(&["EXPIRE", "missing", "1"]), Integer(0));
+    assert_eq!(
+        connection.send(&["SET", "key3", "value3", "EX", "100"]),
+        Simple("OK".into())
+    );
+    assert_eq!(connection.send(&["EXPIRE", "key3", "0"]), Integer(1));
+    assert_eq!(connection.send(&["GET", "key3"]), Bulk(None));
+    assert_eq!(connection.send(&["GET", "key1"]), bulk("value1"));
+
+    assert_eq!(
+        connection.send(&["SET", "key4", "value4", "EX", "0"]),
+        Error("ERR invalid expire time in 'set' command".into())
+    );
+    assert_eq!(
+        connection.send(&["EXPIRE", "key2", "soon"]),
+        Error("ERR value is not an integer or out of range".into())
+    );
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(connection.send(&["GET", "key1"]), Bulk(None));
+    assert_eq!(connection.send(&["EXISTS", "key1", "key2"]), Integer(0));
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// `EXPIRE` shouldn't write back a value that another client has replaced in the meantime
+#[test]
+fn resp_expire_alongside_writes() {
+    let (address, server) = start_server(9106);
+    let done = Arc::new(AtomicBool::new(false));
+    let expirer = {
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            let mut connection = Connection::open(address);
+            while !done.load(Ordering::Relaxed) {
+                connection.send(&["EXPIRE", "key", "100"]);
+            }
+        })
+    };
+
+    let mut connection = Connection::open(address);
+    // Large values leave more time between reading a value and writing it back
+    let value = |i: usize| format!("{i:0>60000}");
+    for i in 1..=200 {
+        let value = value(i);
+        assert_eq!(
+            connection.send(&["SET", "key", &value]),
+            Simple("OK".into())
+        );
+        assert_eq!(connection.send(&["GET", "key"]), bulk(&value));
+    }
+    done.store(true, Ordering::Relaxed);
+    expirer.join().unwrap();
+    assert_eq!(connection.send(&["GET", "key"]), bulk(&value(200)));
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Following the cursor should go through every key once, in pages of `COUNT` keys
+#[test]
+fn resp_scan() {
+    let (address, server) = start_server(9103);
+    let mut connection = Connection::open(address);
+    let mut expected = Vec::new();
+    for i in 0..25 {
+        let key = format!("key{i:02}");
+        connection.send(&["SET", &key, "value"]);
+        expected.push(key);
+    }
+    connection.send(&["SET", "other", "value"]);
+
+    let scan = |connection: &mut Connection, options: &[&str]| {
+        let mut keys = Vec::new();
+        let mut cursor = "0".to_owned();
+        let mut pages = 0;
+        loop {
+            let mut arguments = vec!["SCAN", &cursor];
+            arguments.extend_from_slice(options);
+            let Array(reply) = connection.send(&arguments) else {
+                panic!("expected an array");
+            };
+            let [Bulk(Some(next)), Array(page)] = &reply[..] else {
+                panic!("unexpected reply {reply:?}");
+            };
+            keys.extend(page.iter().map(|key| match key {
+                Bulk(Some(key)) => key.clone(),
+                key => panic!("unexpected key {key:?}"),
+            }));
+            pages += 1;
+            cursor.clone_from(next);
+            if cursor == "0" {
+                return (keys, pages);
+            }
+        }
+    };
+
+    let (keys, pages) = scan(&mut connection, &["COUNT", "10"]);
+    assert_eq!(pages, 3);
+    assert_eq!(keys[..25], expected);
+    assert_eq!(keys[25..], ["other"]);
+
+    let (keys, pages) = scan(&mut connection, &["MATCH", "key1*", "COUNT", "5"]);
+    assert_eq!(pages, 6);
+    assert_eq!(keys, expected[10..20]);
+
+    assert_eq!(
+        connection.send(&["SCAN", "12345"]),
+        Error("ERR invalid cursor".into())
+    );
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// A `SCAN` with a huge `COUNT` should stop at what fits in a frame and carry on from there
+#[test]
+fn resp_scan_within_frame_size() {
+    let address = SocketAddr::from_str("127.0.0.1:9107").unwrap();
+    let limits = Limits {
+        max_frame_size: 1024,
+        ..Limits::default()
+    };
+    let server = TestKvsServer::<KvStore, SharedQueueThreadPool>::with_limits(
+        address,
+        Some(2),
+        Protocol::Resp,
+        limits,
+    )
+    .spawn(1);
+    server.wait_until_ready();
+    let mut connection = Connection::open(address);
+    let expected = (0..20)
+        .map(|i| format!("key{i:02}{}", "x".repeat(100)))
+        .collect::<Vec<_>>();
+    for key in &expected {
+        assert_eq!(connection.send(&["SET", key, "value"]), Simple("OK".into()));
+    }
+
+    let mut keys = Vec::new();
+    let mut cursor = "0".to_owned();
+    loop {
+        let Array(reply) = connection.send(&["SCAN", &cursor, "COUNT", "1000000000"]) else {
+            panic!("expected an array");
+        };
+        let [Bulk(Some(next)), Array(page)] = &reply[..] else {
+            panic!("unexpected reply {reply:?}");
+        };
+        assert!(!page.is_empty() && page.len() < expected.len());
+        keys.extend(page.iter().map(|key| match key {
+            Bulk(Some(key)) => key.clone(),
+            key => panic!("unexpected key {key:?}"),
+        }));
+        cursor.clone_from(next);
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys, expected);
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Inline commands and commands sent before the previous replies were read should be answered in
+// order
+#[test]
+fn resp_pipelined_and_inline_commands() {
+    let (address, server) = start_server(9104);
+    let mut connection = Connection::open(address);
+
+    connection.write(b"PING\r\nSET key1 value1\r\n");
+    connection.write(&encode(&["GET", "key1"]));
+    connection.write(b"\r\nEXISTS key1\n");
+    assert_eq!(connection.read(), Simple("PONG".into()));
+    assert_eq!(connection.read(), Simple("OK".into()));
+    assert_eq!(connection.read(), bulk("value1"));
+    assert_eq!(connection.read(), Integer(1));
+
+    let Bulk(Some(info)) = connection.send(&["INFO"]) else {
+        panic!("expected a bulk string");
+    };
+    assert!(info.contains("engine:kvs\r\n"));
+    assert!(info.contains("db0:keys=1\r\n"));
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
+
+// Malformed commands should be answered with an error before the connection is closed
+#[test]
+fn resp_protocol_errors() {
+    let (address, server) = start_server(9105);
+
+    let mut connection = Connection::open(address);
+    connection.write(b"*2\r\n$3\r\nGET\r\n#4\r\nkey1\r\n");
+    assert_eq!(
+        connection.read(),
+        Error("ERR Protocol error: expected '$', got '#'".into())
+    );
+    assert!(connection.is_closed());
+
+    // A bulk string longer than the server accepts, which it shouldn't try to allocate
+    let mut connection = Connection::open(address);
+    connection.write(b"*2\r\n$3\r\nGET\r\n$999999999999\r\n");
+    let Error(message) = connection.read() else {
+        panic!("expected an error");
+    };
+    assert!(message.starts_with("ERR Protocol error: command of more than"));
+    assert!(connection.is_closed());
+
+    let mut connection = Connection::open(address);
+    assert_eq!(connection.send(&["PING"]), Simple("PONG".into()));
+
+    server.shutdown();
+    server.wait_until_shutdown();
+}
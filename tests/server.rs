@@ -1,8 +1,24 @@
-use kvs::{client::KvsClient, thread_pool::SharedQueueThreadPool, KvStore};
+use kvs::{
+    client::KvsClient,
+    protocol::{self, Frame, Hello, Limits, MessageType, FEATURES, MAGIC, PROTOCOL_VERSION},
+    server::process_stream,
+    shared::{Command, Get, Remove, Request, Response, Scan, Set, WriteBatch},
+    thread_pool::{SharedQueueThreadPool, ThreadPool},
+    KvStore, KvsEngine, KvsError, Transaction,
+};
 
 mod common;
 use crossbeam::channel::unbounded;
-use std::{net::SocketAddr, str::FromStr, sync::Arc, thread, time::Duration};
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    ops::Bound,
+    str::FromStr,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use tempfile::TempDir;
 
 use kvs::thread_pool::RayonThreadPool;
 #[cfg(test)]
@@ -95,3 +111,494 @@ fn client_can_send_bulk_commands_to_server() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// Conditional writes should report a failed condition along with the current value
+#[test]
+fn client_gets_current_value_when_condition_fails() -> anyhow::Result<()> {
+    let address = SocketAddr::from_str("127.0.0.1:9004").unwrap();
+    let test_server =
+        common::TestKvsServer::<KvStore, SharedQueueThreadPool>::new(address, Some(2)).spawn(1);
+    test_server.wait_until_ready();
+    let client = KvsClient::new(address);
+
+    client.set_if_absent("key1", "value1")?;
+    match client.compare_and_swap("key1", "value2", "value3") {
+        Err(KvsError::ConditionFailed(current)) => assert_eq!(current, Some("value1".into())),
+        result => panic!("unexpected result: {result:?}"),
+    }
+    client.compare_and_swap("key1", "value1", "value3")?;
+    assert_eq!(client.get("key1")?, Some("value3".into()));
+
+    test_server.shutdown();
+    test_server.wait_until_shutdown();
+    Ok(())
+}
+
+// Transactions should run over a connection of their own and commit all their writes at once
+#[test]
+fn client_runs_transaction_on_server() -> anyhow::Result<()> {
+    let address = SocketAddr::from_str("127.0.0.1:9005").unwrap();
+    let test_server =
+        common::TestKvsServer::<KvStore, SharedQueueThreadPool>::new(address, Some(2)).spawn(1);
+    test_server.wait_until_ready();
+    let client = KvsClient::new(address);
+    client.set("key1", "value1")?;
+
+    let mut transaction = client.begin()?;
+    assert_eq!(transaction.get("key1")?, Some("value1".into()));
+    assert_eq!(transaction.get("key2")?, None);
+    transaction.set("key2", "value2")?;
+    transaction.remove("key1")?;
+    assert_eq!(transaction.get("key2")?, Some("value2".into()));
+    assert!(client.get("key2").is_err());
+    transaction.commit()?;
+    assert!(client.get("key1").is_err());
+    assert_eq!(client.get("key2")?, Some("value2".into()));
+
+    let mut transaction = client.begin()?;
+    transaction.set("key3", "value3")?;
+    transaction.rollback()?;
+    assert!(client.get("key3").is_err());
+
+    let mut transaction = client.begin()?;
+    assert_eq!(transaction.get("key2")?, Some("value2".into()));
+    transaction.set("key2", "value4")?;
+    client.set("key2", "value5")?;
+    assert!(matches!(
+        transaction.commit(),
+        Err(KvsError::TransactionConflict)
+    ));
+    assert_eq!(client.get("key2")?, Some("value5".into()));
+
+    test_server.shutdown();
+    test_server.wait_until_shutdown();
+    Ok(())
+}
+
+// A connection should carry many requests, each answered with the id it was sent with
+#[test]
+fn server_handles_many_requests_per_connection() -> anyhow::Result<()> {
+    let address = SocketAddr::from_str("127.0.0.1:9006").unwrap();
+    let test_server =
+        common::TestKvsServer::<KvStore, SharedQueueThreadPool>::new(address, Some(1)).spawn(1);
+    test_server.wait_until_ready();
+
+    let stream = TcpStream::connect(address)?;
+    let limits = Limits::default();
+    let version = protocol::say_hello(&stream, &stream, &limits)?.version;
+    let requests = [
+        Request::new(7, Command::from(Set::new("key1".into(), "value1".into()))),
+        Request::new(3, Command::from(Get::new("key1".into()))),
+        Request::new(5, Command::from(Get::new("key2".into()))),
+    ];
+    // Sent all at once, before reading any response
+    for request in &requests {
+        request.write(&stream, version)?;
+    }
+    let responses = (0..requests.len())
+        .map(|_| Response::read(&stream, version, &limits))
+        .collect::<kvs::Result<Vec<_>>>()?;
+    let ids = responses
+        .iter()
+        .map(|response| response.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [7, 3, 5]);
+    let mut responses = responses.into_iter().map(|response| response.response);
+    assert_eq!(responses.next().unwrap().into_result()?, None);
+    assert_eq!(
+        responses.next().unwrap().into_result()?,
+        Some("value1".into())
+    );
+    assert!(responses.next().unwrap().is_err());
+
+    // With a single worker, the client only gets through if the connection above was released
+    drop(stream);
+    let client = KvsClient::new(address);
+    assert_eq!(client.get("key1")?, Some("value1".into()));
+    client.set("key2", "value2")?;
+    assert_eq!(client.get("key2")?, Some("value2".into()));
+
+    test_server.shutdown();
+    test_server.wait_until_shutdown();
+    Ok(())
+}
+
+// Pipelined commands should all be answered, in order, without stopping at a failed one
+#[test]
+fn client_pipelines_commands() -> anyhow::Result<()> {
+    let address = SocketAddr::from_str("127.0.0.1:9007").unwrap();
+    let test_server =
+        common::TestKvsServer::<KvStore, SharedQueueThreadPool>::new(address, Some(1)).spawn(1);
+    test_server.wait_until_ready();
+    let client = KvsClient::new(address);
+
+    let mut commands = Vec::new();
+    for i in 0..500 {
+        commands.push(Command::from(Set::new(
+            format!("key{i}").into(),
+            format!("value{i}").into(),
+        )));
+        commands.push(Command::from(Get::new(format!("key{i}").into())));
+    }
+    commands.push(Command::from(Remove::new("missing".into())));
+    commands.push(Command::from(Get::new("key0".into())));
+    let responses = client.pipeline(&commands)?;
+    assert_eq!(responses.len(), commands.len());
+    for (i, pair) in responses[..1000].chunks(2).enumerate() {
+        assert_eq!(pair[0].clone().into_result()?, None);
+        assert_eq!(
+            pair[1].clone().into_result()?,
+            Some(format!("value{i}").into())
+        );
+    }
+    assert!(responses[1000].is_err());
+    assert_eq!(
+        responses[1001].clone().into_result()?,
+        Some("value0".into())
+    );
+
+    // The same connection carries on afterwards
+    assert_eq!(client.get("key499")?, Some("value499".into()));
+
+    test_server.shutdown();
+    test_server.wait_until_shutdown();
+    Ok(())
+}
+
+// Peers that don't speak the protocol, or no version of it the server does, should be told why
+#[test]
+fn server_negotiates_protocol_version() -> anyhow::Result<()> {
+    let address = SocketAddr::from_str("127.0.0.1:9008").unwrap();
+    let test_server =
+        common::TestKvsServer::<KvStore, SharedQueueThreadPool>::new(address, Some(2)).spawn(1);
+    test_server.wait_until_ready();
+
+    let welcome = KvsClient::new(address).protocol()?;
+    assert_eq!(welcome.version, PROTOCOL_VERSION);
+    assert_eq!(welcome.features, FEATURES);
+
+    // A client from the future
+    let stream = TcpStream::connect(address)?;
+    let hello = Hello {
+        min_version: PROTOCOL_VERSION + 1,
+        max_version: PROTOCOL_VERSION + 2,
+        features: vec!["teleportation".to_owned()],
+    };
+    Frame::write(&stream, PROTOCOL_VERSION + 2, MessageType::Hello, &hello)?;
+    let frame = Frame::read(&stream, u32::MAX)?;
+    assert_eq!(frame.kind, MessageType::Error);
+    match frame.decode::<()>(MessageType::Welcome, PROTOCOL_VERSION) {
+        Err(KvsError::ProtocolError(reason)) => assert!(reason.contains("versions 2 to 3")),
+        result => panic!("expected a protocol error, got {result:?}"),
+    }
+
+    // Unknown features are left out of the ones agreed on
+    let stream = TcpStream::connect(address)?;
+    let hello = Hello {
+        features: vec!["teleportation".to_owned(), protocol::PIPELINING.to_owned()],
+        ..Hello::new()
+    };
+    Frame::write(&stream, PROTOCOL_VERSION, MessageType::Hello, &hello)?;
+    let frame = Frame::read(&stream, u32::MAX)?;
+    let welcome = frame.decode::<protocol::Welcome>(MessageType::Welcome, PROTOCOL_VERSION)?;
+    assert_eq!(welcome.features, [protocol::PIPELINING]);
+
+    // A peer that doesn't frame its messages at all
+    let stream = TcpStream::connect(address)?;
+    bincode::serialize_into(
+        &stream,
+        &Request::new(0, Command::from(Get::new("key".into()))),
+    )?;
+    let frame = Frame::read(&stream, u32::MAX)?;
+    match frame.decode::<()>(MessageType::Welcome, PROTOCOL_VERSION) {
+        Err(KvsError::ProtocolError(reason)) => assert!(reason.contains("kvs protocol")),
+        result => panic!("expected a protocol error, got {result:?}"),
+    }
+
+    test_server.shutdown();
+    test_server.wait_until_shutdown();
+    Ok(())
+}
+
+/// Serves a single connection with `process_stream` on a thread, and returns the client's end of
+/// it, already past the handshake, along with the protocol version agreed on.
+fn serve_one_connection(
+    limits: Limits,
+) -> anyhow::Result<(TcpStream, u16, JoinHandle<anyhow::Result<()>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    let handle = thread::spawn(move || {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        let (stream, _) = listener.accept()?;
+        let pool = SharedQueueThreadPool::new(1)?;
+        process_stream(&store, &pool, &stream, &limits, None)
+    });
+    let stream = TcpStream::connect(address)?;
+    let version = protocol::say_hello(&stream, &stream, &Limits::default())?.version;
+    Ok((stream, version, handle))
+}
+
+// Requests that are too big should be answered with an error, and the connection should carry on
+#[test]
+fn server_rejects_oversized_requests() -> anyhow::Result<()> {
+    let limits = Limits {
+        max_frame_size: 1024,
+        max_key_size: 16,
+        max_value_size: 256,
+    };
+    let (stream, version, handle) = serve_one_connection(limits)?;
+
+    let set = |key: &str, value: String| Command::from(Set::new(key.into(), value.into()));
+    let requests = [
+        Request::new(1, set("key1", "v".repeat(2000))),
+        Request::new(2, set(&"k".repeat(17), "value1".to_owned())),
+        Request::new(3, set("key1", "v".repeat(257))),
+        Request::new(
+            4,
+            Command::from(
+                WriteBatch::new()
+                    .set("key1", "value1")
+                    .set("key2", "v".repeat(257)),
+            ),
+        ),
+        Request::new(5, set("key1", "value1".to_owned())),
+        Request::new(6, Command::from(Get::new("key1".into()))),
+    ];
+    for request in &requests {
+        request.write(&stream, version)?;
+    }
+    let responses = (0..requests.len())
+        .map(|_| Response::read(&stream, version, &Limits::default()))
+        .collect::<kvs::Result<Vec<_>>>()?;
+    let ids = responses
+        .iter()
+        .map(|response| response.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [1, 2, 3, 4, 5, 6]);
+    let mut responses = responses.into_iter().map(|response| response.response);
+    for _ in 0..4 {
+        assert!(matches!(
+            responses.next().unwrap().into_result(),
+            Err(KvsError::LimitExceeded(_))
+        ));
+    }
+    assert_eq!(responses.next().unwrap().into_result()?, None);
+    assert_eq!(
+        responses.next().unwrap().into_result()?,
+        Some("value1".into())
+    );
+
+    drop(stream);
+    handle.join().unwrap()?;
+    Ok(())
+}
+
+// Malformed requests shouldn't make the server allocate what they claim to need
+#[test]
+fn server_survives_hostile_byte_streams() -> anyhow::Result<()> {
+    let (mut stream, version, handle) = serve_one_connection(Limits::default())?;
+
+    // A `Set` whose key claims to be `u64::MAX` bytes long, inside a frame that is well-formed
+    Frame::write(
+        &stream,
+        version,
+        MessageType::Request,
+        &(9_u64, 0_u32, u64::MAX),
+    )?;
+    Request::new(10, Command::from(Set::new("key1".into(), "value1".into())))
+        .write(&stream, version)?;
+    let response = Response::read(&stream, version, &Limits::default())?;
+    assert_eq!(response.id, 9);
+    assert!(response.response.is_err());
+    let response = Response::read(&stream, version, &Limits::default())?;
+    assert_eq!(response.id, 10);
+    assert_eq!(response.response.into_result()?, None);
+
+    // A frame that claims to be 4GiB long, after which the client gives up
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&version.to_le_bytes());
+    header.push(MessageType::Request as u8);
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(&11_u64.to_le_bytes());
+    stream.write_all(&header)?;
+    stream.shutdown(Shutdown::Write)?;
+    let frame = Frame::read(&stream, u32::MAX)?;
+    match frame.decode::<()>(MessageType::Response, version) {
+        Err(KvsError::ProtocolError(reason)) => assert!(reason.contains("mid-frame")),
+        result => panic!("expected a protocol error, got {result:?}"),
+    }
+    assert!(handle.join().unwrap().is_err());
+    Ok(())
+}
+
+// A scan without a limit should come back in pages that fit in a frame, rather than fail
+#[test]
+fn server_pages_scans_to_fit_responses() -> anyhow::Result<()> {
+    let limits = Limits {
+        max_frame_size: 1024,
+        ..Limits::default()
+    };
+    let (stream, version, handle) = serve_one_connection(limits)?;
+
+    for i in 0..10 {
+        let set = Set::new(format!("key{i}").into(), "v".repeat(200).into());
+        Request::new(i, Command::from(set)).write(&stream, version)?;
+        let response = Response::read(&stream, version, &limits)?;
+        assert_eq!(response.response.into_result()?, None);
+    }
+    let mut start = Bound::Unbounded;
+    let mut pages = Vec::new();
+    loop {
+        let scan = Scan::new(start, Bound::Unbounded, None);
+        Request::new(100, Command::from(scan)).write(&stream, version)?;
+        let page = Response::read(&stream, version, &limits)?
+            .response
+            .into_entries()?;
+        let Some((last, _)) = page.last() else { break };
+        start = Bound::Excluded(last.clone());
+        pages.push(page);
+    }
+    assert!(pages.len() > 1);
+    let keys = pages
+        .into_iter()
+        .flatten()
+        .map(|(key, _)| key.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(keys, (0..10).map(|i| format!("key{i}")).collect::<Vec<_>>());
+
+    drop(stream);
+    handle.join().unwrap()?;
+    Ok(())
+}
+
+// The client should hold responses to its own limits, and carry on after one goes over them
+#[test]
+fn client_enforces_limits() -> anyhow::Result<()> {
+    let address = SocketAddr::from_str("127.0.0.1:9009").unwrap();
+    let test_server =
+        common::TestKvsServer::<KvStore, SharedQueueThreadPool>::new(address, Some(2)).spawn(1);
+    test_server.wait_until_ready();
+    let client = KvsClient::new(address);
+    let small_client = KvsClient::new(address).with_limits(Limits {
+        max_value_size: 16,
+        ..Limits::default()
+    });
+
+    client.set("key1", "v".repeat(100))?;
+    client.set("key2", "value2")?;
+    let result = small_client.get("key1");
+    assert!(
+        matches!(result, Err(KvsError::LimitExceeded(_))),
+        "expected the limit to be exceeded, got {result:?}"
+    );
+    assert_eq!(small_client.get("key2")?, Some("value2".into()));
+
+    test_server.shutdown();
+    test_server.wait_until_shutdown();
+    Ok(())
+}
+
+// Idle connections, however many there are, shouldn't keep other clients from being served
+#[test]
+fn serve_clients_past_idle_connections() -> anyhow::Result<()> {
+    let address = SocketAddr::from_str("127.0.0.1:9010").unwrap();
+    let http_address = SocketAddr::from_str("127.0.0.1:9011").unwrap();
+    let workers = 2;
+    let test_server = common::TestKvsServer::<KvStore, SharedQueueThreadPool>::with_http(
+        address,
+        Some(workers),
+        http_address,
+    )
+    .spawn(1);
+    test_server.wait_until_ready();
+
+    // Connections that never send anything, and clients that stay connected between requests
+    let mut idle = Vec::new();
+    for _ in 0..workers * 2 {
+        idle.push(TcpStream::connect(address)?);
+        idle.push(TcpStream::connect(http_address)?);
+    }
+    let idle_clients = (0..workers * 2)
+        .map(|_| {
+            let client = KvsClient::new(address);
+            client.set("idle", "value").map(|()| client)
+        })
+        .collect::<kvs::Result<Vec<_>>>()?;
+
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        let client = KvsClient::new(address);
+        let _ = sender.send(
+            client
+                .set("key1", "value1")
+                .and_then(|()| client.get("key1")),
+        );
+    });
+    let value = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("the client wasn't served while other connections were idle")?;
+    assert_eq!(value, Some("value1".into()));
+
+    let mut http = TcpStream::connect(http_address)?;
+    http.set_read_timeout(Some(Duration::from_secs(5)))?;
+    http.write_all(b"GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    http.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {response}");
+    assert!(response.ends_with("value1"), "got {response}");
+
+    drop((idle, idle_clients));
+    test_server.shutdown();
+    test_server.wait_until_shutdown();
+    Ok(())
+}
+
+// Connections past the limit should be closed, and those under it served as usual
+#[test]
+fn refuse_connections_past_the_limit() -> anyhow::Result<()> {
+    let address = SocketAddr::from_str("127.0.0.1:9012").unwrap();
+    let http_address = SocketAddr::from_str("127.0.0.1:9013").unwrap();
+    let test_server =
+        common::TestKvsServer::<KvStore, SharedQueueThreadPool>::with_max_connections(
+            address,
+            Some(2),
+            http_address,
+            2,
+        )
+        .spawn(1);
+    test_server.wait_until_ready();
+    let http_get = |http: &mut TcpStream| -> io::Result<usize> {
+        http.set_read_timeout(Some(Duration::from_secs(5)))?;
+        http.write_all(b"GET /keys/key1 HTTP/1.1\r\n\r\n")?;
+        http.read(&mut [0; 1024])
+    };
+
+    // Both protocols count towards the limit
+    let first = TcpStream::connect(address)?;
+    protocol::say_hello(&first, &first, &Limits::default())?;
+    let mut http = TcpStream::connect(http_address)?;
+    assert!(http_get(&mut http)? > 0);
+
+    let refused = TcpStream::connect(address)?;
+    refused.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert!(protocol::say_hello(&refused, &refused, &Limits::default()).is_err());
+    let mut refused = TcpStream::connect(http_address)?;
+    assert!(http_get(&mut refused).map_or(true, |read| read == 0));
+
+    // Closing a connection makes room for another one
+    drop(first);
+    let client = KvsClient::new(address);
+    let mut attempts = 0;
+    while client.set("key1", "value1").is_err() {
+        attempts += 1;
+        assert!(attempts < 50, "no room was made for a new connection");
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(client.get("key1")?, Some("value1".into()));
+
+    drop(http);
+    test_server.shutdown();
+    test_server.wait_until_shutdown();
+    Ok(())
+}